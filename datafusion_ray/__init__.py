@@ -24,7 +24,17 @@ from ._datafusion_ray_internal import (
     Context,
     ExecutionGraph,
     QueryStage,
+    StageScheduler,
+    DataFusionRayError,
+    PlanningError,
+    CodecError,
+    ShuffleIOError,
+    ExecutionError,
+    ConfigurationError,
+    MemoryLimitExceeded,
+    ShuffleFetchFailed,
     execute_partition,
+    start_shuffle_server,
 )
 from .context import DatafusionRayContext
 
@@ -24,74 +24,201 @@ import pyarrow as pa
 import ray
 
 import datafusion_ray
-from datafusion_ray import Context, ExecutionGraph, QueryStage
-from typing import List, Any
+from datafusion_ray import Context, ExecutionGraph
+from typing import List, Any, Optional, Tuple, Union
 from datafusion import SessionContext
+from datafusion_ray.ray_utils import locality_aff, node_ids_by_location
 
 
-@ray.remote(num_cpus=0)
-def execute_query_stage(
-    query_stages: list[QueryStage],
-    stage_id: int
-) -> tuple[int, list[ray.ObjectRef]]:
+def execute_graph(
+    graph: ExecutionGraph,
+    task_config: dict,
+    max_task_attempts: int = 3,
+    speculation: bool = False,
+    slice_plans: bool = False,
+) -> list[Any]:
     """
-    Execute a query stage on the workers.
+    Execute the query stages of a graph on the workers.
 
-    Returns the stage ID, and a list of futures for the output partitions of the query stage.
-    """
-    stage = QueryStage(stage_id, query_stages[stage_id])
+    The Rust scheduler decides which tasks are runnable and when to retry failed tasks, this
+    function only launches the tasks on Ray and reports their outcome. With speculation enabled
+    the scheduler may return a second attempt of a slow task, and whichever attempt finishes
+    first is used.
 
-    # execute child stages first
-    child_futures = []
-    for child_id in stage.get_child_stage_ids():
-        child_futures.append(
-            execute_query_stage.remote(query_stages, child_id)
-        )
+    Each task is placed, if possible, on the node holding most of its input, as reported by
+    the locality hints of its query stage: the nodes that ran the map tasks whose shuffle output
+    it reads.
 
-    # if the query stage has a single output partition then we need to execute for the output
-    # partition, otherwise we need to execute in parallel for each input partition
-    concurrency = stage.get_input_partition_count()
-    output_partitions_count = stage.get_output_partition_count()
-    if output_partitions_count == 1:
-        # reduce stage
-        print("Forcing reduce stage concurrency from {} to 1".format(concurrency))
-        concurrency = 1
-
-    print(
-        "Scheduling query stage #{} with {} input partitions and {} output partitions".format(
-            stage.id(), concurrency, output_partitions_count
-        )
-    )
+    With slice_plans enabled each task is shipped a plan that only keeps the files scanned by
+    its partition, instead of the plan of the whole stage. The bytes shipped to the tasks and
+    the time they spent decoding their plan are printed for each stage.
 
-    # A list of (stage ID, list of futures) for each child stage
-    # Each list is a 2-D array of (input partitions, output partitions).
-    child_outputs = ray.get(child_futures)
+    When a stage is read by a LIMIT, the scheduler cancels its remaining tasks once the tasks
+    that succeeded have written enough rows. Their running attempts are cancelled on Ray and an
+    empty output is committed in their place.
 
-    # if we are using disk-based shuffle, wait until the child stages to finish
-    # writing the shuffle files to disk first.
-    ray.get([f for _, lst in child_outputs for f in lst])
+    Returns the results of the final query stage, by partition.
+    """
+    scheduler = graph.create_scheduler(max_task_attempts, speculation)
+    final_stage_id = scheduler.final_stage_id()
+
+    # serialize the query stages and store in Ray object store
+    plan_bytes = {
+        stage.id(): ray.put(stage.get_execution_plan_bytes())
+        for stage in graph.get_query_stages()
+    }
 
-    # schedule the actual execution workers
-    plan_bytes = stage.get_execution_plan_bytes()
-    futures = []
     opt = {}
-    for part in range(concurrency):
-        futures.append(
-            execute_query_partition.options(**opt).remote(
-                stage_id, plan_bytes, part
+    if task_config.get("memory") is not None:
+        opt["memory"] = task_config["memory"]
+
+    # with the in-memory and flight shuffle, the result of each task is passed to the tasks
+    # reading it. In flight mode the result is the location of the task's shuffle files.
+    shuffle_mode = graph.shuffle_mode()
+    pass_outputs = shuffle_mode != "disk"
+
+    # plans sliced for a single partition, stored once and reused by retried tasks
+    task_plan_bytes = {}
+    # plan bytes shipped and plan decode time of the succeeded tasks, by stage
+    plan_metrics = {}
+    stages = {stage.id(): stage for stage in graph.get_query_stages()}
+    child_stage_ids = {
+        stage_id: stage.get_child_stage_ids() for stage_id, stage in stages.items()
+    }
+    outputs = {}
+    # node id of the node that ran each succeeded task, by stage and partition
+    task_nodes = {}
+    node_ids = node_ids_by_location()
+
+    running = {}
+    results = {}
+    while not scheduler.is_complete():
+        for stage_id, part in scheduler.next_runnable_tasks():
+            shuffle_inputs = None
+            if pass_outputs:
+                shuffle_inputs = {
+                    child_id: [
+                        future
+                        for _, future in sorted(outputs[child_id].items())
+                    ]
+                    for child_id in child_stage_ids[stage_id]
+                }
+            shuffle_locations = {
+                child_id: list(task_nodes.get(child_id, {}).values())
+                for child_id in child_stage_ids[stage_id]
+            }
+            locations = stages[stage_id].get_preferred_locations(shuffle_locations)
+            task_opt = {**opt, **locality_aff(locations, node_ids), "num_returns": 2}
+            if slice_plans:
+                if (stage_id, part) not in task_plan_bytes:
+                    task_plan_bytes[(stage_id, part)] = ray.put(
+                        stages[stage_id].get_execution_plan_bytes(part)
+                    )
+                task_plan = task_plan_bytes[(stage_id, part)]
+            else:
+                task_plan = plan_bytes[stage_id]
+            info_future, future = execute_query_partition.options(**task_opt).remote(
+                stage_id,
+                task_plan,
+                part,
+                task_config.get("memory_pool", "fair"),
+                task_config.get("spill_dir"),
+                shuffle_inputs,
+                shuffle_mode == "flight",
+            )
+            running[future] = (stage_id, part, info_future)
+
+        if not running:
+            # ray.wait would return immediately and the loop would never end
+            raise RuntimeError("No tasks are running but the query has not completed")
+
+        # wake up periodically so that the scheduler can speculate slow tasks
+        timeout = 0.1 if speculation else None
+        ready, _ = ray.wait(list(running.keys()), num_returns=1, timeout=timeout)
+        for future in ready:
+            stage_id, part, info_future = running.pop(future)
+            try:
+                result = ray.get(future)
+            except Exception as e:
+                # Ray wraps exceptions raised by the task
+                cause = getattr(e, "cause", e)
+                if isinstance(cause, datafusion_ray.ShuffleFetchFailed):
+                    print(f"Task {stage_id}/{part} lost its input: {cause}")
+                    # rerun the map tasks whose output was lost, then this task
+                    scheduler.map_outputs_lost(
+                        stage_id, part, cause.stage_id, cause.map_partitions
+                    )
+                    continue
+                print(f"Task {stage_id}/{part} failed: {e}")
+                # raises once the task has no attempts left
+                scheduler.task_failed(stage_id, part, str(e))
+                continue
+            # a duplicate attempt of a task that already succeeded is ignored
+            if not scheduler.task_succeeded(stage_id, part):
+                continue
+            info = ray.get(info_future)
+            task_nodes.setdefault(stage_id, {})[part] = info["node_id"]
+            stage_metrics = plan_metrics.setdefault(
+                stage_id, {"tasks": 0, "plan_bytes": 0, "decode_secs": 0.0}
             )
+            stage_metrics["tasks"] += 1
+            stage_metrics["plan_bytes"] += info["plan_bytes"]
+            stage_metrics["decode_secs"] += info["decode_secs"]
+            if stage_id == final_stage_id:
+                results[part] = result
+                continue
+            outputs.setdefault(stage_id, {})[part] = future
+            if info.get("output_rows") is not None:
+                scheduler.task_output_rows(stage_id, part, info["output_rows"])
+            for cancelled in scheduler.take_cancelled_tasks():
+                for attempt, (attempt_stage_id, attempt_part, _) in list(running.items()):
+                    if (attempt_stage_id, attempt_part) == cancelled:
+                        # the scheduler does not wait for the outcome of a stopped attempt
+                        ray.cancel(attempt)
+                        del running[attempt]
+                cancelled_stage_id, cancelled_part = cancelled
+                print(f"Task {cancelled_stage_id}/{cancelled_part} is not needed, cancelling")
+                outputs.setdefault(cancelled_stage_id, {})[cancelled_part] = ray.put(
+                    stages[cancelled_stage_id].commit_empty_output(cancelled_part)
+                )
+
+    for stage_id, m in sorted(plan_metrics.items()):
+        print(
+            f"Stage {stage_id}: shipped {m['plan_bytes']} plan bytes to {m['tasks']} tasks, "
+            f"decoded in {m['decode_secs']:.3f}s"
         )
 
-    return stage_id, futures
+    return [results[part] for part in sorted(results)]
 
 
 @ray.remote
 def execute_query_partition(
     stage_id: int,
     plan_bytes: bytes,
-    part: int
-) -> Iterable[pa.RecordBatch]:
+    part: int,
+    memory_pool: str = "fair",
+    spill_dir: Optional[str] = None,
+    shuffle_inputs: Optional[dict] = None,
+    shuffle_server: bool = False,
+) -> Tuple[dict, Iterable[pa.RecordBatch]]:
+    """
+    Execute a partition of a query stage. Returns information about the task, and the result
+    of the task. The information holds the id of the node that ran the task, which is used to
+    place the tasks reading its output, the size and decode time of the task's plan, and the
+    number of rows written by a map task.
+    """
     start_time = time.time()
+    if shuffle_server:
+        # serve the shuffle files written by this worker to the other nodes
+        datafusion_ray.start_shuffle_server(ray.util.get_node_ip_address())
+    if shuffle_inputs is not None:
+        # fetch the shuffle output, or its location, of the input stages from the object store
+        shuffle_inputs = {
+            stage_id: ray.get(futures) for stage_id, futures in shuffle_inputs.items()
+        }
+    # size the task's memory pool from the memory resource Ray reserved for it
+    memory = ray.get_runtime_context().get_assigned_resources().get("memory")
+    memory_limit = int(memory) if memory else None
     # plan = datafusion_ray.deserialize_execution_plan(plan_bytes)
     # print(
     #     "Worker executing plan {} partition #{} with shuffle inputs {}".format(
@@ -103,7 +230,17 @@ def execute_query_partition(
     # This is delegating to DataFusion for execution, but this would be a good place
     # to plug in other execution engines by translating the plan into another engine's plan
     # (perhaps via Substrait, once DataFusion supports converting a physical plan to Substrait)
-    ret = datafusion_ray.execute_partition(plan_bytes, part)
+    metrics = {}
+    ret = datafusion_ray.execute_partition(
+        plan_bytes,
+        part,
+        stage_id=stage_id,
+        memory_limit=memory_limit,
+        memory_pool=memory_pool,
+        spill_dir=spill_dir,
+        shuffle_inputs=shuffle_inputs,
+        metrics=metrics,
+    )
     duration = time.time() - start_time
     event = {
         "cat": f"{stage_id}-{part}",
@@ -113,52 +250,207 @@ def execute_query_partition(
         "ts": int(start_time * 1_000_000),
         "dur": int(duration * 1_000_000),
         "ph": "X",
+        "args": metrics,
     }
     print(json.dumps(event), end=",")
-    return ret[0] if len(ret) == 1 else ret
+    info = {"node_id": ray.get_runtime_context().get_node_id(), **metrics}
+    return info, ret[0] if len(ret) == 1 else ret
 
 
 class DatafusionRayContext:
-    def __init__(self, df_ctx: SessionContext):
+    def __init__(
+        self,
+        df_ctx: Optional[SessionContext] = None,
+        task_memory: Optional[int] = None,
+        memory_pool: str = "fair",
+        spill_dir: Optional[str] = None,
+        max_task_attempts: int = 3,
+        speculation: bool = False,
+        shuffle_mode: str = "disk",
+        shuffle_storage: Optional[str] = None,
+        mmap_shuffle_reads: bool = False,
+        slice_plans: bool = False,
+        scan_task_bytes: Optional[int] = None,
+        exchange_partition_bytes: Optional[int] = None,
+        min_exchange_partitions: int = 1,
+        max_exchange_partitions: int = 1024,
+    ):
+        """
+        :param df_ctx: DataFusion context that SQL statements run against, on which the tables
+            registered through this context are registered too. Without it, SQL statements run
+            against the tables registered through this context.
+        :param task_memory: bytes of Ray memory resource to reserve for each task. The task's
+            memory pool is sized from this reservation. Tasks are unbounded if not set.
+        :param memory_pool: "fair" to share the limit between spillable operators, or "greedy"
+        :param spill_dir: directory for spill files, defaults to the OS temp directory
+        :param max_task_attempts: number of times a failed task is attempted before the query
+            fails
+        :param speculation: launch a second attempt of tasks that are much slower than the
+            other tasks of their stage
+        :param shuffle_mode: "disk" to exchange data between query stages through shuffle
+            files, which requires a filesystem shared by all workers, "memory" to pass it
+            through the Ray object store, or "flight" to write shuffle files on each worker's
+            node and fetch them from an Arrow Flight server running in each worker
+        :param shuffle_storage: directory or object store URL, such as "s3://bucket/prefix",
+            under which shuffle files are written. Defaults to a local temp directory. S3
+            credentials are read from the AWS_* environment variables of the workers.
+        :param mmap_shuffle_reads: memory-map shuffle files on the local filesystem when
+            reading them, which avoids copying them into newly allocated memory
+        :param slice_plans: ship each task a plan that only keeps the files scanned by its
+            partition, which shrinks the plans of stages scanning many files
+        :param scan_task_bytes: bytes of Parquet files scanned by each task of the stages
+            reading tables. Large files are split at row group boundaries and small files are
+            packed together. By default these stages have one task per partition planned by
+            DataFusion, as set by target_partitions.
+        :param exchange_partition_bytes: estimated bytes of input per partition of each hash
+            exchange between stages. By default every exchange has target_partitions partitions.
+        :param min_exchange_partitions: fewest partitions of an exchange sized from its input
+        :param max_exchange_partitions: most partitions of an exchange sized from its input
+        """
         self.df_ctx = df_ctx
         self.ctx = Context(df_ctx)
+        self.task_config = {
+            "memory": task_memory,
+            "memory_pool": memory_pool,
+            "spill_dir": spill_dir,
+        }
+        self.max_task_attempts = max_task_attempts
+        self.speculation = speculation
+        # the options of Context.plan
+        self.planning_options = {
+            "shuffle_mode": shuffle_mode,
+            "shuffle_storage": shuffle_storage,
+            "mmap_shuffle_reads": mmap_shuffle_reads,
+            "scan_task_bytes": scan_task_bytes,
+            "exchange_partition_bytes": exchange_partition_bytes,
+            "min_exchange_partitions": min_exchange_partitions,
+            "max_exchange_partitions": max_exchange_partitions,
+        }
+        self.slice_plans = slice_plans
+
+    def register_csv(
+        self,
+        table_name: str,
+        path: Union[str, List[str]],
+        has_header: bool = True,
+        delimiter: str = ",",
+        table_partition_cols: Optional[List[Tuple[str, pa.DataType]]] = None,
+        schema: Optional[pa.Schema] = None,
+        file_extension: Optional[str] = None,
+    ):
+        """
+        Register a table over CSV files. `path` is a path or URL of a file or directory, or a
+        list of them. `table_partition_cols` lists the name and type of the columns whose
+        values are taken from hive-style `column=value` directories, and `schema` overrides
+        the schema inferred from the files.
+        """
+        self.ctx.register_csv(
+            table_name,
+            path,
+            has_header,
+            delimiter,
+            table_partition_cols or [],
+            schema,
+            file_extension,
+        )
 
-    def register_csv(self, table_name: str, path: str, has_header: bool):
-        self.ctx.register_csv(table_name, path, has_header)
+    def register_parquet(
+        self,
+        table_name: str,
+        path: Union[str, List[str]],
+        table_partition_cols: Optional[List[Tuple[str, pa.DataType]]] = None,
+        schema: Optional[pa.Schema] = None,
+        file_extension: Optional[str] = None,
+    ):
+        """Register a table over Parquet files, see `register_csv`"""
+        self.ctx.register_parquet(
+            table_name, path, table_partition_cols or [], schema, file_extension
+        )
+
+    def register_json(
+        self,
+        table_name: str,
+        path: Union[str, List[str]],
+        table_partition_cols: Optional[List[Tuple[str, pa.DataType]]] = None,
+        schema: Optional[pa.Schema] = None,
+        file_extension: Optional[str] = None,
+    ):
+        """Register a table over newline-delimited JSON files, see `register_csv`"""
+        self.ctx.register_json(
+            table_name, path, table_partition_cols or [], schema, file_extension
+        )
 
-    def register_parquet(self, table_name: str, path: str):
-        self.ctx.register_parquet(table_name, path)
+    def register_avro(
+        self,
+        table_name: str,
+        path: Union[str, List[str]],
+        table_partition_cols: Optional[List[Tuple[str, pa.DataType]]] = None,
+        schema: Optional[pa.Schema] = None,
+        file_extension: Optional[str] = None,
+    ):
+        """Register a table over Avro files, see `register_csv`"""
+        self.ctx.register_avro(
+            table_name, path, table_partition_cols or [], schema, file_extension
+        )
+
+    def register_arrow(
+        self,
+        table_name: str,
+        path: Union[str, List[str]],
+        table_partition_cols: Optional[List[Tuple[str, pa.DataType]]] = None,
+        schema: Optional[pa.Schema] = None,
+        file_extension: Optional[str] = None,
+    ):
+        """Register a table over Arrow IPC files, see `register_csv`"""
+        self.ctx.register_arrow(
+            table_name, path, table_partition_cols or [], schema, file_extension
+        )
 
     def register_data_lake(self, table_name: str, paths: List[str]):
         self.ctx.register_datalake_table(table_name, paths)
 
-    def sql(self, sql: str) -> pa.RecordBatch:
-        # TODO we should parse sql and inspect the plan rather than
-        # perform a string comparison here
-        sql_str = sql.lower()
-        if "create view" in sql_str or "drop view" in sql_str:
-            self.ctx.sql(sql)
-            return []
-
-        df = self.df_ctx.sql(sql)
-        return self.plan(df.execution_plan())
-
-    def plan(self, execution_plan: Any) -> pa.RecordBatch:
-
-        graph = self.ctx.plan(execution_plan)
-        final_stage_id = graph.get_final_query_stage().id()
-        # serialize the query stages and store in Ray object store
-        query_stages = [
-                graph.get_query_stage(i).get_execution_plan_bytes()
-            for i in range(final_stage_id + 1)
-        ]
-        # schedule execution
-        future = execute_query_stage.remote(
-            query_stages,
-            final_stage_id
+    def sql(self, sql: str, deterministic: bool = False) -> Any:
+        """
+        Run a SQL statement, or a script of statements separated by semicolons, and return the
+        result of the last statement. Queries and writes such as INSERT INTO and COPY TO run on
+        the workers, with one writer per partition, and writes return the number of rows
+        written. The EXPLAIN of a query or a write returns the plans of its query stages, and
+        other statements, such as DDL and SET, run against the local DataFusion context. With
+        `deterministic` the rows of queries are returned in the same order on every run, at the
+        cost of reading the shuffle output of the tasks one at a time.
+        """
+        result = []
+        for statement in self.ctx.parse_sql(sql):
+            if statement.kind in ("query", "dml"):
+                graph = self._plan_sql(statement.sql, deterministic)
+                result = self._execute(graph)
+            elif statement.distributed_explain:
+                graph = self._plan_sql(statement.explained.sql, deterministic)
+                result = graph.explain(statement.verbose)
+            else:
+                result = self.ctx.sql(statement.sql)
+        return result
+
+    def plan(self, execution_plan: Any, deterministic: bool = False) -> pa.RecordBatch:
+        return self._execute(self._plan_graph(execution_plan, deterministic))
+
+    def _execute(self, graph: ExecutionGraph) -> pa.RecordBatch:
+        partitions = execute_graph(
+            graph,
+            self.task_config,
+            self.max_task_attempts,
+            self.speculation,
+            self.slice_plans,
         )
-        _, partitions = ray.get(future)
         # assert len(partitions) == 1, len(partitions)
-        result_set = ray.get(partitions[0])
-        return result_set
+        return partitions[0]
 
+    def _plan_sql(self, sql: str, deterministic: bool) -> ExecutionGraph:
+        return self.ctx.plan_sql(
+            sql, {**self.planning_options, "deterministic": deterministic}
+        )
+
+    def _plan_graph(self, execution_plan: Any, deterministic: bool) -> ExecutionGraph:
+        return self.ctx.plan(
+            execution_plan, {**self.planning_options, "deterministic": deterministic}
+        )
@@ -29,3 +29,30 @@ def node_aff(node_id: ray.NodeID, *, soft: bool = False) -> dict:
 
 def current_node_aff() -> dict:
     return node_aff(ray.get_runtime_context().get_node_id())
+
+
+def node_ids_by_location() -> dict:
+    """Map the id, hostname and IP address of every alive node to its node id"""
+    node_ids = {}
+    for node in ray.nodes():
+        if not node.get("Alive"):
+            continue
+        node_id = node["NodeID"]
+        hostname = node.get("NodeManagerHostname")
+        address = node.get("NodeManagerAddress")
+        for location in (node_id, hostname, address):
+            if location:
+                node_ids.setdefault(location, node_id)
+    return node_ids
+
+
+def locality_aff(locations: list, node_ids: dict) -> dict:
+    """
+    Soft affinity to the first of `locations`, node ids or hostnames ordered by preference,
+    that is a node of the cluster. Empty if none of them is.
+    """
+    for location in locations:
+        node_id = node_ids.get(location)
+        if node_id is not None:
+            return node_aff(node_id, soft=True)
+    return {}
@@ -20,7 +20,16 @@ from datafusion import SessionContext
 
 
 def test():
+    ctx = Context()
+    ctx.register_csv("tips", "examples/tips.csv", has_header=True)
+    ctx.plan_sql("SELECT * FROM tips")
+
+
+def test_session_ctx():
     df_ctx = SessionContext()
-    ctx = Context(df_ctx, False)
-    df_ctx.register_csv("tips", "examples/tips.csv", has_header=True)
-    ctx.plan("SELECT * FROM tips")
+    ctx = Context(df_ctx)
+    # the tables registered through this context are registered on the DataFusion context
+    ctx.register_csv("tips", "examples/tips.csv", has_header=True)
+    ctx.plan_sql("SELECT * FROM tips")
+    batches = ctx.sql("SELECT count(*) AS c FROM tips")
+    assert batches[0].column(0)[0].as_py() == 244
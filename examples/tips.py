@@ -31,8 +31,8 @@ df_ctx = SessionContext()
 
 ray_ctx = DatafusionRayContext(df_ctx)
 # Register either a CSV or Parquet file
-# ctx.register_csv("tips", f"{SCRIPT_DIR}/tips.csv", True)
-df_ctx.register_parquet("tips", f"{SCRIPT_DIR}/tips.parquet")
+# ray_ctx.register_csv("tips", f"{SCRIPT_DIR}/tips.csv", True)
+ray_ctx.register_parquet("tips", f"{SCRIPT_DIR}/tips.parquet")
 
 result_set = ray_ctx.sql(
     "select sex, smoker, avg(tip/total_bill) as tip_pct from tips group by sex, smoker"
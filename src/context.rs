@@ -15,9 +15,16 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use crate::planner::{make_execution_graph, PyExecutionGraph};
-use crate::shuffle::ShuffleCodec;
-use datafusion::arrow::pyarrow::ToPyArrow;
+use crate::distributed::DistributedContext;
+use crate::error::RayError;
+use crate::exchange::ExchangePartitionConfig;
+use crate::planner::PyExecutionGraph;
+use crate::runtime::{MemoryPoolType, TaskRuntimeConfig};
+use crate::shuffle::{self, map_task_rows, with_shuffle_inputs, ShuffleCodec, ShuffleWriterExec};
+use crate::sql::{parse_sql, PySqlStatement};
+use crate::table::{TableDefinition, TableFormat};
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::pyarrow::{PyArrowType, ToPyArrow};
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::error::{DataFusionError, Result};
 use datafusion::execution::context::TaskContext;
@@ -28,11 +35,12 @@ use datafusion_proto::physical_plan::AsExecutionPlan;
 use datafusion_proto::protobuf;
 use futures::StreamExt;
 use prost::Message;
-use pyo3::exceptions::PyRuntimeError;
 use pyo3::prelude::*;
-use pyo3::types::{PyBytes, PyTuple};
+use pyo3::types::{PyBytes, PyDict, PyTuple};
 use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::runtime::Runtime;
 use tokio::task::JoinHandle;
 
@@ -40,7 +48,28 @@ type PyResultSet = Vec<PyObject>;
 
 #[pyclass(name = "Context", module = "datafusion_ray", subclass)]
 pub struct PyContext {
-    pub(crate) py_ctx: PyObject,
+    /// DataFusion Python context that plans and runs the SQL statements, if this context was
+    /// created with one. The tables registered through this context are registered on it.
+    py_ctx: Option<PyObject>,
+    /// Session that holds the tables registered through this context and that plans and runs
+    /// its SQL statements when there is no Python context
+    ctx: SessionContext,
+}
+
+/// Paths of a table, as a single string or a list
+#[derive(FromPyObject)]
+pub enum PyTablePaths {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl From<PyTablePaths> for Vec<String> {
+    fn from(paths: PyTablePaths) -> Self {
+        match paths {
+            PyTablePaths::Single(path) => vec![path],
+            PyTablePaths::Multiple(paths) => paths,
+        }
+    }
 }
 
 pub(crate) fn execution_plan_from_pyany(
@@ -49,7 +78,7 @@ pub(crate) fn execution_plan_from_pyany(
     let py_proto = py_plan.call_method0("to_proto")?;
     let plan_bytes: &[u8] = py_proto.extract()?;
     let plan_node = protobuf::PhysicalPlanNode::try_decode(plan_bytes).map_err(|e| {
-        PyRuntimeError::new_err(format!(
+        RayError::Codec(format!(
             "Unable to decode physical plan protobuf message: {}",
             e
         ))
@@ -58,36 +87,194 @@ pub(crate) fn execution_plan_from_pyany(
     let codec = ShuffleCodec {};
     let runtime = RuntimeEnv::default();
     let registry = SessionContext::new();
-    plan_node
+    Ok(plan_node
         .try_into_physical_plan(&registry, &runtime, &codec)
-        .map_err(|e| e.into())
+        .map_err(codec_error)?)
+}
+
+/// Report a failure to convert a plan from or to protobuf as a codec error
+pub(crate) fn codec_error(e: DataFusionError) -> RayError {
+    match RayError::planning(e) {
+        RayError::Planning(e) => RayError::Codec(e.to_string()),
+        e => e,
+    }
 }
 
 #[pymethods]
 impl PyContext {
+    /// Create a context whose SQL statements run against `session_ctx`, a DataFusion Python
+    /// `SessionContext`, or against the tables registered through this context if not set
     #[new]
-    pub fn new(session_ctx: PyObject) -> Result<Self> {
-        Ok(Self {
+    #[pyo3(signature = (session_ctx=None))]
+    pub fn new(session_ctx: Option<PyObject>) -> Self {
+        Self {
             py_ctx: session_ctx,
-        })
+            ctx: SessionContext::new(),
+        }
     }
 
-    /// Execute SQL directly against the DataFusion context. Useful for statements
-    /// such as "create view" or "drop view"
-    pub fn sql(&self, query: &str, py: Python) -> PyResult<()> {
-        println!("Executing {}", query);
-        // let _df = wait_for_future(py, self.ctx.sql(sql))?;
-        let _df = self.run_sql(query, py);
-        Ok(())
+    /// Execute a single statement directly against the session of this context and return its
+    /// result. Used for the statements that run locally, such as DDL and SET.
+    pub fn sql(&self, query: &str, py: Python<'_>) -> PyResult<PyObject> {
+        if let Some(py_ctx) = &self.py_ctx {
+            return run_sql(py_ctx, query, py)?.call_method0(py, "collect");
+        }
+        let ctx = self.ctx.clone();
+        let query = query.to_string();
+        let batches = wait_for_future(py, async move { ctx.sql(&query).await?.collect().await })?
+            .map_err(RayError::from)?;
+        batches.to_pyarrow(py)
     }
 
-    fn run_sql(&self, query: &str, py: Python) -> PyResult<Py<PyAny>> {
-        let args = PyTuple::new_bound(py, [query]);
-        self.py_ctx.call_method1(py, "sql", args)
+    /// Register a listing table of Parquet files. `path` is a path or URL, or a list of them.
+    /// `table_partition_cols` lists the name and type of the columns whose values are taken
+    /// from hive-style `column=value` directories, and `schema` overrides the schema inferred
+    /// from the files.
+    #[pyo3(signature = (name, path, table_partition_cols=vec![], schema=None, file_extension=None))]
+    pub fn register_parquet(
+        &self,
+        name: &str,
+        path: PyTablePaths,
+        table_partition_cols: Vec<(String, PyArrowType<DataType>)>,
+        schema: Option<PyArrowType<Schema>>,
+        file_extension: Option<&str>,
+        py: Python,
+    ) -> PyResult<()> {
+        let table = TableDefinition::new(name, TableFormat::Parquet, path.into());
+        self.register_table(table, table_partition_cols, schema, file_extension, py)
+    }
+
+    /// Register a listing table of CSV files, see `register_parquet`
+    #[pyo3(signature = (name, path, has_header=true, delimiter=",", table_partition_cols=vec![], schema=None, file_extension=None))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn register_csv(
+        &self,
+        name: &str,
+        path: PyTablePaths,
+        has_header: bool,
+        delimiter: &str,
+        table_partition_cols: Vec<(String, PyArrowType<DataType>)>,
+        schema: Option<PyArrowType<Schema>>,
+        file_extension: Option<&str>,
+        py: Python,
+    ) -> PyResult<()> {
+        let [delimiter] = delimiter.as_bytes() else {
+            return Err(RayError::Configuration(format!(
+                "The delimiter must be a single byte, got '{delimiter}'"
+            ))
+            .into());
+        };
+        let table = TableDefinition::new(name, TableFormat::Csv, path.into())
+            .with_has_header(has_header)
+            .with_delimiter(*delimiter);
+        self.register_table(table, table_partition_cols, schema, file_extension, py)
     }
 
-    /// Plan a distributed SELECT query for executing against the Ray workers
-    pub fn plan(&self, plan: &Bound<PyAny>) -> PyResult<PyExecutionGraph> {
+    /// Register a listing table of newline-delimited JSON files, see `register_parquet`
+    #[pyo3(signature = (name, path, table_partition_cols=vec![], schema=None, file_extension=None))]
+    pub fn register_json(
+        &self,
+        name: &str,
+        path: PyTablePaths,
+        table_partition_cols: Vec<(String, PyArrowType<DataType>)>,
+        schema: Option<PyArrowType<Schema>>,
+        file_extension: Option<&str>,
+        py: Python,
+    ) -> PyResult<()> {
+        let table = TableDefinition::new(name, TableFormat::NdJson, path.into());
+        self.register_table(table, table_partition_cols, schema, file_extension, py)
+    }
+
+    /// Register a listing table of Avro files, see `register_parquet`
+    #[pyo3(signature = (name, path, table_partition_cols=vec![], schema=None, file_extension=None))]
+    pub fn register_avro(
+        &self,
+        name: &str,
+        path: PyTablePaths,
+        table_partition_cols: Vec<(String, PyArrowType<DataType>)>,
+        schema: Option<PyArrowType<Schema>>,
+        file_extension: Option<&str>,
+        py: Python,
+    ) -> PyResult<()> {
+        let table = TableDefinition::new(name, TableFormat::Avro, path.into());
+        self.register_table(table, table_partition_cols, schema, file_extension, py)
+    }
+
+    /// Register a listing table of Arrow IPC files, see `register_parquet`
+    #[pyo3(signature = (name, path, table_partition_cols=vec![], schema=None, file_extension=None))]
+    pub fn register_arrow(
+        &self,
+        name: &str,
+        path: PyTablePaths,
+        table_partition_cols: Vec<(String, PyArrowType<DataType>)>,
+        schema: Option<PyArrowType<Schema>>,
+        file_extension: Option<&str>,
+        py: Python,
+    ) -> PyResult<()> {
+        let table = TableDefinition::new(name, TableFormat::Arrow, path.into());
+        self.register_table(table, table_partition_cols, schema, file_extension, py)
+    }
+
+    /// Register a table over the Parquet files of several directories
+    pub fn register_datalake_table(
+        &self,
+        name: &str,
+        paths: Vec<String>,
+        py: Python,
+    ) -> PyResult<()> {
+        let table = TableDefinition::new(name, TableFormat::Parquet, paths);
+        self.register_table(table, vec![], None, None, py)
+    }
+
+    /// Plan a distributed SELECT query against the session of this context, see `plan`
+    #[pyo3(signature = (sql, options=None))]
+    pub fn plan_sql(
+        &self,
+        sql: &str,
+        options: Option<&Bound<'_, PyDict>>,
+        py: Python,
+    ) -> PyResult<PyExecutionGraph> {
+        if let Some(py_ctx) = &self.py_ctx {
+            let py_plan = run_sql(py_ctx, sql, py)?.call_method0(py, "execution_plan")?;
+            return self.plan(py_plan.bind(py), options, py);
+        }
+        let ctx = with_planning_options(DistributedContext::new(self.ctx.clone()), options)?;
+        let sql = sql.to_string();
+        let graph = wait_for_future(py, async move { ctx.plan_sql(&sql).await })?
+            .map_err(RayError::planning)?;
+        Ok(PyExecutionGraph::new(graph))
+    }
+
+    /// Split a SQL script into statements and classify them as "query", "explain", "ddl",
+    /// "dml" or "session", which decides whether they are run on the workers or locally
+    #[pyo3(signature = (sql, dialect="generic"))]
+    pub fn parse_sql(&self, sql: &str, dialect: &str) -> PyResult<Vec<PySqlStatement>> {
+        let statements = parse_sql(sql, dialect).map_err(RayError::planning)?;
+        Ok(statements.into_iter().map(PySqlStatement::new).collect())
+    }
+
+    /// Plan a distributed SELECT query for executing against the Ray workers. `options` is a
+    /// dict of planning options, all of which are optional:
+    ///
+    /// - `shuffle_mode`: "disk", or "memory" for the query stages to exchange data through the
+    ///   Ray object store instead of shuffle files, or "flight"
+    /// - `shuffle_storage`: directory or object store URL, such as `s3://bucket/prefix`, under
+    ///   which shuffle files are written
+    /// - `mmap_shuffle_reads`: memory-map the shuffle files on the local filesystem when read
+    /// - `deterministic`: read the map outputs in a fixed order, so that the query returns rows
+    ///   in the same order on every run
+    /// - `scan_task_bytes`: re-split the Parquet scans of the leaf query stages so that each
+    ///   task scans about that many bytes, instead of one task per partition of the plan
+    /// - `exchange_partition_bytes`: give each hash exchange one partition per that many bytes
+    ///   of estimated input, between `min_exchange_partitions` (1 by default) and
+    ///   `max_exchange_partitions` (1024 by default), instead of `target_partitions`
+    #[pyo3(signature = (plan, options=None))]
+    pub fn plan(
+        &self,
+        plan: &Bound<PyAny>,
+        options: Option<&Bound<'_, PyDict>>,
+        py: Python,
+    ) -> PyResult<PyExecutionGraph> {
         // println!("Planning {}", sql);
         // let df = wait_for_future(py, self.ctx.sql(sql))?;
         // let py_df = self.run_sql(sql, py)?;
@@ -95,7 +282,10 @@ impl PyContext {
         // let py_plan = py_plan.bind(py);
 
         let plan = execution_plan_from_pyany(plan)?;
-        let graph = make_execution_graph(plan.clone())?;
+        // scans are re-split with the object stores registered on the session of this context
+        let ctx = with_planning_options(DistributedContext::new(self.ctx.clone()), options)?;
+        let graph = wait_for_future(py, async move { ctx.plan_physical(plan).await })?
+            .map_err(RayError::planning)?;
 
         // debug logging
         let mut stages = graph.query_stages.values().collect::<Vec<_>>();
@@ -112,37 +302,277 @@ impl PyContext {
     }
 
     /// Execute a partition of a query plan. This will typically be executing a shuffle write and write the results to disk
+    #[pyo3(signature = (plan, part, stage_id=None, memory_limit=None, memory_pool="fair", spill_dir=None, shuffle_inputs=None, metrics=None))]
+    #[allow(clippy::too_many_arguments)]
     pub fn execute_partition(
         &self,
         plan: &Bound<'_, PyBytes>,
         part: usize,
-        py: Python,
+        stage_id: Option<usize>,
+        memory_limit: Option<usize>,
+        memory_pool: &str,
+        spill_dir: Option<&str>,
+        shuffle_inputs: Option<HashMap<usize, Vec<PyArrowType<RecordBatch>>>>,
+        metrics: Option<&Bound<'_, PyDict>>,
+        py: Python<'_>,
     ) -> PyResult<PyResultSet> {
-        execute_partition(plan, part, py)
+        execute_partition(
+            plan,
+            part,
+            stage_id,
+            memory_limit,
+            memory_pool,
+            spill_dir,
+            shuffle_inputs,
+            metrics,
+            py,
+        )
+    }
+}
+
+impl PyContext {
+    fn register_table(
+        &self,
+        table: TableDefinition,
+        table_partition_cols: Vec<(String, PyArrowType<DataType>)>,
+        schema: Option<PyArrowType<Schema>>,
+        file_extension: Option<&str>,
+        py: Python,
+    ) -> PyResult<()> {
+        let table = table
+            .with_partition_cols(
+                table_partition_cols
+                    .into_iter()
+                    .map(|(name, data_type)| (name, data_type.0))
+                    .collect(),
+            )
+            .with_schema(schema.map(|schema| Arc::new(schema.0)))
+            .with_file_extension(file_extension);
+        if let Some(py_ctx) = &self.py_ctx {
+            return register_py_table(py_ctx, &table, py);
+        }
+        let ctx = self.ctx.clone();
+        wait_for_future(py, async move { table.register(&ctx).await })?
+            .map_err(RayError::planning)?;
+        Ok(())
     }
 }
 
+/// Run a SQL statement against a DataFusion Python context and return its DataFrame
+fn run_sql(py_ctx: &PyObject, query: &str, py: Python) -> PyResult<PyObject> {
+    let args = PyTuple::new_bound(py, [query]);
+    py_ctx.call_method1(py, "sql", args)
+}
+
+/// Register a table on a DataFusion Python context with the registration method of its format
+fn register_py_table(py_ctx: &PyObject, table: &TableDefinition, py: Python) -> PyResult<()> {
+    let unsupported = |reason: &str| -> PyErr {
+        RayError::Configuration(format!(
+            "Cannot register {} table '{}' on a DataFusion SessionContext: {reason}",
+            table.format, table.name
+        ))
+        .into()
+    };
+    let kwargs = PyDict::new_bound(py);
+    let file_extension = table
+        .file_extension
+        .as_deref()
+        .unwrap_or(table.format.default_file_extension());
+    kwargs.set_item("file_extension", file_extension)?;
+    if let Some(schema) = &table.schema {
+        kwargs.set_item("schema", schema.to_pyarrow(py)?)?;
+    }
+    if !table.partition_cols.is_empty() {
+        // DataFusion Python takes the types of partition columns by name
+        let partition_cols = table
+            .partition_cols
+            .iter()
+            .map(|(name, data_type)| match data_type {
+                DataType::Utf8 => Ok((name.as_str(), "string")),
+                DataType::Int32 => Ok((name.as_str(), "int")),
+                other => Err(unsupported(&format!(
+                    "partition columns of type {other} are not supported"
+                ))),
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        kwargs.set_item("table_partition_cols", partition_cols)?;
+    }
+    let method = match table.format {
+        TableFormat::Parquet => "register_listing_table",
+        TableFormat::Csv if !table.partition_cols.is_empty() => {
+            return Err(unsupported(
+                "partition columns are not supported for CSV files",
+            ))
+        }
+        TableFormat::Csv => {
+            kwargs.set_item("has_header", table.has_header)?;
+            kwargs.set_item("delimiter", (table.delimiter as char).to_string())?;
+            "register_csv"
+        }
+        TableFormat::NdJson => "register_json",
+        TableFormat::Avro => "register_avro",
+        TableFormat::Arrow => return Err(unsupported("Arrow IPC files are not supported")),
+    };
+    let path = match (table.format, table.paths.as_slice()) {
+        (_, []) => return Err(unsupported("the table has no paths")),
+        (_, [path]) => path.into_py(py),
+        (TableFormat::Csv, paths) => paths.to_vec().into_py(py),
+        _ => return Err(unsupported("only CSV tables can have more than one path")),
+    };
+    py_ctx.call_method_bound(py, method, (table.name.as_str(), path), Some(&kwargs))?;
+    Ok(())
+}
+
+/// Apply the planning options of `PyContext::plan` to a distributed context
+fn with_planning_options(
+    mut ctx: DistributedContext,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<DistributedContext> {
+    let options = match options {
+        Some(options) => options,
+        None => return Ok(ctx),
+    };
+    // the partition count of each hash exchange is only chosen from its input size if
+    // `exchange_partition_bytes` is set
+    let mut exchange_partition_bytes = None;
+    let mut exchange_partitions = ExchangePartitionConfig::default();
+    for (key, value) in options.iter() {
+        let key: String = key.extract()?;
+        ctx = match key.as_str() {
+            "shuffle_mode" => {
+                let shuffle_mode: String = value.extract()?;
+                ctx.with_shuffle_mode(shuffle_mode.parse().map_err(RayError::planning)?)
+            }
+            "shuffle_storage" => {
+                let shuffle_storage: Option<String> = value.extract()?;
+                ctx.with_shuffle_storage(shuffle_storage.as_deref())
+            }
+            "mmap_shuffle_reads" => ctx.with_mmap_shuffle_reads(value.extract()?),
+            "deterministic" => ctx.with_deterministic(value.extract()?),
+            "scan_task_bytes" => ctx.with_scan_task_bytes(value.extract()?),
+            "exchange_partition_bytes" => {
+                exchange_partition_bytes = value.extract()?;
+                ctx
+            }
+            "min_exchange_partitions" => {
+                exchange_partitions.min_partitions = value.extract()?;
+                ctx
+            }
+            "max_exchange_partitions" => {
+                exchange_partitions.max_partitions = value.extract()?;
+                ctx
+            }
+            _ => {
+                return Err(
+                    RayError::Configuration(format!("Unknown planning option '{key}'")).into(),
+                )
+            }
+        };
+    }
+    let exchange_partitions =
+        exchange_partition_bytes.map(|target_partition_bytes| ExchangePartitionConfig {
+            target_partition_bytes,
+            ..exchange_partitions
+        });
+    Ok(ctx.with_exchange_partitions(exchange_partitions))
+}
+
+/// Run a future on a new Tokio runtime, releasing the GIL while it runs
+pub(crate) fn wait_for_future<F>(py: Python, future: F) -> Result<F::Output>
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    py.allow_threads(|| Ok(Runtime::new()?.block_on(future)))
+}
+
+/// Execute a partition of a serialized query plan.
+///
+/// When `memory_limit` is set the task runs against a memory pool of that many bytes and
+/// spillable operators write to `spill_dir`. A task that still exceeds the limit raises
+/// `MemoryLimitExceeded`. A task whose shuffle input has been lost raises `ShuffleFetchFailed`.
+/// Other failures raise `ExecutionError`, `ShuffleIOError` or `ConfigurationError`, with the
+/// `stage_id` and `partition` of the task.
+///
+/// With the in-memory and flight shuffle, `shuffle_inputs` maps the id of each stage that the
+/// plan reads from to the batches returned by that stage's tasks, in partition order.
+///
+/// When `metrics` is a dict, the size of the serialized plan and the time spent decoding it
+/// are set as its `plan_bytes` and `decode_secs` items, and the number of rows written by a
+/// map task as its `output_rows` item.
 #[pyfunction]
+#[pyo3(signature = (plan_bytes, part, stage_id=None, memory_limit=None, memory_pool="fair", spill_dir=None, shuffle_inputs=None, metrics=None))]
+#[allow(clippy::too_many_arguments)]
 pub fn execute_partition(
     plan_bytes: &Bound<'_, PyBytes>,
     part: usize,
-    py: Python,
+    stage_id: Option<usize>,
+    memory_limit: Option<usize>,
+    memory_pool: &str,
+    spill_dir: Option<&str>,
+    shuffle_inputs: Option<HashMap<usize, Vec<PyArrowType<RecordBatch>>>>,
+    metrics: Option<&Bound<'_, PyDict>>,
+    py: Python<'_>,
 ) -> PyResult<PyResultSet> {
-    let plan = deserialize_execution_plan(plan_bytes)?;
-    _execute_partition(plan, part)
-        .unwrap()
+    let start = Instant::now();
+    let mut plan = deserialize_execution_plan(plan_bytes)?;
+    if let Some(metrics) = metrics {
+        metrics.set_item("plan_bytes", plan_bytes.as_bytes().len())?;
+        metrics.set_item("decode_secs", start.elapsed().as_secs_f64())?;
+    }
+    if let Some(inputs) = shuffle_inputs {
+        let inputs = inputs
+            .into_iter()
+            .map(|(id, batches)| (id, batches.into_iter().map(|b| b.0).collect()))
+            .collect();
+        plan = with_shuffle_inputs(plan, &inputs)
+            .map_err(|e| RayError::task(stage_id, Some(part), e))?;
+    }
+    let mut config = TaskRuntimeConfig::new().with_memory_pool(
+        memory_pool
+            .parse::<MemoryPoolType>()
+            .map_err(RayError::from)?,
+    );
+    if let Some(limit) = memory_limit {
+        config = config.with_memory_limit(limit);
+    }
+    if let Some(dir) = spill_dir {
+        config = config.with_spill_dir(dir);
+    }
+    let stage_id = stage_id.or_else(|| {
+        plan.as_any()
+            .downcast_ref::<ShuffleWriterExec>()
+            .map(|writer| writer.stage_id)
+    });
+    let results = py
+        .allow_threads(|| _execute_partition(plan, part, &config))
+        .map_err(|e| RayError::task(stage_id, Some(part), e))?;
+    if let (Some(metrics), Some(rows)) = (metrics, map_task_rows(&results)) {
+        metrics.set_item("output_rows", rows)?;
+    }
+    results
         .into_iter()
         .map(|batch| batch.to_pyarrow(py))
         .collect()
 }
 
+/// Start the Arrow Flight server that serves the shuffle files written by this process in
+/// flight mode, unless it is already running, and return its port. `host` must be an address
+/// that the other nodes can reach.
+#[pyfunction]
+#[pyo3(signature = (host, port=0))]
+pub fn start_shuffle_server(host: &str, port: u16) -> PyResult<u16> {
+    Ok(shuffle::start_shuffle_server(host, port).map_err(RayError::shuffle_io)?)
+}
+
 pub fn serialize_execution_plan(
     plan: Arc<dyn ExecutionPlan>,
-    py: Python,
+    py: Python<'_>,
 ) -> PyResult<Bound<'_, PyBytes>> {
     let codec = ShuffleCodec {};
     let proto =
-        datafusion_proto::protobuf::PhysicalPlanNode::try_from_physical_plan(plan.clone(), &codec)?;
+        datafusion_proto::protobuf::PhysicalPlanNode::try_from_physical_plan(plan.clone(), &codec)
+            .map_err(codec_error)?;
 
     let bytes = proto.encode_to_vec();
     Ok(PyBytes::new_bound(py, &bytes))
@@ -152,7 +582,7 @@ pub fn deserialize_execution_plan(proto_msg: &Bound<PyBytes>) -> PyResult<Arc<dy
     let bytes: &[u8] = proto_msg.extract()?;
     let proto_plan =
         datafusion_proto::protobuf::PhysicalPlanNode::try_decode(bytes).map_err(|e| {
-            PyRuntimeError::new_err(format!(
+            RayError::Codec(format!(
                 "Unable to decode logical node from serialized bytes: {}",
                 e
             ))
@@ -162,27 +592,37 @@ pub fn deserialize_execution_plan(proto_msg: &Bound<PyBytes>) -> PyResult<Arc<dy
     let codec = ShuffleCodec {};
     let plan = proto_plan
         .try_into_physical_plan(&ctx, &ctx.runtime_env(), &codec)
-        .map_err(DataFusionError::from)?;
+        .map_err(codec_error)?;
 
     Ok(plan)
 }
 
-/// Execute a partition of a query plan. This will typically be executing a shuffle write and
-/// write the results to disk, except for the final query stage, which will return the data.
-/// inputs is a list of tuples of (stage_id, partition_id, bytes) for each input partition.
-fn _execute_partition(plan: Arc<dyn ExecutionPlan>, part: usize) -> Result<Vec<RecordBatch>> {
-    let ctx = Arc::new(TaskContext::new(
+/// Create the context for executing a single task. Each task gets its own runtime so that
+/// memory limits apply per task.
+pub(crate) fn create_task_context(config: &TaskRuntimeConfig) -> Result<Arc<TaskContext>> {
+    Ok(Arc::new(TaskContext::new(
         Some("task_id".to_string()),
         "session_id".to_string(),
         SessionConfig::default(),
         HashMap::new(),
         HashMap::new(),
         HashMap::new(),
-        Arc::new(RuntimeEnv::default()),
-    ));
+        config.create_runtime_env()?,
+    )))
+}
+
+/// Execute a partition of a query plan. This will typically be executing a shuffle write and
+/// write the results to disk, except for the final query stage, which will return the data.
+/// The task runs against a runtime created from `config`, which bounds its memory.
+fn _execute_partition(
+    plan: Arc<dyn ExecutionPlan>,
+    part: usize,
+    config: &TaskRuntimeConfig,
+) -> Result<Vec<RecordBatch>> {
+    let ctx = create_task_context(config)?;
 
     // create a Tokio runtime to run the async code
-    let rt = Runtime::new().unwrap();
+    let rt = Runtime::new()?;
 
     let fut: JoinHandle<Result<Vec<RecordBatch>>> = rt.spawn(async move {
         let mut stream = plan.execute(part, ctx)?;
@@ -194,6 +634,98 @@ fn _execute_partition(plan: Arc<dyn ExecutionPlan>, part: usize) -> Result<Vec<R
     });
 
     // block and wait on future
-    let results = rt.block_on(fut).unwrap()?;
+    let results = rt.block_on(fut).map_err(DataFusionError::ExecutionJoin)??;
     Ok(results)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::{is_memory_limit_error, MemoryPoolType};
+    use crate::test_utils::{TempDir, TestResult};
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::datasource::MemTable;
+    use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+    use datafusion::physical_plan::common::collect;
+
+    async fn sort_plan() -> TestResult<Arc<dyn ExecutionPlan>> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let batches = (0..256)
+            .map(|i| {
+                let values = (0..8192)
+                    .map(|j| (j * 7919 + i) % 100_000)
+                    .collect::<Vec<i64>>();
+                RecordBatch::try_new(schema.clone(), vec![Arc::new(Int64Array::from(values))])
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(1));
+        ctx.register_table("t", Arc::new(MemTable::try_new(schema, vec![batches])?))?;
+        let plan = ctx
+            .sql("SELECT a FROM t ORDER BY a")
+            .await?
+            .create_physical_plan()
+            .await?;
+        Ok(Arc::new(CoalescePartitionsExec::new(plan)))
+    }
+
+    /// Number of spills of the operators of an executed plan
+    fn spill_count(plan: &dyn ExecutionPlan) -> usize {
+        let spills = plan
+            .metrics()
+            .and_then(|metrics| metrics.spill_count())
+            .unwrap_or(0);
+        spills
+            + plan
+                .children()
+                .iter()
+                .map(|child| spill_count(child.as_ref()))
+                .sum::<usize>()
+    }
+
+    #[test]
+    fn memory_limit_exceeded() -> TestResult<()> {
+        let plan = Runtime::new()?.block_on(sort_plan())?;
+        let config = TaskRuntimeConfig::new()
+            .with_memory_limit(64 * 1024)
+            .with_memory_pool(MemoryPoolType::Greedy);
+        let err = _execute_partition(plan, 0, &config).unwrap_err();
+        assert!(is_memory_limit_error(&err), "unexpected error: {err}");
+        Ok(())
+    }
+
+    #[test]
+    fn spill_to_configured_dir() -> TestResult<()> {
+        let plan = Runtime::new()?.block_on(sort_plan())?;
+        let spill_dir = TempDir::new()?;
+        let config = TaskRuntimeConfig::new()
+            .with_memory_limit(16 * 1024 * 1024)
+            .with_memory_pool(MemoryPoolType::FairSpill)
+            .with_spill_dir(spill_dir.path_str());
+        let ctx = create_task_context(&config)?;
+        let batches = Runtime::new()?.block_on(collect(plan.execute(0, ctx.clone())?))?;
+        let values = batches
+            .iter()
+            .flat_map(|b| {
+                b.column(0)
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .unwrap()
+                    .values()
+                    .to_vec()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(256 * 8192, values.len());
+        assert!(values.windows(2).all(|w| w[0] <= w[1]));
+        // the sort ran out of memory and spilled
+        assert!(spill_count(plan.as_ref()) > 0);
+        // to the directory that the disk manager of the task creates under the configured one,
+        // which is kept until the runtime of the task is dropped
+        let dirs = std::fs::read_dir(spill_dir.path())?.collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(1, dirs.len());
+        assert!(dirs[0].file_type()?.is_dir());
+        drop(ctx);
+        assert_eq!(0, std::fs::read_dir(spill_dir.path())?.count());
+        Ok(())
+    }
+}
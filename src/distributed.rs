@@ -0,0 +1,335 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use crate::exchange::{plan_exchange_partitions, ExchangePartitionConfig};
+use crate::executor::{LocalExecutor, TaskRunner};
+use crate::planner::{make_execution_graph_with_shuffle_storage, ExecutionGraph};
+use crate::scan::plan_scan_partitions;
+use crate::shuffle::ShuffleMode;
+use crate::sql::{parse_sql, SqlStatement};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::physical_plan::ExecutionPlan;
+use datafusion::prelude::{DataFrame, SessionContext};
+use std::sync::Arc;
+
+/// Plans queries into [`ExecutionGraph`]s of query stages and executes them, from Rust.
+///
+/// Queries are planned against a DataFusion [`SessionContext`], which holds the registered
+/// tables. The query stages are executed by a [`LocalExecutor`], which schedules the tasks of
+/// each stage and hands them to a [`TaskRunner`].
+#[derive(Clone)]
+pub struct DistributedContext {
+    ctx: SessionContext,
+    /// How the query stages exchange data
+    shuffle_mode: ShuffleMode,
+    /// Path or object store URL under which shuffle files are written
+    shuffle_storage: Option<String>,
+    /// Memory-map shuffle files on the local filesystem when they are read
+    mmap_shuffle_reads: bool,
+    /// Read the map outputs of each exchange in a fixed order
+    deterministic: bool,
+    /// Bytes scanned by each task of the leaf query stages, if their scans are re-split
+    scan_task_bytes: Option<usize>,
+    /// How the partition count of each hash exchange is chosen, if not `target_partitions`
+    exchange_partitions: Option<ExchangePartitionConfig>,
+    executor: LocalExecutor,
+}
+
+impl Default for DistributedContext {
+    fn default() -> Self {
+        Self::new(SessionContext::new())
+    }
+}
+
+impl DistributedContext {
+    pub fn new(ctx: SessionContext) -> Self {
+        Self {
+            ctx,
+            shuffle_mode: ShuffleMode::Disk,
+            shuffle_storage: None,
+            mmap_shuffle_reads: false,
+            deterministic: false,
+            scan_task_bytes: None,
+            exchange_partitions: None,
+            executor: LocalExecutor::default(),
+        }
+    }
+
+    pub fn with_shuffle_mode(mut self, shuffle_mode: ShuffleMode) -> Self {
+        self.shuffle_mode = shuffle_mode;
+        self
+    }
+
+    pub fn with_shuffle_storage(mut self, shuffle_storage: Option<&str>) -> Self {
+        self.shuffle_storage = shuffle_storage.map(str::to_string);
+        self
+    }
+
+    pub fn with_mmap_shuffle_reads(mut self, mmap_shuffle_reads: bool) -> Self {
+        self.mmap_shuffle_reads = mmap_shuffle_reads;
+        self
+    }
+
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Re-split the Parquet scans of the leaf query stages so that each task scans about
+    /// `scan_task_bytes`, instead of one task per partition planned by DataFusion
+    pub fn with_scan_task_bytes(mut self, scan_task_bytes: Option<usize>) -> Self {
+        self.scan_task_bytes = scan_task_bytes;
+        self
+    }
+
+    /// Choose the partition count of each hash exchange from the estimated size of its input,
+    /// instead of using `target_partitions` for every exchange
+    pub fn with_exchange_partitions(
+        mut self,
+        exchange_partitions: Option<ExchangePartitionConfig>,
+    ) -> Self {
+        self.exchange_partitions = exchange_partitions;
+        self
+    }
+
+    pub fn with_executor(mut self, executor: LocalExecutor) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Run the tasks of each query with `runner`
+    pub fn with_task_runner(mut self, runner: Arc<dyn TaskRunner>) -> Self {
+        self.executor = self.executor.with_task_runner(runner);
+        self
+    }
+
+    pub fn session_context(&self) -> &SessionContext {
+        &self.ctx
+    }
+
+    /// Plan a SQL query, or a write such as INSERT INTO or COPY TO, into query stages
+    pub async fn plan_sql(&self, sql: &str) -> Result<ExecutionGraph> {
+        self.plan_dataframe(self.ctx.sql(sql).await?).await
+    }
+
+    /// Plan a DataFrame into query stages
+    pub async fn plan_dataframe(&self, df: DataFrame) -> Result<ExecutionGraph> {
+        self.plan_physical(df.create_physical_plan().await?).await
+    }
+
+    /// Break a physical plan into query stages
+    pub async fn plan_physical(&self, plan: Arc<dyn ExecutionPlan>) -> Result<ExecutionGraph> {
+        let plan = match self.scan_task_bytes {
+            Some(target_bytes) => {
+                plan_scan_partitions(plan, target_bytes, &self.ctx.runtime_env()).await?
+            }
+            None => plan,
+        };
+        let plan = match &self.exchange_partitions {
+            Some(config) => plan_exchange_partitions(plan, config)?,
+            None => plan,
+        };
+        let mut graph = make_execution_graph_with_shuffle_storage(
+            plan,
+            self.shuffle_mode,
+            self.shuffle_storage.as_deref(),
+        )?;
+        graph.optimize_query_stages()?;
+        if self.mmap_shuffle_reads {
+            graph.with_mmap_shuffle_reads()?;
+        }
+        if self.deterministic {
+            graph.with_deterministic_shuffle_reads()?;
+        }
+        Ok(graph)
+    }
+
+    /// Execute the query stages of a graph and return the batches of the final query stage
+    pub async fn execute(&self, graph: &ExecutionGraph) -> Result<Vec<RecordBatch>> {
+        self.executor.execute_graph(graph).await
+    }
+
+    /// Run a SQL statement, or a script of statements separated by semicolons, and return the
+    /// result of the last statement. Queries and writes are executed in query stages, the
+    /// EXPLAIN of a query or a write returns the query stages, and other statements run
+    /// against the session context. Writes return the number of rows written.
+    pub async fn sql(&self, sql: &str) -> Result<Vec<RecordBatch>> {
+        let dialect = self
+            .ctx
+            .state()
+            .config()
+            .options()
+            .sql_parser
+            .dialect
+            .clone();
+        let mut results = vec![];
+        for statement in parse_sql(sql, &dialect)? {
+            results = self.execute_statement(&statement).await?;
+        }
+        Ok(results)
+    }
+
+    async fn execute_statement(&self, statement: &SqlStatement) -> Result<Vec<RecordBatch>> {
+        match statement {
+            SqlStatement::Query(sql) | SqlStatement::Dml(sql) => {
+                let graph = self.plan_sql(sql).await?;
+                self.execute(&graph).await
+            }
+            SqlStatement::Explain {
+                verbose,
+                statement: explained,
+                ..
+            } if statement.is_distributed_explain() => {
+                let graph = self.plan_sql(explained.sql()).await?;
+                Ok(vec![graph.explain(*verbose)?])
+            }
+            statement => self.ctx.sql(statement.sql()).await?.collect().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor::LocalTaskRunner;
+    use crate::scheduler::TaskId;
+    use crate::test_utils::{TempDir, TestResult};
+    use async_trait::async_trait;
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use datafusion::datasource::MemTable;
+    use datafusion::prelude::SessionConfig;
+    use std::collections::BTreeSet;
+    use std::sync::Mutex;
+    use tokio::runtime::Runtime;
+
+    /// Runs tasks in-process and records which ones it ran
+    #[derive(Debug, Default)]
+    struct RecordingRunner {
+        tasks: Mutex<BTreeSet<TaskId>>,
+    }
+
+    #[async_trait]
+    impl TaskRunner for RecordingRunner {
+        async fn run_task(
+            &self,
+            task: TaskId,
+            plan: Arc<dyn ExecutionPlan>,
+        ) -> Result<Vec<RecordBatch>> {
+            self.tasks.lock().unwrap().insert(task);
+            LocalTaskRunner::default().run_task(task, plan).await
+        }
+    }
+
+    #[test]
+    fn execute_sql_with_task_runner() -> TestResult<()> {
+        let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(2));
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let partitions = (0..2)
+            .map(|p| {
+                let values = (0..100).map(|i| i % 7 + p).collect::<Vec<i64>>();
+                RecordBatch::try_new(schema.clone(), vec![Arc::new(Int64Array::from(values))])
+                    .map(|batch| vec![batch])
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        ctx.register_table("t", Arc::new(MemTable::try_new(schema, partitions)?))?;
+        let sql = "SELECT a, count(*) AS c FROM t GROUP BY a ORDER BY a";
+
+        let runner = Arc::new(RecordingRunner::default());
+        let shuffle_dir = TempDir::new()?;
+        let ctx = DistributedContext::new(ctx)
+            .with_shuffle_storage(Some(shuffle_dir.path_str()))
+            .with_task_runner(runner.clone());
+        let rt = Runtime::new()?;
+        let expected =
+            rt.block_on(async { ctx.session_context().sql(sql).await?.collect().await })?;
+        let graph = rt.block_on(ctx.plan_sql(sql))?;
+        let actual = rt.block_on(ctx.execute(&graph))?;
+        assert_eq!(
+            pretty_format_batches(&expected)?.to_string(),
+            pretty_format_batches(&actual)?.to_string()
+        );
+
+        let expected_tasks = graph
+            .query_stages
+            .values()
+            .flat_map(|stage| {
+                (0..stage.get_task_count()).map(|partition| TaskId {
+                    stage_id: stage.id,
+                    partition,
+                })
+            })
+            .collect::<BTreeSet<_>>();
+        assert_eq!(expected_tasks, *runner.tasks.lock().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn run_script() -> TestResult<()> {
+        let shuffle_dir = TempDir::new()?;
+        let ctx = DistributedContext::default().with_shuffle_storage(Some(shuffle_dir.path_str()));
+        let rt = Runtime::new()?;
+        let sql = "CREATE VIEW v AS SELECT * FROM (VALUES (1, 'a'), (2, 'b'), (3, 'a')) t(x, y);\
+                   SET datafusion.execution.batch_size = 1024;\
+                   SELECT y, sum(x) AS s FROM v GROUP BY y ORDER BY y";
+        let results = rt.block_on(ctx.sql(sql))?;
+        assert_eq!(
+            "+---+---+\n\
+             | y | s |\n\
+             +---+---+\n\
+             | a | 4 |\n\
+             | b | 2 |\n\
+             +---+---+",
+            pretty_format_batches(&results)?.to_string()
+        );
+
+        let results = rt.block_on(ctx.sql("EXPLAIN SELECT y, sum(x) FROM v GROUP BY y"))?;
+        let graph = rt.block_on(ctx.plan_sql("SELECT y, sum(x) FROM v GROUP BY y"))?;
+        assert_eq!(vec![graph.explain(false)?], results);
+        assert_eq!(graph.query_stages.len(), results[0].num_rows());
+        Ok(())
+    }
+
+    #[test]
+    fn run_copy_and_create_external_table() -> TestResult<()> {
+        let shuffle_dir = TempDir::new()?;
+        let data_dir = TempDir::new()?;
+        let ctx = DistributedContext::default().with_shuffle_storage(Some(shuffle_dir.path_str()));
+        let rt = Runtime::new()?;
+        let dir = data_dir.path_str();
+        let sql = format!(
+            "COPY (SELECT * FROM (VALUES (1, 'a'), (2, 'b;c')) t(x, y)) TO '{dir}/' \
+             STORED AS CSV OPTIONS ('format.has_header' 'true');\
+             CREATE EXTERNAL TABLE copied (x BIGINT, y VARCHAR) STORED AS CSV \
+             LOCATION '{dir}/' OPTIONS ('format.has_header' 'true');\
+             SELECT x, y FROM copied ORDER BY x"
+        );
+        let results = rt.block_on(ctx.sql(&sql))?;
+        assert_eq!(
+            "+---+-----+\n\
+             | x | y   |\n\
+             +---+-----+\n\
+             | 1 | a   |\n\
+             | 2 | b;c |\n\
+             +---+-----+",
+            pretty_format_batches(&results)?.to_string()
+        );
+        Ok(())
+    }
+}
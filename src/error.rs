@@ -0,0 +1,410 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use crate::runtime::is_memory_limit_error;
+use crate::shuffle::find_shuffle_fetch_failure;
+use datafusion::error::DataFusionError;
+use pyo3::create_exception;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use std::error::Error;
+use std::fmt;
+
+pub type Result<T, E = RayError> = std::result::Result<T, E>;
+
+create_exception!(
+    datafusion_ray,
+    DataFusionRayError,
+    PyRuntimeError,
+    "Base class of the exceptions raised by datafusion_ray. Exceptions raised while running a \
+     task have `stage_id` and `partition` attributes, which are None when unknown"
+);
+
+create_exception!(
+    datafusion_ray,
+    PlanningError,
+    DataFusionRayError,
+    "Raised when a query cannot be planned or split into query stages"
+);
+
+create_exception!(
+    datafusion_ray,
+    CodecError,
+    DataFusionRayError,
+    "Raised when a plan cannot be serialized or deserialized"
+);
+
+create_exception!(
+    datafusion_ray,
+    ShuffleIOError,
+    DataFusionRayError,
+    "Raised when shuffle data cannot be written or read"
+);
+
+create_exception!(
+    datafusion_ray,
+    ExecutionError,
+    DataFusionRayError,
+    "Raised when a task fails"
+);
+
+create_exception!(
+    datafusion_ray,
+    ConfigurationError,
+    DataFusionRayError,
+    "Raised when an option or setting is invalid"
+);
+
+create_exception!(
+    datafusion_ray,
+    MemoryLimitExceeded,
+    ExecutionError,
+    "Raised when a task exceeds the memory limit of its memory pool"
+);
+
+create_exception!(
+    datafusion_ray,
+    ShuffleFetchFailed,
+    ShuffleIOError,
+    "Raised when a task cannot read the shuffle output of some map tasks. The `stage_id` and \
+     `map_partitions` attributes identify the map tasks that need to be run again"
+);
+
+/// Errors surfaced by this crate. Each variant maps to a Python exception class, and errors
+/// that happen while running a task carry the query stage and partition of the task.
+#[derive(Debug)]
+pub enum RayError {
+    /// The query could not be planned or split into query stages
+    Planning(DataFusionError),
+    /// A plan could not be serialized or deserialized
+    Codec(String),
+    /// Shuffle data could not be written or read
+    ShuffleIo {
+        stage_id: Option<usize>,
+        partition: Option<usize>,
+        source: DataFusionError,
+    },
+    /// A task failed
+    Execution {
+        stage_id: Option<usize>,
+        partition: Option<usize>,
+        source: DataFusionError,
+    },
+    /// An option or setting is invalid
+    Configuration(String),
+}
+
+impl RayError {
+    /// Classify an error raised while running partition `partition` of query stage `stage_id`
+    pub fn task(stage_id: Option<usize>, partition: Option<usize>, e: DataFusionError) -> Self {
+        let e = match RayError::downcast(e) {
+            Ok(e) => return e.with_task(stage_id, partition),
+            Err(e) => e,
+        };
+        if let DataFusionError::Configuration(msg) = e {
+            RayError::Configuration(msg)
+        } else if find_shuffle_fetch_failure(&e).is_some() {
+            RayError::ShuffleIo {
+                stage_id,
+                partition,
+                source: e,
+            }
+        } else {
+            RayError::Execution {
+                stage_id,
+                partition,
+                source: e,
+            }
+        }
+    }
+
+    /// Classify an error raised while planning a query
+    pub fn planning(e: DataFusionError) -> Self {
+        match RayError::downcast(e) {
+            Ok(e) => e,
+            Err(DataFusionError::Configuration(msg)) => RayError::Configuration(msg),
+            Err(e) => RayError::Planning(e),
+        }
+    }
+
+    /// Classify an error raised while reading or writing shuffle data outside of a task
+    pub fn shuffle_io(e: DataFusionError) -> Self {
+        match RayError::downcast(e) {
+            Ok(e) => e,
+            Err(DataFusionError::Configuration(msg)) => RayError::Configuration(msg),
+            Err(e) => RayError::ShuffleIo {
+                stage_id: None,
+                partition: None,
+                source: e,
+            },
+        }
+    }
+
+    /// The error of this crate that `e` wraps, if any
+    fn downcast(e: DataFusionError) -> std::result::Result<RayError, DataFusionError> {
+        match e {
+            DataFusionError::External(e) => match e.downcast::<RayError>() {
+                Ok(e) => Ok(*e),
+                Err(e) => Err(DataFusionError::External(e)),
+            },
+            DataFusionError::Context(context, e) => {
+                RayError::downcast(*e).map_err(|e| DataFusionError::Context(context, Box::new(e)))
+            }
+            e => Err(e),
+        }
+    }
+
+    /// Attach the query stage and partition of a task, keeping any that are already known
+    fn with_task(self, stage: Option<usize>, part: Option<usize>) -> Self {
+        match self {
+            RayError::ShuffleIo {
+                stage_id,
+                partition,
+                source,
+            } => RayError::ShuffleIo {
+                stage_id: stage_id.or(stage),
+                partition: partition.or(part),
+                source,
+            },
+            RayError::Execution {
+                stage_id,
+                partition,
+                source,
+            } => RayError::Execution {
+                stage_id: stage_id.or(stage),
+                partition: partition.or(part),
+                source,
+            },
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for RayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RayError::Planning(e) => write!(f, "Planning error: {e}"),
+            RayError::Codec(msg) => write!(f, "Codec error: {msg}"),
+            RayError::ShuffleIo {
+                stage_id,
+                partition,
+                source,
+            } => {
+                write!(f, "Shuffle I/O error")?;
+                write_task(f, *stage_id, *partition)?;
+                write!(f, ": {source}")
+            }
+            RayError::Execution {
+                stage_id,
+                partition,
+                source,
+            } => {
+                write!(f, "Execution error")?;
+                write_task(f, *stage_id, *partition)?;
+                write!(f, ": {source}")
+            }
+            RayError::Configuration(msg) => write!(f, "Configuration error: {msg}"),
+        }
+    }
+}
+
+fn write_task(
+    f: &mut fmt::Formatter<'_>,
+    stage: Option<usize>,
+    part: Option<usize>,
+) -> fmt::Result {
+    match (stage, part) {
+        (Some(stage), Some(part)) => write!(f, " in query stage {stage} partition {part}"),
+        (Some(stage), None) => write!(f, " in query stage {stage}"),
+        (None, Some(part)) => write!(f, " in partition {part}"),
+        (None, None) => Ok(()),
+    }
+}
+
+impl Error for RayError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RayError::Planning(e)
+            | RayError::ShuffleIo { source: e, .. }
+            | RayError::Execution { source: e, .. } => Some(e),
+            RayError::Codec(_) | RayError::Configuration(_) => None,
+        }
+    }
+}
+
+impl From<DataFusionError> for RayError {
+    fn from(e: DataFusionError) -> Self {
+        RayError::task(None, None, e)
+    }
+}
+
+impl From<RayError> for DataFusionError {
+    fn from(e: RayError) -> Self {
+        DataFusionError::External(Box::new(e))
+    }
+}
+
+impl From<RayError> for PyErr {
+    fn from(e: RayError) -> Self {
+        Python::with_gil(|py| {
+            let msg = e.to_string();
+            let (err, attributes) = match &e {
+                RayError::Planning(_) => (PlanningError::new_err(msg), vec![]),
+                RayError::Codec(_) => (CodecError::new_err(msg), vec![]),
+                RayError::Configuration(_) => (ConfigurationError::new_err(msg), vec![]),
+                RayError::ShuffleIo {
+                    stage_id,
+                    partition,
+                    source,
+                } => match find_shuffle_fetch_failure(source) {
+                    // the stage of a fetch failure is the stage whose output was lost
+                    Some(lost) => (
+                        ShuffleFetchFailed::new_err(msg),
+                        vec![
+                            ("stage_id", lost.stage_id.into_py(py)),
+                            ("map_partitions", lost.map_partitions.into_py(py)),
+                            ("partition", partition.into_py(py)),
+                        ],
+                    ),
+                    None => (
+                        ShuffleIOError::new_err(msg),
+                        task_attributes(py, *stage_id, *partition),
+                    ),
+                },
+                RayError::Execution {
+                    stage_id,
+                    partition,
+                    source,
+                } => {
+                    let err = if is_memory_limit_error(source) {
+                        MemoryLimitExceeded::new_err(msg)
+                    } else {
+                        ExecutionError::new_err(msg)
+                    };
+                    (err, task_attributes(py, *stage_id, *partition))
+                }
+            };
+            let value = err.value_bound(py);
+            for (name, attribute) in attributes {
+                if let Err(e) = value.setattr(name, attribute) {
+                    return e;
+                }
+            }
+            err
+        })
+    }
+}
+
+fn task_attributes(
+    py: Python<'_>,
+    stage_id: Option<usize>,
+    partition: Option<usize>,
+) -> Vec<(&'static str, PyObject)> {
+    vec![
+        ("stage_id", stage_id.into_py(py)),
+        ("partition", partition.into_py(py)),
+    ]
+}
+
+/// Register the exception classes with the Python module
+pub(crate) fn register_exceptions(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add(
+        "DataFusionRayError",
+        py.get_type_bound::<DataFusionRayError>(),
+    )?;
+    m.add("PlanningError", py.get_type_bound::<PlanningError>())?;
+    m.add("CodecError", py.get_type_bound::<CodecError>())?;
+    m.add("ShuffleIOError", py.get_type_bound::<ShuffleIOError>())?;
+    m.add("ExecutionError", py.get_type_bound::<ExecutionError>())?;
+    m.add(
+        "ConfigurationError",
+        py.get_type_bound::<ConfigurationError>(),
+    )?;
+    m.add(
+        "MemoryLimitExceeded",
+        py.get_type_bound::<MemoryLimitExceeded>(),
+    )?;
+    m.add(
+        "ShuffleFetchFailed",
+        py.get_type_bound::<ShuffleFetchFailed>(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shuffle::ShuffleFileCorrupted;
+
+    #[test]
+    fn classify_task_errors() {
+        let err = RayError::task(
+            Some(1),
+            Some(2),
+            DataFusionError::Execution("boom".to_string()),
+        );
+        assert!(matches!(
+            err,
+            RayError::Execution {
+                stage_id: Some(1),
+                partition: Some(2),
+                ..
+            }
+        ));
+        assert_eq!(
+            "Execution error in query stage 1 partition 2: Execution error: boom",
+            err.to_string()
+        );
+
+        let corrupted = ShuffleFileCorrupted {
+            stage_id: 0,
+            map_partition: 3,
+            path: "/tmp/x.arrow".to_string(),
+            expected: 1,
+            actual: 2,
+        };
+        let err = RayError::task(Some(1), Some(0), corrupted.into());
+        assert!(matches!(err, RayError::ShuffleIo { .. }));
+
+        let err = RayError::task(None, None, DataFusionError::Configuration("x".to_string()));
+        assert!(matches!(err, RayError::Configuration(_)));
+    }
+
+    #[test]
+    fn downcast_errors_of_this_crate() {
+        let codec: DataFusionError = RayError::Codec("bad plan".to_string()).into();
+        let err = RayError::task(Some(1), Some(2), codec.context("decoding"));
+        assert!(matches!(err, RayError::Codec(msg) if msg == "bad plan"));
+
+        let execution: DataFusionError = RayError::Execution {
+            stage_id: None,
+            partition: Some(4),
+            source: DataFusionError::Execution("boom".to_string()),
+        }
+        .into();
+        let err = RayError::planning(execution);
+        assert!(matches!(
+            err,
+            RayError::Execution {
+                stage_id: None,
+                partition: Some(4),
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,360 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Partition counts of the hash exchanges between query stages.
+//!
+//! DataFusion hash partitions every exchange into `target_partitions` partitions.
+//! [`plan_exchange_partitions`] chooses the partition count of each hash exchange from the
+//! estimated size of its input instead. Exchanges whose outputs are joined partition by
+//! partition, such as the two inputs of a partitioned hash join, get the same count.
+
+use crate::query_stage::file_scan_config;
+use datafusion::error::Result;
+use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+use datafusion::physical_plan::repartition::RepartitionExec;
+use datafusion::physical_plan::sorts::sort_preserving_merge::SortPreservingMergeExec;
+use datafusion::physical_plan::{with_new_children_if_necessary, ExecutionPlan, Partitioning};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Settings for choosing the partition count of each hash exchange from the size of its input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExchangePartitionConfig {
+    /// Fewest partitions of an exchange
+    pub min_partitions: usize,
+    /// Most partitions of an exchange
+    pub max_partitions: usize,
+    /// Estimated bytes of input per partition
+    pub target_partition_bytes: usize,
+}
+
+impl Default for ExchangePartitionConfig {
+    fn default() -> Self {
+        Self {
+            min_partitions: 1,
+            max_partitions: 1024,
+            target_partition_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl ExchangePartitionConfig {
+    /// The partition count of an exchange with `bytes` of input
+    fn partition_count(&self, bytes: usize) -> usize {
+        let target = self.target_partition_bytes.max(1);
+        let count = bytes / target + usize::from(bytes % target != 0);
+        count
+            .min(self.max_partitions)
+            .max(self.min_partitions)
+            .max(1)
+    }
+}
+
+/// Set the partition count of each hash repartition of a plan from the estimated size of its
+/// input. The exchanges whose size cannot be estimated keep their partition count.
+pub fn plan_exchange_partitions(
+    plan: Arc<dyn ExecutionPlan>,
+    config: &ExchangePartitionConfig,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let mut groups = ExchangeGroups::default();
+    groups.visit(&plan);
+
+    // all the exchanges of a group get the count of their combined input
+    let mut counts = HashMap::new();
+    for exchanges in groups.groups() {
+        let bytes = exchanges
+            .iter()
+            .filter_map(|key| groups.bytes[key])
+            .collect::<Vec<_>>();
+        if bytes.is_empty() {
+            continue;
+        }
+        let count = config.partition_count(bytes.iter().sum());
+        debug!("Using {count} partitions for the exchanges with input of {bytes:?} bytes");
+        for key in exchanges {
+            counts.insert(key, count);
+        }
+    }
+    with_partition_counts(plan, &counts)
+}
+
+/// Identifies a node of a plan while the plan is not modified
+fn node_key(plan: &Arc<dyn ExecutionPlan>) -> usize {
+    Arc::as_ptr(plan) as *const () as usize
+}
+
+/// The hash repartitions of a plan, grouped by the partitioning their outputs must share
+#[derive(Debug, Default)]
+struct ExchangeGroups {
+    /// Estimated input bytes of each hash repartition
+    bytes: HashMap<usize, Option<usize>>,
+    /// Group of each hash repartition
+    group: HashMap<usize, usize>,
+}
+
+impl ExchangeGroups {
+    fn visit(&mut self, plan: &Arc<dyn ExecutionPlan>) {
+        for child in plan.children() {
+            self.visit(child);
+        }
+        if let Some(repart) = plan.as_any().downcast_ref::<RepartitionExec>() {
+            if matches!(repart.partitioning(), Partitioning::Hash(_, _)) {
+                let key = node_key(plan);
+                self.bytes
+                    .insert(key, estimated_bytes(repart.input().as_ref()));
+                self.group.insert(key, key);
+            }
+        }
+        // the inputs of a plan with as many partitions as each of them are joined partition
+        // by partition, so the exchanges they come from must have the same count
+        let count = partition_count(plan.as_ref());
+        let co_partitioned = plan
+            .children()
+            .into_iter()
+            .filter(|child| partition_count(child.as_ref()) == count)
+            .collect::<Vec<_>>();
+        if co_partitioned.len() > 1 && !is_merge(plan.as_ref()) {
+            let mut exchanges = vec![];
+            for child in co_partitioned {
+                source_exchanges(child, &mut exchanges);
+            }
+            for pair in exchanges.windows(2) {
+                self.merge(pair[0], pair[1]);
+            }
+        }
+    }
+
+    /// Put the exchanges of the groups of `a` and `b` in one group
+    fn merge(&mut self, a: usize, b: usize) {
+        let (Some(from), Some(to)) = (self.group.get(&b).copied(), self.group.get(&a).copied())
+        else {
+            return;
+        };
+        for group in self.group.values_mut() {
+            if *group == from {
+                *group = to;
+            }
+        }
+    }
+
+    /// The hash repartitions of each group
+    fn groups(&self) -> Vec<Vec<usize>> {
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (key, group) in &self.group {
+            groups.entry(*group).or_default().push(*key);
+        }
+        groups.into_values().collect()
+    }
+}
+
+fn partition_count(plan: &dyn ExecutionPlan) -> usize {
+    plan.properties().output_partitioning().partition_count()
+}
+
+fn is_merge(plan: &dyn ExecutionPlan) -> bool {
+    plan.as_any().is::<CoalescePartitionsExec>() || plan.as_any().is::<SortPreservingMergeExec>()
+}
+
+/// The hash repartitions whose partitions are the partitions of a plan, found through the
+/// operators that keep the partitions of their inputs
+fn source_exchanges(plan: &Arc<dyn ExecutionPlan>, exchanges: &mut Vec<usize>) {
+    if let Some(repart) = plan.as_any().downcast_ref::<RepartitionExec>() {
+        if matches!(repart.partitioning(), Partitioning::Hash(_, _)) {
+            exchanges.push(node_key(plan));
+            return;
+        }
+    }
+    if is_merge(plan.as_ref()) {
+        return;
+    }
+    let count = partition_count(plan.as_ref());
+    for child in plan.children() {
+        if partition_count(child.as_ref()) == count {
+            source_exchanges(child, exchanges);
+        }
+    }
+}
+
+/// Estimated size of the output of a plan, from its statistics or else from the size of the
+/// files it scans
+fn estimated_bytes(plan: &dyn ExecutionPlan) -> Option<usize> {
+    if let Some(bytes) = plan
+        .statistics()
+        .ok()
+        .and_then(|stats| stats.total_byte_size.get_value().copied())
+    {
+        return Some(bytes);
+    }
+    if let Some(config) = file_scan_config(plan) {
+        let bytes = config
+            .file_groups
+            .iter()
+            .flatten()
+            .map(|file| match &file.range {
+                Some(range) => (range.end - range.start) as usize,
+                None => file.object_meta.size,
+            })
+            .sum();
+        return Some(bytes);
+    }
+    let children = plan
+        .children()
+        .into_iter()
+        .filter_map(|child| estimated_bytes(child.as_ref()))
+        .collect::<Vec<_>>();
+    if children.is_empty() {
+        None
+    } else {
+        Some(children.into_iter().sum())
+    }
+}
+
+/// Copy of a plan whose hash repartitions have the partition counts of `counts`, by node
+fn with_partition_counts(
+    plan: Arc<dyn ExecutionPlan>,
+    counts: &HashMap<usize, usize>,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let count = counts.get(&node_key(&plan)).copied();
+    let children = plan
+        .children()
+        .into_iter()
+        .map(|child| with_partition_counts(child.clone(), counts))
+        .collect::<Result<Vec<_>>>()?;
+    if let (Some(repart), Some(count)) = (plan.as_any().downcast_ref::<RepartitionExec>(), count) {
+        if let Partitioning::Hash(exprs, _) = repart.partitioning() {
+            let mut exec = RepartitionExec::try_new(
+                children[0].clone(),
+                Partitioning::Hash(exprs.clone(), count),
+            )?;
+            if repart.preserve_order() {
+                exec = exec.with_preserve_order();
+            }
+            return Ok(Arc::new(exec));
+        }
+    }
+    with_new_children_if_necessary(plan, children)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::distributed::DistributedContext;
+    use crate::test_utils::{TempDir, TestResult};
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use datafusion::datasource::MemTable;
+    use datafusion::prelude::{SessionConfig, SessionContext};
+    use tokio::runtime::Runtime;
+
+    /// The partition counts of the hash repartitions of a plan
+    fn hash_partition_counts(plan: &Arc<dyn ExecutionPlan>) -> Vec<usize> {
+        let mut counts = vec![];
+        if let Some(repart) = plan.as_any().downcast_ref::<RepartitionExec>() {
+            if let Partitioning::Hash(_, count) = repart.partitioning() {
+                counts.push(*count);
+            }
+        }
+        for child in plan.children() {
+            counts.extend(hash_partition_counts(child));
+        }
+        counts
+    }
+
+    fn table(rows: i64) -> TestResult<Arc<MemTable>> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int64, false),
+            Field::new("v", DataType::Int64, false),
+        ]));
+        let partitions = (0..2)
+            .map(|p| {
+                let keys = (0..rows).map(|i| (i + p) % 10).collect::<Vec<i64>>();
+                let values = (0..rows).collect::<Vec<i64>>();
+                RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(Int64Array::from(keys)),
+                        Arc::new(Int64Array::from(values)),
+                    ],
+                )
+                .map(|batch| vec![batch])
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(Arc::new(MemTable::try_new(schema, partitions)?))
+    }
+
+    #[test]
+    fn partition_counts_from_input_size() -> TestResult<()> {
+        let config = SessionConfig::new()
+            .with_target_partitions(4)
+            .set_usize(
+                "datafusion.optimizer.hash_join_single_partition_threshold",
+                0,
+            )
+            .set_usize(
+                "datafusion.optimizer.hash_join_single_partition_threshold_rows",
+                0,
+            );
+        let ctx = SessionContext::new_with_config(config);
+        ctx.register_table("big", table(10_000)?)?;
+        ctx.register_table("small", table(100)?)?;
+        let sql = "SELECT b.k, count(*) AS c, sum(s.v) AS v \
+                   FROM big b JOIN small s ON b.k = s.k \
+                   GROUP BY b.k ORDER BY b.k";
+        let rt = Runtime::new()?;
+        let plan = rt.block_on(async { ctx.sql(sql).await?.create_physical_plan().await })?;
+        assert!(hash_partition_counts(&plan).iter().all(|count| *count == 4));
+
+        let config = ExchangePartitionConfig {
+            min_partitions: 2,
+            max_partitions: 8,
+            target_partition_bytes: 16 * 1024,
+        };
+        let planned = plan_exchange_partitions(plan.clone(), &config)?;
+        let counts = hash_partition_counts(&planned);
+        // the inputs of the join have the same count, which is driven by the big table
+        assert_eq!(vec![8, 8], counts);
+
+        let config = ExchangePartitionConfig {
+            target_partition_bytes: usize::MAX,
+            ..config
+        };
+        let planned = plan_exchange_partitions(plan, &config)?;
+        assert_eq!(vec![2, 2], hash_partition_counts(&planned));
+
+        // the query returns the same result with any partition counts
+        let expected = rt.block_on(async { ctx.sql(sql).await?.collect().await })?;
+        let shuffle_dir = TempDir::new()?;
+        for target_partition_bytes in [1, 16 * 1024, usize::MAX] {
+            let config = ExchangePartitionConfig {
+                target_partition_bytes,
+                ..config
+            };
+            let ctx = DistributedContext::new(ctx.clone())
+                .with_shuffle_storage(Some(shuffle_dir.path_str()))
+                .with_exchange_partitions(Some(config));
+            let actual = rt.block_on(ctx.sql(sql))?;
+            assert_eq!(
+                pretty_format_batches(&expected)?.to_string(),
+                pretty_format_batches(&actual)?.to_string()
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use crate::context::create_task_context;
+use crate::planner::ExecutionGraph;
+use crate::runtime::TaskRuntimeConfig;
+use crate::scheduler::{SpeculationConfig, StageScheduler, TaskId, DEFAULT_MAX_TASK_ATTEMPTS};
+use crate::shuffle::{
+    find_shuffle_fetch_failure, map_task_rows, start_shuffle_server, with_shuffle_inputs,
+    ShuffleMode,
+};
+use async_trait::async_trait;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::common::collect;
+use datafusion::physical_plan::ExecutionPlan;
+use log::debug;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Builder;
+use tokio::task::{AbortHandle, JoinSet};
+
+/// Runs the tasks of a query. A task executes one partition of the plan of a query stage and
+/// returns the batches it produces, which for all but the final query stage describe the
+/// shuffle output of the task.
+///
+/// Implementations can run tasks in-process, like [`LocalTaskRunner`], or send them to remote
+/// workers, in which case the plan can be serialized with the
+/// [`ShuffleCodec`](crate::shuffle::ShuffleCodec).
+#[async_trait]
+pub trait TaskRunner: Debug + Send + Sync {
+    async fn run_task(
+        &self,
+        task: TaskId,
+        plan: Arc<dyn ExecutionPlan>,
+    ) -> Result<Vec<RecordBatch>>;
+}
+
+/// Runs each task in this process, against a runtime created from a [`TaskRuntimeConfig`]
+#[derive(Debug, Clone, Default)]
+pub struct LocalTaskRunner {
+    config: TaskRuntimeConfig,
+}
+
+impl LocalTaskRunner {
+    pub fn new(config: TaskRuntimeConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl TaskRunner for LocalTaskRunner {
+    async fn run_task(
+        &self,
+        task: TaskId,
+        plan: Arc<dyn ExecutionPlan>,
+    ) -> Result<Vec<RecordBatch>> {
+        let ctx = create_task_context(&self.config)?;
+        collect(plan.execute(task.partition, ctx)?).await
+    }
+}
+
+/// Executes an [`ExecutionGraph`] without Ray.
+///
+/// Tasks are scheduled by a [`StageScheduler`] and run concurrently by a [`TaskRunner`], which
+/// by default runs them on a thread pool in this process. Stages exchange data through the same
+/// shuffle files that the Ray workers use.
+#[derive(Debug, Clone)]
+pub struct LocalExecutor {
+    /// Number of threads used to run tasks
+    concurrency: usize,
+    /// Runs each task
+    runner: Arc<dyn TaskRunner>,
+    /// Number of times a task is attempted before the query fails
+    max_task_attempts: usize,
+    /// Launch duplicate attempts of slow tasks when set
+    speculation: Option<SpeculationConfig>,
+}
+
+/// How often to look for slow tasks while waiting for tasks to complete
+const SPECULATION_INTERVAL: Duration = Duration::from_millis(100);
+
+impl Default for LocalExecutor {
+    fn default() -> Self {
+        let concurrency = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Self::new(concurrency)
+    }
+}
+
+impl LocalExecutor {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            concurrency: concurrency.max(1),
+            runner: Arc::new(LocalTaskRunner::default()),
+            max_task_attempts: DEFAULT_MAX_TASK_ATTEMPTS,
+            speculation: None,
+        }
+    }
+
+    /// Run the tasks in this process with the given runtime settings
+    pub fn with_task_config(mut self, config: TaskRuntimeConfig) -> Self {
+        self.runner = Arc::new(LocalTaskRunner::new(config));
+        self
+    }
+
+    pub fn with_task_runner(mut self, runner: Arc<dyn TaskRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    pub fn with_max_task_attempts(mut self, max_task_attempts: usize) -> Self {
+        self.max_task_attempts = max_task_attempts;
+        self
+    }
+
+    pub fn with_speculation(mut self, speculation: SpeculationConfig) -> Self {
+        self.speculation = Some(speculation);
+        self
+    }
+
+    /// Execute all query stages on a new thread pool and return the batches produced by the
+    /// final query stage
+    pub fn execute(&self, graph: &ExecutionGraph) -> Result<Vec<RecordBatch>> {
+        let rt = Builder::new_multi_thread()
+            .worker_threads(self.concurrency)
+            .enable_all()
+            .build()?;
+        rt.block_on(self.execute_graph(graph))
+    }
+
+    /// Execute all query stages on the current Tokio runtime and return the batches produced
+    /// by the final query stage
+    pub async fn execute_graph(&self, graph: &ExecutionGraph) -> Result<Vec<RecordBatch>> {
+        let mut scheduler =
+            StageScheduler::try_new(graph)?.with_max_task_attempts(self.max_task_attempts);
+        if let Some(speculation) = self.speculation {
+            scheduler = scheduler.with_speculation(speculation);
+        }
+        let final_stage_id = scheduler.final_stage_id();
+        let mut running = JoinSet::new();
+        // the running attempts of each task, which are aborted when the task is cancelled
+        let mut attempts: HashMap<TaskId, Vec<(usize, AbortHandle)>> = HashMap::new();
+        // aborted attempts, whose outcome is ignored even if they finished before the abort
+        let mut aborted = HashSet::new();
+        let mut next_attempt = 0;
+        let mut results = BTreeMap::new();
+        if graph.shuffle_mode == ShuffleMode::Flight {
+            // all tasks run in this process, so a single shuffle server serves every file
+            start_shuffle_server("127.0.0.1", 0)?;
+        }
+        // task results by stage and partition, which hold the shuffle output in memory mode and
+        // its location in flight mode
+        let mut outputs: HashMap<usize, BTreeMap<usize, Vec<RecordBatch>>> = HashMap::new();
+        while !scheduler.is_complete() {
+            for task in scheduler.next_runnable_tasks() {
+                let stage = scheduler.query_stage(task.stage_id)?;
+                let mut plan = stage.plan.clone();
+                if graph.shuffle_mode != ShuffleMode::Disk {
+                    let inputs = stage
+                        .get_child_stage_ids()
+                        .into_iter()
+                        .map(|id| {
+                            let batches = outputs.get(&id).map_or(vec![], |stage_outputs| {
+                                stage_outputs.values().flatten().cloned().collect()
+                            });
+                            (id, batches)
+                        })
+                        .collect();
+                    plan = with_shuffle_inputs(plan, &inputs)?;
+                }
+                let runner = self.runner.clone();
+                debug!("LocalExecutor launching task {task}");
+                let attempt = next_attempt;
+                next_attempt += 1;
+                let handle = running
+                    .spawn(async move { (task, attempt, runner.run_task(task, plan).await) });
+                attempts.entry(task).or_default().push((attempt, handle));
+            }
+
+            let joined = if self.speculation.is_some() && !running.is_empty() {
+                // wake up periodically so that slow tasks can be speculated
+                match tokio::time::timeout(SPECULATION_INTERVAL, running.join_next()).await {
+                    Ok(joined) => joined,
+                    Err(_) => continue,
+                }
+            } else {
+                running.join_next().await
+            };
+            let Some(joined) = joined else {
+                return Err(DataFusionError::Internal(
+                    "No tasks are running but the query has not completed".to_string(),
+                ));
+            };
+            let (task, result) = match joined {
+                // an attempt of a cancelled task
+                Ok((_, attempt, _)) if aborted.contains(&attempt) => continue,
+                Ok((task, _, result)) => (task, result),
+                Err(e) if e.is_cancelled() => continue,
+                Err(e) => return Err(DataFusionError::ExecutionJoin(e)),
+            };
+            match result {
+                Ok(batches) => {
+                    if !scheduler.task_succeeded(task)? {
+                        continue;
+                    }
+                    if task.stage_id == final_stage_id {
+                        results.insert(task.partition, batches);
+                        continue;
+                    }
+                    if let Some(rows) = map_task_rows(&batches) {
+                        scheduler.task_output_rows(task, rows)?;
+                    }
+                    outputs
+                        .entry(task.stage_id)
+                        .or_default()
+                        .insert(task.partition, batches);
+                    for cancelled in scheduler.take_cancelled_tasks() {
+                        debug!("LocalExecutor cancelling task {cancelled}");
+                        for (attempt, handle) in attempts.remove(&cancelled).unwrap_or_default() {
+                            handle.abort();
+                            aborted.insert(attempt);
+                        }
+                        let stage = scheduler.query_stage(cancelled.stage_id)?;
+                        let batch = stage.commit_empty_output(cancelled.partition).await?;
+                        outputs
+                            .entry(cancelled.stage_id)
+                            .or_default()
+                            .insert(cancelled.partition, vec![batch]);
+                    }
+                }
+                Err(e) => {
+                    if let Some(lost) = find_shuffle_fetch_failure(&e) {
+                        debug!("Task {task} could not read its shuffle input: {e}");
+                        scheduler.map_outputs_lost(task, lost.stage_id, &lost.map_partitions)?;
+                    } else if scheduler.task_failed(task, &e.to_string()).is_err() {
+                        let attempts = scheduler.task_attempts(task)?;
+                        return Err(
+                            e.context(format!("Task {task} failed after {attempts} attempts"))
+                        );
+                    }
+                }
+            }
+        }
+        Ok(results.into_values().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::planner::make_execution_graph;
+    use crate::test_utils::{ShuffleDirs, TestResult};
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use datafusion::datasource::MemTable;
+    use datafusion::prelude::{SessionConfig, SessionContext};
+    use std::sync::Arc;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn execute_aggregate_and_join() -> TestResult<()> {
+        let sql = "SELECT k.name, count(*) AS c, sum(v.v) AS s \
+                   FROM v JOIN k ON v.k = k.k \
+                   GROUP BY k.name ORDER BY k.name";
+        let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(3));
+        register_tables(&ctx)?;
+
+        let rt = Runtime::new()?;
+        let expected = rt.block_on(async { ctx.sql(sql).await?.collect().await })?;
+        let plan = rt.block_on(async { ctx.sql(sql).await?.create_physical_plan().await })?;
+        drop(rt);
+
+        let graph = make_execution_graph(plan)?;
+        let _shuffle_dirs = ShuffleDirs::of(&graph);
+        assert!(graph.query_stages.len() > 1);
+
+        let actual = LocalExecutor::new(4).execute(&graph)?;
+        assert_eq!(
+            pretty_format_batches(&expected)?.to_string(),
+            pretty_format_batches(&actual)?.to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn execute_with_speculation() -> TestResult<()> {
+        let sql = "SELECT k.name, sum(v.v) AS s FROM v JOIN k ON v.k = k.k \
+                   GROUP BY k.name ORDER BY k.name";
+        let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(3));
+        register_tables(&ctx)?;
+
+        let rt = Runtime::new()?;
+        let expected = rt.block_on(async { ctx.sql(sql).await?.collect().await })?;
+        let plan = rt.block_on(async { ctx.sql(sql).await?.create_physical_plan().await })?;
+        drop(rt);
+
+        // speculate every task that is still running once another task of its stage succeeded
+        let speculation = SpeculationConfig {
+            quantile: 0.0,
+            multiplier: 0.0,
+            min_runtime: Duration::ZERO,
+        };
+        let graph = make_execution_graph(plan)?;
+        let _shuffle_dirs = ShuffleDirs::of(&graph);
+        let actual = LocalExecutor::new(4)
+            .with_speculation(speculation)
+            .execute(&graph)?;
+        assert_eq!(
+            pretty_format_batches(&expected)?.to_string(),
+            pretty_format_batches(&actual)?.to_string()
+        );
+        Ok(())
+    }
+
+    fn register_tables(ctx: &SessionContext) -> TestResult<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int64, false),
+            Field::new("v", DataType::Int64, false),
+        ]));
+        let partitions = (0..4)
+            .map(|p| {
+                let keys = (0..1000).map(|i| (i * 31 + p) % 10).collect::<Vec<i64>>();
+                let values = (0..1000).map(|i| i + p * 1000).collect::<Vec<i64>>();
+                RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(Int64Array::from(keys)),
+                        Arc::new(Int64Array::from(values)),
+                    ],
+                )
+                .map(|batch| vec![batch])
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        ctx.register_table("v", Arc::new(MemTable::try_new(schema, partitions)?))?;
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int64Array::from((0..10).collect::<Vec<i64>>())),
+                Arc::new(StringArray::from(
+                    (0..10).map(|i| format!("key{i}")).collect::<Vec<_>>(),
+                )),
+            ],
+        )?;
+        ctx.register_table("k", Arc::new(MemTable::try_new(schema, vec![vec![batch]])?))?;
+        Ok(())
+    }
+}
@@ -20,13 +20,32 @@ extern crate core;
 use pyo3::prelude::*;
 
 mod proto;
-use crate::context::execute_partition;
+use crate::context::{execute_partition, start_shuffle_server};
 pub use proto::generated::protobuf;
 
+// the pyo3 macros convert the results of Python methods with a redundant `.into()`, which
+// the modules that define Python classes allow
+#[allow(clippy::useless_conversion)]
 pub mod context;
+pub mod distributed;
+pub mod error;
+pub mod exchange;
+pub mod executor;
+pub mod limit;
+#[allow(clippy::useless_conversion)]
 pub mod planner;
+#[allow(clippy::useless_conversion)]
 pub mod query_stage;
+pub mod runtime;
+pub mod scan;
+#[allow(clippy::useless_conversion)]
+pub mod scheduler;
 pub mod shuffle;
+pub mod sink;
+pub mod sql;
+pub mod table;
+#[cfg(test)]
+mod test_utils;
 
 /// A Python module implemented in Rust.
 #[pymodule]
@@ -35,6 +54,10 @@ fn _datafusion_ray_internal(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<context::PyContext>()?;
     m.add_class::<planner::PyExecutionGraph>()?;
     m.add_class::<query_stage::PyQueryStage>()?;
+    m.add_class::<scheduler::PyStageScheduler>()?;
+    m.add_class::<sql::PySqlStatement>()?;
     m.add_function(wrap_pyfunction!(execute_partition, m)?)?;
+    m.add_function(wrap_pyfunction!(start_shuffle_server, m)?)?;
+    error::register_exceptions(m)?;
     Ok(())
 }
@@ -0,0 +1,235 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Distributed execution of queries with a LIMIT.
+//!
+//! A LIMIT is applied by a [`GlobalLimitExec`] over the merged partitions of its input, which
+//! becomes an exchange between query stages. [`plan_distributed_limit`] limits each partition
+//! of that input, so that each map task of the stage below the exchange writes at most as
+//! many rows as the query returns. When the rows do not have to be sorted, the final rows can
+//! come from any map tasks, and [`stage_row_limits`] tells the scheduler how many rows of a
+//! stage are enough to cancel its remaining tasks.
+
+use crate::shuffle::{MemoryShuffleReaderExec, ShuffleReaderExec};
+use datafusion::common::tree_node::{Transformed, TreeNode};
+use datafusion::error::Result;
+use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+use datafusion::physical_plan::limit::{GlobalLimitExec, LocalLimitExec};
+use datafusion::physical_plan::sorts::sort_preserving_merge::SortPreservingMergeExec;
+use datafusion::physical_plan::ExecutionPlan;
+use std::sync::Arc;
+
+/// Limit each input partition of the merges that are followed by a LIMIT, or that have a
+/// limit of their own, to the number of rows that the limit needs
+pub fn plan_distributed_limit(plan: Arc<dyn ExecutionPlan>) -> Result<Arc<dyn ExecutionPlan>> {
+    plan.transform_up(|node| {
+        let any = node.as_any();
+        let limited = if let Some(limit) = any.downcast_ref::<GlobalLimitExec>() {
+            match limit.fetch() {
+                Some(fetch) => limit_merge_input(limit.input(), limit.skip() + fetch)?
+                    .map(|merge| node.clone().with_new_children(vec![merge]))
+                    .transpose()?,
+                None => None,
+            }
+        } else if let Some(merge) = any.downcast_ref::<SortPreservingMergeExec>() {
+            match merge.fetch() {
+                Some(fetch) => limit_merge_input(&node, fetch)?,
+                None => None,
+            }
+        } else {
+            None
+        };
+        Ok(match limited {
+            Some(node) => Transformed::yes(node),
+            None => Transformed::no(node),
+        })
+    })
+    .map(|transformed| transformed.data)
+}
+
+/// Copy of a merge of partitions whose input partitions are each limited to `limit` rows, or
+/// `None` if the plan is not a merge or its input is already limited
+fn limit_merge_input(
+    plan: &Arc<dyn ExecutionPlan>,
+    limit: usize,
+) -> Result<Option<Arc<dyn ExecutionPlan>>> {
+    let any = plan.as_any();
+    if !any.is::<CoalescePartitionsExec>() && !any.is::<SortPreservingMergeExec>() {
+        return Ok(None);
+    }
+    let input = plan.children()[0];
+    if input.fetch().map_or(false, |fetch| fetch <= limit)
+        || input.properties().output_partitioning().partition_count() < 2
+    {
+        return Ok(None);
+    }
+    // a local limit keeps the order of its input, which a sorted merge relies on
+    let input = Arc::new(LocalLimitExec::new(input.clone(), limit));
+    Ok(Some(plan.clone().with_new_children(vec![input])?))
+}
+
+/// The query stages read by a plan whose output is only needed up to a number of rows,
+/// because they are merged into a LIMIT that does not sort them. Returns the id of each such
+/// stage and the number of rows it needs to produce, counting the rows the LIMIT skips.
+/// Stages read through a filter are left out, because the rows written by their map tasks
+/// are not all returned by the reader.
+pub fn stage_row_limits(plan: &dyn ExecutionPlan) -> Vec<(usize, usize)> {
+    let mut limits = vec![];
+    if let Some(limit) = plan.as_any().downcast_ref::<GlobalLimitExec>() {
+        let merged = limit
+            .input()
+            .as_any()
+            .downcast_ref::<CoalescePartitionsExec>();
+        if let (Some(fetch), Some(coalesce)) = (limit.fetch(), merged) {
+            let reader = coalesce.input().as_any();
+            let stage_id = if let Some(reader) = reader.downcast_ref::<ShuffleReaderExec>() {
+                reader.filter.is_none().then_some(reader.stage_id)
+            } else {
+                reader
+                    .downcast_ref::<MemoryShuffleReaderExec>()
+                    .map(|reader| reader.stage_id)
+            };
+            if let Some(stage_id) = stage_id {
+                limits.push((stage_id, limit.skip() + fetch));
+            }
+        }
+    }
+    for child in plan.children() {
+        limits.extend(stage_row_limits(child.as_ref()));
+    }
+    limits
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor::LocalExecutor;
+    use crate::planner::make_execution_graph;
+    use crate::shuffle::{LocalShuffleStorage, ShuffleWriterExec};
+    use crate::test_utils::{ShuffleDirs, TestResult};
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::datasource::MemTable;
+    use datafusion::logical_expr::Operator;
+    use datafusion::physical_expr::expressions::{col, lit, BinaryExpr};
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::{displayable, Partitioning};
+    use datafusion::prelude::{SessionConfig, SessionContext};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn limit_merge_inputs() -> TestResult<()> {
+        let batches = partitions(4)?;
+        let input = Arc::new(MemoryExec::try_new(&batches, batches[0][0].schema(), None)?);
+        let merge = Arc::new(CoalescePartitionsExec::new(input));
+        let plan = Arc::new(GlobalLimitExec::new(merge, 5, Some(10)));
+
+        let plan = plan_distributed_limit(plan)?;
+        let expected = "GlobalLimitExec: skip=5, fetch=10\
+                        \n  CoalescePartitionsExec\
+                        \n    LocalLimitExec: fetch=15\
+                        \n      MemoryExec: partitions=4, partition_sizes=[1, 1, 1, 1]\n";
+        assert_eq!(
+            expected,
+            displayable(plan.as_ref()).indent(false).to_string()
+        );
+
+        // planning again does not add another limit
+        let plan = plan_distributed_limit(plan)?;
+        assert_eq!(
+            expected,
+            displayable(plan.as_ref()).indent(false).to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn execute_limit_with_row_limits() -> TestResult<()> {
+        let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(4));
+        let batches = partitions(8)?;
+        let table = MemTable::try_new(batches[0][0].schema(), batches)?;
+        ctx.register_table("t", Arc::new(table))?;
+
+        let rt = Runtime::new()?;
+        let plan = rt.block_on(async {
+            ctx.sql("SELECT a FROM t WHERE a % 2 = 0 LIMIT 10")
+                .await?
+                .create_physical_plan()
+                .await
+        })?;
+        drop(rt);
+
+        let graph = make_execution_graph(plan)?;
+        let _shuffle_dirs = ShuffleDirs::of(&graph);
+        let final_stage = graph.get_final_query_stage()?;
+        let limits = stage_row_limits(final_stage.plan.as_ref());
+        assert_eq!(1, limits.len());
+        let (stage_id, rows) = limits[0];
+        assert_eq!(10, rows);
+
+        // each map task writes at most as many rows as the query returns
+        let map_stage = &graph.query_stages[&stage_id];
+        let writer = map_stage
+            .plan
+            .as_any()
+            .downcast_ref::<ShuffleWriterExec>()
+            .unwrap();
+        assert_eq!(Some(10), writer.children()[0].fetch());
+
+        let actual = LocalExecutor::new(1).execute(&graph)?;
+        let rows = actual.iter().map(|b| b.num_rows()).sum::<usize>();
+        assert_eq!(10, rows);
+        Ok(())
+    }
+
+    #[test]
+    fn no_row_limits_through_filtered_reader() -> TestResult<()> {
+        let schema = partitions(1)?[0][0].schema();
+        let partitioning = Partitioning::UnknownPartitioning(4);
+        let storage = Arc::new(LocalShuffleStorage::new("/tmp"));
+        let reader = ShuffleReaderExec::new(0, schema.clone(), partitioning, storage, 4);
+        let limit_of = |reader: ShuffleReaderExec| {
+            let merge = Arc::new(CoalescePartitionsExec::new(Arc::new(reader)));
+            GlobalLimitExec::new(merge, 0, Some(10))
+        };
+        assert_eq!(vec![(0, 10)], stage_row_limits(&limit_of(reader.clone())));
+
+        // the map tasks may write more rows than the limit before any is read back
+        let predicate = Arc::new(BinaryExpr::new(
+            col("a", &schema)?,
+            Operator::Gt,
+            lit(1000i64),
+        ));
+        let reader = reader.with_filter(predicate);
+        assert!(stage_row_limits(&limit_of(reader)).is_empty());
+        Ok(())
+    }
+
+    /// `count` partitions of 100 rows each
+    fn partitions(count: i64) -> TestResult<Vec<Vec<RecordBatch>>> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        (0..count)
+            .map(|p| {
+                let values = (p * 100..(p + 1) * 100).collect::<Vec<i64>>();
+                let batch =
+                    RecordBatch::try_new(schema.clone(), vec![Arc::new(Int64Array::from(values))])?;
+                Ok(vec![batch])
+            })
+            .collect()
+    }
+}
@@ -15,10 +15,27 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use crate::error::RayError;
+use crate::executor::LocalExecutor;
+use crate::limit::plan_distributed_limit;
 use crate::query_stage::PyQueryStage;
 use crate::query_stage::QueryStage;
-use crate::shuffle::{ShuffleReaderExec, ShuffleWriterExec};
-use datafusion::error::Result;
+use crate::scheduler::{
+    PyStageScheduler, SpeculationConfig, StageScheduler, DEFAULT_MAX_TASK_ATTEMPTS,
+};
+use crate::shuffle::{
+    parse_shuffle_storage, LocalShuffleStorage, MemoryShuffleReaderExec, ShuffleMode,
+    ShuffleReaderExec, ShuffleReaderPushdown, ShuffleStorage, ShuffleWriterExec, SHUFFLE_ROOT_DIR,
+};
+use crate::sink::plan_distributed_write;
+use datafusion::arrow::array::StringArray;
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use datafusion::arrow::pyarrow::ToPyArrow;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::common::config::ConfigOptions;
+use datafusion::common::tree_node::{Transformed, TreeNode};
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_optimizer::PhysicalOptimizerRule;
 use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
 use datafusion::physical_plan::repartition::RepartitionExec;
 use datafusion::physical_plan::sorts::sort_preserving_merge::SortPreservingMergeExec;
@@ -45,27 +62,81 @@ impl PyExecutionGraph {
 #[pymethods]
 impl PyExecutionGraph {
     /// Get a list of stages sorted by id
-    pub fn get_query_stages(&self) -> Vec<PyQueryStage> {
+    pub fn get_query_stages(&self) -> PyResult<Vec<PyQueryStage>> {
         let mut stages = vec![];
-        let max_id = self.graph.get_final_query_stage().id;
+        let max_id = self
+            .graph
+            .get_final_query_stage()
+            .map_err(RayError::planning)?
+            .id;
         for id in 0..=max_id {
-            stages.push(PyQueryStage::from_rust(
-                self.graph.query_stages.get(&id).unwrap().clone(),
-            ));
+            stages.push(self.get_query_stage(id)?);
         }
-        stages
+        Ok(stages)
     }
 
     pub fn get_query_stage(&self, id: usize) -> PyResult<PyQueryStage> {
         if let Some(stage) = self.graph.query_stages.get(&id) {
             Ok(PyQueryStage::from_rust(stage.clone()))
         } else {
-            todo!()
+            Err(RayError::Planning(DataFusionError::Plan(format!(
+                "Query stage #{id} not found"
+            )))
+            .into())
         }
     }
 
-    pub fn get_final_query_stage(&self) -> PyQueryStage {
-        PyQueryStage::from_rust(self.graph.get_final_query_stage())
+    pub fn get_final_query_stage(&self) -> PyResult<PyQueryStage> {
+        let stage = self
+            .graph
+            .get_final_query_stage()
+            .map_err(RayError::planning)?;
+        Ok(PyQueryStage::from_rust(stage))
+    }
+
+    /// How the query stages exchange data: "disk", "memory" or "flight". In memory and flight
+    /// mode the result of each task of a query stage must be passed to the tasks of the stages
+    /// that read it.
+    pub fn shuffle_mode(&self) -> String {
+        self.graph.shuffle_mode.to_string()
+    }
+
+    /// Create a scheduler that tracks the execution of this graph. With `speculation` enabled
+    /// the scheduler launches duplicate attempts of slow tasks.
+    #[pyo3(signature = (max_task_attempts=DEFAULT_MAX_TASK_ATTEMPTS, speculation=false))]
+    pub fn create_scheduler(
+        &self,
+        max_task_attempts: usize,
+        speculation: bool,
+    ) -> PyResult<PyStageScheduler> {
+        let mut scheduler = StageScheduler::try_new(&self.graph)
+            .map_err(RayError::planning)?
+            .with_max_task_attempts(max_task_attempts);
+        if speculation {
+            scheduler = scheduler.with_speculation(SpeculationConfig::default());
+        }
+        Ok(PyStageScheduler::new(scheduler))
+    }
+
+    /// The plans of the query stages, in the format of an EXPLAIN result
+    #[pyo3(signature = (verbose=false))]
+    pub fn explain(&self, verbose: bool, py: Python) -> PyResult<PyObject> {
+        let batch = self.graph.explain(verbose).map_err(RayError::planning)?;
+        batch.to_pyarrow(py)
+    }
+
+    /// Execute the query stages in this process instead of on Ray workers. This avoids the
+    /// scheduling overhead of Ray for small queries.
+    #[pyo3(signature = (concurrency=None))]
+    pub fn execute_local(&self, concurrency: Option<usize>, py: Python) -> PyResult<Vec<PyObject>> {
+        let executor = concurrency.map(LocalExecutor::new).unwrap_or_default();
+        let batches = py
+            .allow_threads(|| executor.execute(&self.graph))
+            .map_err(RayError::from)?;
+        batches
+            .into_iter()
+            .map(|batch| batch.to_pyarrow(py))
+            .collect()
     }
 }
 
@@ -73,6 +144,10 @@ impl PyExecutionGraph {
 pub struct ExecutionGraph {
     /// Query stages by id
     pub query_stages: HashMap<usize, Arc<QueryStage>>,
+    /// How the query stages exchange data
+    pub shuffle_mode: ShuffleMode,
+    /// Path or object store URL under which shuffle files are written
+    pub shuffle_storage: Option<String>,
     id_generator: AtomicUsize,
 }
 
@@ -86,6 +161,8 @@ impl ExecutionGraph {
     pub fn new() -> Self {
         Self {
             query_stages: HashMap::new(),
+            shuffle_mode: ShuffleMode::Disk,
+            shuffle_storage: None,
             id_generator: AtomicUsize::new(0),
         }
     }
@@ -96,16 +173,101 @@ impl ExecutionGraph {
         stage_id
     }
 
-    fn get_final_query_stage(&self) -> Arc<QueryStage> {
+    pub fn get_final_query_stage(&self) -> Result<Arc<QueryStage>> {
         // the final query stage is always the last to be created and
         // therefore has the highest id
-        let mut max_id = 0;
-        for k in self.query_stages.keys() {
-            if *k > max_id {
-                max_id = *k;
-            }
+        self.query_stages
+            .iter()
+            .max_by_key(|(id, _)| **id)
+            .map(|(_, stage)| stage.clone())
+            .ok_or_else(|| {
+                DataFusionError::Internal("The execution graph has no query stages".to_string())
+            })
+    }
+
+    /// Memory-map the shuffle files that each query stage reads from the local filesystem,
+    /// instead of copying them into newly allocated buffers
+    pub fn with_mmap_shuffle_reads(&mut self) -> Result<()> {
+        self.transform_query_stages(|plan| {
+            plan.transform_up(|node| {
+                if let Some(reader) = node.as_any().downcast_ref::<ShuffleReaderExec>() {
+                    let reader = reader.with_mmap(true);
+                    Ok(Transformed::yes(Arc::new(reader) as Arc<dyn ExecutionPlan>))
+                } else {
+                    Ok(Transformed::no(node))
+                }
+            })
+            .map(|transformed| transformed.data)
+        })
+    }
+
+    /// Read the output of the map tasks of each exchange in map partition order, one map task
+    /// after the other, so that the query returns rows in the same order on every run
+    pub fn with_deterministic_shuffle_reads(&mut self) -> Result<()> {
+        self.transform_query_stages(|plan| {
+            plan.transform_up(|node| {
+                if let Some(reader) = node.as_any().downcast_ref::<ShuffleReaderExec>() {
+                    let reader = reader.with_deterministic(true);
+                    Ok(Transformed::yes(Arc::new(reader) as Arc<dyn ExecutionPlan>))
+                } else {
+                    Ok(Transformed::no(node))
+                }
+            })
+            .map(|transformed| transformed.data)
+        })
+    }
+
+    /// The plans of the query stages, in the format of an EXPLAIN result: one row per query
+    /// stage, with a `plan_type` column naming the stage and a `plan` column. With `verbose`
+    /// the plans include the schema of each operator.
+    pub fn explain(&self, verbose: bool) -> Result<RecordBatch> {
+        let mut stages = self.query_stages.values().collect::<Vec<_>>();
+        stages.sort_by_key(|stage| stage.id);
+        let plan_types = stages
+            .iter()
+            .map(|stage| format!("query_stage_{}", stage.id))
+            .collect::<Vec<_>>();
+        let plans = stages
+            .iter()
+            .map(|stage| {
+                let plan = displayable(stage.plan.as_ref());
+                if verbose {
+                    plan.set_show_schema(true).indent(true).to_string()
+                } else {
+                    plan.indent(false).to_string()
+                }
+            })
+            .collect::<Vec<_>>();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("plan_type", DataType::Utf8, false),
+            Field::new("plan", DataType::Utf8, false),
+        ]));
+        Ok(RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(plan_types)),
+                Arc::new(StringArray::from(plans)),
+            ],
+        )?)
+    }
+
+    /// Re-optimize the plan of each query stage now that the exchanges between stages are
+    /// shuffle readers, pushing projections and filters into the readers
+    pub fn optimize_query_stages(&mut self) -> Result<()> {
+        let rule = ShuffleReaderPushdown::new();
+        let config = ConfigOptions::new();
+        self.transform_query_stages(|plan| rule.optimize(plan, &config))
+    }
+
+    fn transform_query_stages(
+        &mut self,
+        f: impl Fn(Arc<dyn ExecutionPlan>) -> Result<Arc<dyn ExecutionPlan>>,
+    ) -> Result<()> {
+        for stage in self.query_stages.values_mut() {
+            let plan = f(stage.plan.clone())?;
+            *stage = Arc::new(QueryStage::new(stage.id, plan));
         }
-        self.query_stages.get(&max_id).unwrap().clone()
+        Ok(())
     }
 
     fn next_id(&self) -> usize {
@@ -114,7 +276,37 @@ impl ExecutionGraph {
 }
 
 pub fn make_execution_graph(plan: Arc<dyn ExecutionPlan>) -> Result<ExecutionGraph> {
+    make_execution_graph_with_shuffle_mode(plan, ShuffleMode::Disk)
+}
+
+/// Break a physical plan into query stages that exchange data using the given shuffle mode
+pub fn make_execution_graph_with_shuffle_mode(
+    plan: Arc<dyn ExecutionPlan>,
+    shuffle_mode: ShuffleMode,
+) -> Result<ExecutionGraph> {
+    make_execution_graph_with_shuffle_storage(plan, shuffle_mode, None)
+}
+
+/// Break a physical plan into query stages that exchange data using the given shuffle mode.
+/// The shuffle files of each stage are written under `shuffle_storage`, a path or object store
+/// URL, or to a local temporary directory if it is not set.
+pub fn make_execution_graph_with_shuffle_storage(
+    plan: Arc<dyn ExecutionPlan>,
+    shuffle_mode: ShuffleMode,
+    shuffle_storage: Option<&str>,
+) -> Result<ExecutionGraph> {
+    if shuffle_mode == ShuffleMode::Flight && shuffle_storage.is_some() {
+        return Err(DataFusionError::Configuration(
+            "The flight shuffle writes to the local disk of each worker and does not support \
+             a shuffle storage"
+                .to_string(),
+        ));
+    }
     let mut graph = ExecutionGraph::new();
+    graph.shuffle_mode = shuffle_mode;
+    graph.shuffle_storage = shuffle_storage.map(str::to_string);
+    let plan = plan_distributed_write(plan)?;
+    let plan = plan_distributed_limit(plan)?;
     let root = generate_query_stages(plan, &mut graph)?;
     // We force the final stage to produce a single partition to return
     // to the driver. This might not suit ETL workloads.
@@ -200,17 +392,41 @@ fn create_shuffle_exchange(
 ) -> Result<Arc<dyn ExecutionPlan>> {
     // introduce shuffle to produce one output partition
     let stage_id = graph.next_id();
+    let map_partitions = plan.properties().output_partitioning().partition_count();
 
-    // create temp dir for stage shuffle files
-    let temp_dir = create_temp_dir(stage_id)?;
+    if graph.shuffle_mode == ShuffleMode::Memory {
+        let shuffle_writer = Arc::new(ShuffleWriterExec::new_in_memory(
+            stage_id,
+            plan.clone(),
+            partitioning_scheme.clone(),
+        ));
+        let stage_id = graph.add_query_stage(stage_id, shuffle_writer);
+        return Ok(Arc::new(MemoryShuffleReaderExec::new(
+            stage_id,
+            plan.schema(),
+            partitioning_scheme,
+            map_partitions,
+        )));
+    }
+
+    let storage = create_stage_storage(graph, stage_id)?;
 
     let shuffle_writer_input = plan.clone();
-    let shuffle_writer: Arc<dyn ExecutionPlan> = Arc::new(ShuffleWriterExec::new(
-        stage_id,
-        shuffle_writer_input,
-        partitioning_scheme.clone(),
-        &temp_dir,
-    ));
+    let shuffle_writer: Arc<dyn ExecutionPlan> = if graph.shuffle_mode == ShuffleMode::Flight {
+        Arc::new(ShuffleWriterExec::new_flight(
+            stage_id,
+            shuffle_writer_input,
+            partitioning_scheme.clone(),
+            storage.url(),
+        ))
+    } else {
+        Arc::new(ShuffleWriterExec::new(
+            stage_id,
+            shuffle_writer_input,
+            partitioning_scheme.clone(),
+            storage.clone(),
+        ))
+    };
 
     debug!(
         "Created shuffle writer with output partitioning {:?}",
@@ -223,28 +439,42 @@ fn create_shuffle_exchange(
         stage_id,
         plan.schema(),
         partitioning_scheme,
-        &temp_dir,
+        storage,
+        map_partitions,
     )))
 }
 
-fn create_temp_dir(stage_id: usize) -> Result<String> {
+/// Create the storage for the shuffle files of a query stage, under the shuffle storage of the
+/// graph or in a new temp dir
+fn create_stage_storage(
+    graph: &ExecutionGraph,
+    stage_id: usize,
+) -> Result<Arc<dyn ShuffleStorage>> {
     let uuid = Uuid::new_v4();
-    let temp_dir = format!("/tmp/ray-sql-{uuid}-stage-{stage_id}");
+    if let Some(url) = &graph.shuffle_storage {
+        let url = format!(
+            "{}/ray-sql-{uuid}-stage-{stage_id}",
+            url.trim_end_matches('/')
+        );
+        debug!("Using shuffle storage: {url}");
+        return parse_shuffle_storage(&url);
+    }
+    let temp_dir = format!("{SHUFFLE_ROOT_DIR}/ray-sql-{uuid}-stage-{stage_id}");
     debug!("Creating temp shuffle dir: {temp_dir}");
     std::fs::create_dir(&temp_dir)?;
-    Ok(temp_dir)
+    Ok(Arc::new(LocalShuffleStorage::new(&temp_dir)))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_utils::{run_differential, ShuffleDirs, TestResult};
     use datafusion::physical_plan::displayable;
     use datafusion::prelude::{ParquetReadOptions, SessionConfig, SessionContext};
     use pretty_assertions::assert_eq;
     use regex::Regex;
     use std::path::Path;
     use std::{env, fs};
-    type TestResult<T> = std::result::Result<T, anyhow::Error>;
 
     #[tokio::test]
     async fn test_q1() -> TestResult<()> {
@@ -364,6 +594,139 @@ mod test {
         do_test(22).await
     }
 
+    #[test]
+    fn test_distributed_q1() -> TestResult<()> {
+        do_differential_test(1)
+    }
+
+    #[test]
+    fn test_distributed_q2() -> TestResult<()> {
+        do_differential_test(2)
+    }
+
+    #[test]
+    fn test_distributed_q3() -> TestResult<()> {
+        do_differential_test(3)
+    }
+
+    #[test]
+    fn test_distributed_q4() -> TestResult<()> {
+        do_differential_test(4)
+    }
+
+    #[test]
+    fn test_distributed_q5() -> TestResult<()> {
+        do_differential_test(5)
+    }
+
+    #[test]
+    fn test_distributed_q6() -> TestResult<()> {
+        do_differential_test(6)
+    }
+
+    #[test]
+    fn test_distributed_q7() -> TestResult<()> {
+        do_differential_test(7)
+    }
+
+    #[test]
+    fn test_distributed_q8() -> TestResult<()> {
+        do_differential_test(8)
+    }
+
+    #[test]
+    fn test_distributed_q9() -> TestResult<()> {
+        do_differential_test(9)
+    }
+
+    #[test]
+    fn test_distributed_q10() -> TestResult<()> {
+        do_differential_test(10)
+    }
+
+    #[test]
+    fn test_distributed_q11() -> TestResult<()> {
+        do_differential_test(11)
+    }
+
+    #[test]
+    fn test_distributed_q12() -> TestResult<()> {
+        do_differential_test(12)
+    }
+
+    #[test]
+    fn test_distributed_q13() -> TestResult<()> {
+        do_differential_test(13)
+    }
+
+    #[test]
+    fn test_distributed_q14() -> TestResult<()> {
+        do_differential_test(14)
+    }
+
+    #[ignore = "multi-statement query"]
+    #[test]
+    fn test_distributed_q15() -> TestResult<()> {
+        do_differential_test(15)
+    }
+
+    #[test]
+    fn test_distributed_q16() -> TestResult<()> {
+        do_differential_test(16)
+    }
+
+    #[test]
+    fn test_distributed_q17() -> TestResult<()> {
+        do_differential_test(17)
+    }
+
+    #[test]
+    fn test_distributed_q18() -> TestResult<()> {
+        do_differential_test(18)
+    }
+
+    #[test]
+    fn test_distributed_q19() -> TestResult<()> {
+        do_differential_test(19)
+    }
+
+    #[test]
+    fn test_distributed_q20() -> TestResult<()> {
+        do_differential_test(20)
+    }
+
+    #[test]
+    fn test_distributed_q21() -> TestResult<()> {
+        do_differential_test(21)
+    }
+
+    #[test]
+    fn test_distributed_q22() -> TestResult<()> {
+        do_differential_test(22)
+    }
+
+    /// Run a query with plain DataFusion and through the distributed execution graph and check
+    /// that both return the same rows
+    fn do_differential_test(n: u8) -> TestResult<()> {
+        let sql = fs::read_to_string(format!("testdata/queries/q{n}.sql"))?;
+        let runs = [
+            (1, ShuffleMode::Disk),
+            (2, ShuffleMode::Disk),
+            (4, ShuffleMode::Disk),
+            (4, ShuffleMode::Memory),
+            (4, ShuffleMode::Flight),
+        ];
+        for (target_partitions, shuffle_mode) in runs {
+            let (expected, actual) = run_differential(&sql, target_partitions, shuffle_mode)?;
+            assert_eq!(
+                expected, actual,
+                "q{n} returned different results with target_partitions={target_partitions} \
+                 and {shuffle_mode:?} shuffle"
+            );
+        }
+        Ok(())
+    }
+
     async fn do_test(n: u8) -> TestResult<()> {
         let tpch_path_env_var = "TPCH_DATA_PATH";
         let data_path = env::var(tpch_path_env_var)
@@ -402,7 +765,8 @@ mod test {
 
         output.push_str("DataFusion Ray Distributed Plan\n===========\n\n");
         let graph = make_execution_graph(plan)?;
-        for id in 0..=graph.get_final_query_stage().id {
+        let _shuffle_dirs = ShuffleDirs::of(&graph);
+        for id in 0..=graph.get_final_query_stage()?.id {
             let query_stage = graph.query_stages.get(&id).unwrap();
             output.push_str(&format!(
                 "Query Stage #{id} ({} -> {}):\n{}\n",
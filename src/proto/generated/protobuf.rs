@@ -1,7 +1,7 @@
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct RaySqlExecNode {
-    #[prost(oneof = "ray_sql_exec_node::PlanType", tags = "1, 2")]
+    #[prost(oneof = "ray_sql_exec_node::PlanType", tags = "1, 2, 3, 4, 5, 6")]
     pub plan_type: ::core::option::Option<ray_sql_exec_node::PlanType>,
 }
 /// Nested message and enum types in `RaySqlExecNode`.
@@ -13,8 +13,61 @@ pub mod ray_sql_exec_node {
         ShuffleReader(super::ShuffleReaderExecNode),
         #[prost(message, tag = "2")]
         ShuffleWriter(super::ShuffleWriterExecNode),
+        #[prost(message, tag = "3")]
+        MemoryShuffleReader(super::MemoryShuffleReaderExecNode),
+        #[prost(message, tag = "4")]
+        FileScan(super::FileScanExecNode),
+        #[prost(message, tag = "5")]
+        SinkWriter(super::SinkWriterExecNode),
+        #[prost(message, tag = "6")]
+        SinkCommit(super::SinkCommitExecNode),
     }
 }
+/// Writer of the partitions of a distributed write, each into a staging directory of its own
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SinkWriterExecNode {
+    /// directory under which the staging directories of the tasks are created
+    #[prost(string, tag = "4")]
+    pub staging_url: ::prost::alloc::string::String,
+    #[prost(oneof = "sink_writer_exec_node::Sink", tags = "1, 2, 3")]
+    pub sink: ::core::option::Option<sink_writer_exec_node::Sink>,
+}
+/// Nested message and enum types in `SinkWriterExecNode`.
+pub mod sink_writer_exec_node {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Sink {
+        #[prost(message, tag = "1")]
+        Parquet(::datafusion_proto::protobuf::ParquetSink),
+        #[prost(message, tag = "2")]
+        Csv(::datafusion_proto::protobuf::CsvSink),
+        #[prost(message, tag = "3")]
+        Json(::datafusion_proto::protobuf::JsonSink),
+    }
+}
+/// Moves the files staged by the writers of a distributed write to their final location
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SinkCommitExecNode {
+    /// directory the files are moved to
+    #[prost(string, tag = "1")]
+    pub target_url: ::prost::alloc::string::String,
+    /// directory under which the writers staged their files
+    #[prost(string, tag = "2")]
+    pub staging_url: ::prost::alloc::string::String,
+}
+/// Scan of files in a format that datafusion-proto cannot encode
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FileScanExecNode {
+    #[prost(message, optional, tag = "1")]
+    pub base_conf: ::core::option::Option<
+        ::datafusion_proto::protobuf::FileScanExecConf,
+    >,
+    #[prost(enumeration = "FileScanFormat", tag = "2")]
+    pub format: i32,
+}
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ShuffleReaderExecNode {
@@ -29,9 +82,30 @@ pub struct ShuffleReaderExecNode {
     pub partitioning: ::core::option::Option<
         ::datafusion_proto::protobuf::PhysicalHashRepartition,
     >,
-    /// directory for shuffle files
-    #[prost(string, tag = "4")]
-    pub shuffle_dir: ::prost::alloc::string::String,
+    /// number of map tasks writing the shuffle files
+    #[prost(uint32, tag = "5")]
+    pub map_partition_count: u32,
+    /// storage for shuffle files
+    #[prost(message, optional, tag = "6")]
+    pub storage: ::core::option::Option<ShuffleStorageNode>,
+    /// memory-map shuffle files on the local filesystem
+    #[prost(bool, tag = "7")]
+    pub mmap: bool,
+    /// columns of the schema that are read, all columns if not set
+    #[prost(message, optional, tag = "8")]
+    pub projection: ::core::option::Option<ShuffleProjection>,
+    /// predicate on the projected columns
+    #[prost(message, optional, tag = "9")]
+    pub filter: ::core::option::Option<::datafusion_proto::protobuf::PhysicalExprNode>,
+    /// read the map outputs in map partition order, one after the other
+    #[prost(bool, tag = "10")]
+    pub deterministic: bool,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ShuffleProjection {
+    #[prost(uint32, repeated, tag = "1")]
+    pub columns: ::prost::alloc::vec::Vec<u32>,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -47,7 +121,178 @@ pub struct ShuffleWriterExecNode {
     pub partitioning: ::core::option::Option<
         ::datafusion_proto::protobuf::PhysicalHashRepartition,
     >,
-    /// directory for shuffle files
+    /// how the shuffle output is passed to the stages reading it
+    #[prost(enumeration = "ShuffleMode", tag = "5")]
+    pub shuffle_mode: i32,
+    /// storage for shuffle files
+    #[prost(message, optional, tag = "6")]
+    pub storage: ::core::option::Option<ShuffleStorageNode>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ShuffleStorageNode {
+    #[prost(enumeration = "ShuffleStorageBackend", tag = "1")]
+    pub backend: i32,
+    /// directory for local storage, URL of the store otherwise
+    #[prost(string, tag = "2")]
+    pub url: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MemoryShuffleReaderExecNode {
+    /// stage to read from
+    #[prost(uint32, tag = "1")]
+    pub stage_id: u32,
+    /// schema of the shuffle stage
+    #[prost(message, optional, tag = "2")]
+    pub schema: ::core::option::Option<::datafusion_proto::protobuf::Schema>,
+    /// this must match the output partitioning of the writer we are reading from
+    #[prost(message, optional, tag = "3")]
+    pub partitioning: ::core::option::Option<
+        ::datafusion_proto::protobuf::PhysicalHashRepartition,
+    >,
+    /// number of map tasks whose output is read
+    #[prost(uint32, tag = "4")]
+    pub map_partition_count: u32,
+}
+/// Metadata written by each shuffle writer task, describing its output files
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ShuffleMapOutput {
+    /// stage that wrote the shuffle files
+    #[prost(uint32, tag = "1")]
+    pub stage_id: u32,
+    /// input partition of the stage that was written
+    #[prost(uint32, tag = "2")]
+    pub map_partition: u32,
+    /// one entry per output partition that received data
+    #[prost(message, repeated, tag = "3")]
+    pub partitions: ::prost::alloc::vec::Vec<ShufflePartitionOutput>,
+    /// address of the shuffle server serving the files, in flight mode
     #[prost(string, tag = "4")]
+    pub host: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "5")]
+    pub port: u32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ShufflePartitionOutput {
+    /// output partition
+    #[prost(uint32, tag = "1")]
+    pub partition: u32,
+    /// shuffle file containing the partition, relative to the shuffle storage
+    #[prost(string, tag = "2")]
+    pub path: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "3")]
+    pub num_rows: u64,
+    #[prost(uint64, tag = "4")]
+    pub num_batches: u64,
+    #[prost(uint64, tag = "5")]
+    pub num_bytes: u64,
+    /// CRC32 checksum of the shuffle file
+    #[prost(uint32, tag = "6")]
+    pub checksum: u32,
+}
+/// Ticket of a Flight DoGet request for one output partition of a shuffle writer task
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ShuffleTicket {
+    /// directory the shuffle files were written to
+    #[prost(string, tag = "1")]
     pub shuffle_dir: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "2")]
+    pub stage_id: u32,
+    #[prost(uint32, tag = "3")]
+    pub map_partition: u32,
+    #[prost(uint32, tag = "4")]
+    pub partition: u32,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum FileScanFormat {
+    /// newline-delimited JSON, compressed according to the file extension
+    Ndjson = 0,
+    /// Arrow IPC files
+    Arrow = 1,
+}
+impl FileScanFormat {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            FileScanFormat::Ndjson => "FILE_SCAN_FORMAT_NDJSON",
+            FileScanFormat::Arrow => "FILE_SCAN_FORMAT_ARROW",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "FILE_SCAN_FORMAT_NDJSON" => Some(Self::Ndjson),
+            "FILE_SCAN_FORMAT_ARROW" => Some(Self::Arrow),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ShuffleStorageBackend {
+    Local = 0,
+    Memory = 1,
+    ObjectStore = 2,
+}
+impl ShuffleStorageBackend {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ShuffleStorageBackend::Local => "SHUFFLE_STORAGE_BACKEND_LOCAL",
+            ShuffleStorageBackend::Memory => "SHUFFLE_STORAGE_BACKEND_MEMORY",
+            ShuffleStorageBackend::ObjectStore => "SHUFFLE_STORAGE_BACKEND_OBJECT_STORE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "SHUFFLE_STORAGE_BACKEND_LOCAL" => Some(Self::Local),
+            "SHUFFLE_STORAGE_BACKEND_MEMORY" => Some(Self::Memory),
+            "SHUFFLE_STORAGE_BACKEND_OBJECT_STORE" => Some(Self::ObjectStore),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ShuffleMode {
+    /// shuffle files in a directory that all readers can access
+    Disk = 0,
+    /// shuffle output returned as task results
+    Memory = 1,
+    /// shuffle files served by the shuffle server of the node that wrote them
+    Flight = 2,
+}
+impl ShuffleMode {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ShuffleMode::Disk => "DISK",
+            ShuffleMode::Memory => "MEMORY",
+            ShuffleMode::Flight => "FLIGHT",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "DISK" => Some(Self::Disk),
+            "MEMORY" => Some(Self::Memory),
+            "FLIGHT" => Some(Self::Flight),
+            _ => None,
+        }
+    }
 }
@@ -16,4 +16,5 @@
 // under the License.
 
 #[rustfmt::skip]
+#[allow(clippy::large_enum_variant)]
 pub mod generated;
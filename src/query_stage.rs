@@ -15,14 +15,26 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use crate::context::serialize_execution_plan;
-use crate::shuffle::{ShuffleCodec, ShuffleReaderExec};
-use datafusion::error::Result;
-use datafusion::physical_plan::{ExecutionPlan, Partitioning};
+use crate::context::{codec_error, serialize_execution_plan, wait_for_future};
+use crate::error::RayError;
+use crate::shuffle::{
+    file_compression, MemoryShuffleReaderExec, ShuffleCodec, ShuffleReaderExec, ShuffleWriterExec,
+};
+use datafusion::arrow::pyarrow::PyArrowType;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::physical_plan::{
+    ArrowExec, AvroExec, CsvExec, FileScanConfig, NdJsonExec, ParquetExec,
+};
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+use datafusion::physical_plan::sorts::sort_preserving_merge::SortPreservingMergeExec;
+use datafusion::physical_plan::union::UnionExec;
+use datafusion::physical_plan::{with_new_children_if_necessary, ExecutionPlan, Partitioning};
 use datafusion::prelude::SessionContext;
 use datafusion_proto::bytes::physical_plan_from_bytes_with_extension_codec;
 use pyo3::prelude::*;
 use pyo3::types::PyBytes;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 #[pyclass(name = "QueryStage", module = "datafusion_ray", subclass)]
@@ -39,10 +51,11 @@ impl PyQueryStage {
 #[pymethods]
 impl PyQueryStage {
     #[new]
-    pub fn new(id: usize, bytes: Vec<u8>) -> Result<Self> {
+    pub fn new(id: usize, bytes: Vec<u8>) -> PyResult<Self> {
         let ctx = SessionContext::new();
         let codec = ShuffleCodec {};
-        let plan = physical_plan_from_bytes_with_extension_codec(&bytes, &ctx, &codec)?;
+        let plan = physical_plan_from_bytes_with_extension_codec(&bytes, &ctx, &codec)
+            .map_err(codec_error)?;
         Ok(PyQueryStage {
             stage: Arc::new(QueryStage { id, plan }),
         })
@@ -52,8 +65,22 @@ impl PyQueryStage {
         self.stage.id
     }
 
-    pub fn get_execution_plan_bytes<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
-        serialize_execution_plan(self.stage.plan.clone(), py)
+    /// The serialized plan of the stage. With `partition`, the plan of that task only, in which
+    /// the file scans only list the files that the task reads.
+    #[pyo3(signature = (partition=None))]
+    pub fn get_execution_plan_bytes<'py>(
+        &self,
+        partition: Option<usize>,
+        py: Python<'py>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let plan = match partition {
+            Some(partition) => self
+                .stage
+                .plan_for_partition(partition)
+                .map_err(RayError::planning)?,
+            None => self.stage.plan.clone(),
+        };
+        serialize_execution_plan(plan, py)
     }
 
     pub fn get_child_stage_ids(&self) -> Vec<usize> {
@@ -67,6 +94,30 @@ impl PyQueryStage {
     pub fn get_output_partition_count(&self) -> usize {
         self.stage.get_output_partition_count()
     }
+
+    /// Commit an empty output for a task of this stage that was cancelled, and return the
+    /// batch that the task would have returned
+    pub fn commit_empty_output(
+        &self,
+        partition: usize,
+        py: Python,
+    ) -> PyResult<PyArrowType<RecordBatch>> {
+        let batch = wait_for_future(py, self.stage.commit_empty_output(partition))?
+            .map_err(|e| RayError::task(Some(self.stage.id), Some(partition), e))?;
+        Ok(PyArrowType(batch))
+    }
+
+    /// Node ids or hostnames holding the input of the tasks of this stage, most preferred
+    /// first. `shuffle_locations` maps each child stage to the nodes that ran its map tasks, one
+    /// entry per map task.
+    #[pyo3(signature = (shuffle_locations=None))]
+    pub fn get_preferred_locations(
+        &self,
+        shuffle_locations: Option<HashMap<usize, Vec<String>>>,
+    ) -> Vec<String> {
+        self.stage
+            .preferred_locations(&shuffle_locations.unwrap_or_default())
+    }
 }
 
 #[derive(Debug)]
@@ -108,14 +159,332 @@ impl QueryStage {
     pub fn get_output_partition_count(&self) -> usize {
         _get_output_partition_count(self.plan.as_ref())
     }
+
+    /// Nodes holding the input of the tasks of this stage, most preferred first. The shuffle
+    /// input of a task is read from every map task of the child stages, so the nodes that ran
+    /// the most map tasks come first. File scans give no hints, because the host of an object
+    /// store URL, such as the namenode of `hdfs://namenode:9000`, does not hold the files.
+    pub fn preferred_locations(
+        &self,
+        shuffle_locations: &HashMap<usize, Vec<String>>,
+    ) -> Vec<String> {
+        let mut weights = HashMap::new();
+        for stage_id in self.get_child_stage_ids() {
+            for node in shuffle_locations.get(&stage_id).into_iter().flatten() {
+                *weights.entry(node.clone()).or_insert(0u64) += 1;
+            }
+        }
+        rank(weights)
+    }
+
+    /// Commit an empty output for task `partition` of this stage, which is not needed anymore
+    pub async fn commit_empty_output(&self, partition: usize) -> Result<RecordBatch> {
+        match self.plan.as_any().downcast_ref::<ShuffleWriterExec>() {
+            Some(writer) => writer.commit_empty_output(partition).await,
+            None => Err(DataFusionError::Internal(format!(
+                "Query stage #{} does not write shuffle output",
+                self.id
+            ))),
+        }
+    }
+
+    /// Copy of the plan of this stage for task `partition`, in which the file scans only list
+    /// the files that the task reads. The task still executes partition `partition` of the
+    /// copy, but its serialized plan no longer grows with the number of files of the stage.
+    pub fn plan_for_partition(&self, partition: usize) -> Result<Arc<dyn ExecutionPlan>> {
+        slice_plan(self.plan.clone(), TaskPartitions::One(partition))
+    }
+
+    /// Get the number of tasks needed to execute this query stage. A shuffle write runs one
+    /// task per input partition, while the final query stage runs one task per partition
+    /// that it returns.
+    pub fn get_task_count(&self) -> usize {
+        if self.plan.as_any().is::<ShuffleWriterExec>() {
+            self.get_input_partition_count()
+        } else {
+            self.plan
+                .properties()
+                .output_partitioning()
+                .partition_count()
+        }
+    }
 }
 
 fn collect_child_stage_ids(plan: &dyn ExecutionPlan, ids: &mut Vec<usize>) {
     if let Some(shuffle_reader) = plan.as_any().downcast_ref::<ShuffleReaderExec>() {
         ids.push(shuffle_reader.stage_id);
+    } else if let Some(shuffle_reader) = plan.as_any().downcast_ref::<MemoryShuffleReaderExec>() {
+        ids.push(shuffle_reader.stage_id);
     } else {
         for child_plan in plan.children() {
             collect_child_stage_ids(child_plan.as_ref(), ids);
         }
     }
 }
+
+/// Keys by decreasing weight, then by name
+fn rank(weights: HashMap<String, u64>) -> Vec<String> {
+    let mut weights = weights.into_iter().collect::<Vec<_>>();
+    weights.sort_by(|(a, x), (b, y)| y.cmp(x).then_with(|| a.cmp(b)));
+    weights.into_iter().map(|(key, _)| key).collect()
+}
+
+/// The file scan configuration of a plan that scans files
+pub(crate) fn file_scan_config(plan: &dyn ExecutionPlan) -> Option<&FileScanConfig> {
+    let plan = plan.as_any();
+    if let Some(scan) = plan.downcast_ref::<ParquetExec>() {
+        Some(scan.base_config())
+    } else if let Some(scan) = plan.downcast_ref::<CsvExec>() {
+        Some(scan.base_config())
+    } else if let Some(scan) = plan.downcast_ref::<NdJsonExec>() {
+        Some(scan.base_config())
+    } else if let Some(scan) = plan.downcast_ref::<AvroExec>() {
+        Some(scan.base_config())
+    } else {
+        plan.downcast_ref::<ArrowExec>()
+            .map(|scan| scan.base_config())
+    }
+}
+
+/// The partitions of a plan that a task runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TaskPartitions {
+    One(usize),
+    All,
+    None,
+}
+
+/// The partitions of each child of a plan that a task running `partitions` of the plan runs
+fn child_partitions(plan: &dyn ExecutionPlan, partitions: TaskPartitions) -> Vec<TaskPartitions> {
+    let children = plan.children();
+    let any = plan.as_any();
+    if partitions == TaskPartitions::None {
+        return vec![TaskPartitions::None; children.len()];
+    }
+    if any.is::<UnionExec>() {
+        // the partitions of a union are the partitions of its inputs, one input after the other
+        let mut offset = 0;
+        return children
+            .iter()
+            .map(|child| {
+                let count = child.properties().output_partitioning().partition_count();
+                let child_partitions = match partitions {
+                    TaskPartitions::One(p) if (offset..offset + count).contains(&p) => {
+                        TaskPartitions::One(p - offset)
+                    }
+                    TaskPartitions::One(_) => TaskPartitions::None,
+                    other => other,
+                };
+                offset += count;
+                child_partitions
+            })
+            .collect();
+    }
+    // a shuffle writer task runs one partition of its input
+    if any.is::<ShuffleWriterExec>() {
+        return vec![partitions; children.len()];
+    }
+    let merges_partitions =
+        any.is::<CoalescePartitionsExec>() || any.is::<SortPreservingMergeExec>();
+    let count = plan.properties().output_partitioning().partition_count();
+    children
+        .iter()
+        .map(|child| {
+            if !merges_partitions
+                && child.properties().output_partitioning().partition_count() == count
+            {
+                partitions
+            } else {
+                TaskPartitions::All
+            }
+        })
+        .collect()
+}
+
+/// Copy of a plan in which the file scans only list the files that a task running
+/// `partitions` of the plan reads. The other file groups are emptied rather than removed, so
+/// that the partitions of the plan keep their numbers.
+fn slice_plan(
+    plan: Arc<dyn ExecutionPlan>,
+    partitions: TaskPartitions,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    if let Some(config) = file_scan_config(plan.as_ref()) {
+        if partitions == TaskPartitions::All {
+            return Ok(plan);
+        }
+        let mut config = config.clone();
+        config.file_groups = config
+            .file_groups
+            .into_iter()
+            .enumerate()
+            .map(|(i, files)| {
+                if partitions == TaskPartitions::One(i) {
+                    files
+                } else {
+                    vec![]
+                }
+            })
+            .collect();
+        return Ok(with_file_scan_config(plan.as_ref(), config));
+    }
+    let children = plan
+        .children()
+        .into_iter()
+        .zip(child_partitions(plan.as_ref(), partitions))
+        .map(|(child, partitions)| slice_plan(child.clone(), partitions))
+        .collect::<Result<Vec<_>>>()?;
+    with_new_children_if_necessary(plan, children)
+}
+
+/// Copy of a file scan that scans the files of `config`, keeping the options that are
+/// serialized with the scan
+pub(crate) fn with_file_scan_config(
+    plan: &dyn ExecutionPlan,
+    config: FileScanConfig,
+) -> Arc<dyn ExecutionPlan> {
+    let any = plan.as_any();
+    if let Some(scan) = any.downcast_ref::<ParquetExec>() {
+        let mut builder = ParquetExec::builder(config)
+            .with_table_parquet_options(scan.table_parquet_options().clone());
+        if let Some(predicate) = scan.predicate() {
+            builder = builder.with_predicate(predicate.clone());
+        }
+        builder.build_arc()
+    } else if let Some(scan) = any.downcast_ref::<CsvExec>() {
+        let compression = file_compression(&config);
+        Arc::new(
+            CsvExec::builder(config)
+                .with_has_header(scan.has_header())
+                .with_delimeter(scan.delimiter())
+                .with_quote(scan.quote())
+                .with_terminator(scan.terminator())
+                .with_escape(scan.escape())
+                .with_comment(scan.comment())
+                .with_newlines_in_values(scan.newlines_in_values())
+                .with_file_compression_type(compression)
+                .build(),
+        )
+    } else if any.is::<NdJsonExec>() {
+        let compression = file_compression(&config);
+        Arc::new(NdJsonExec::new(config, compression))
+    } else if any.is::<AvroExec>() {
+        Arc::new(AvroExec::new(config))
+    } else {
+        Arc::new(ArrowExec::new(config))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shuffle::LocalShuffleStorage;
+    use crate::test_utils::{TempDir, TestResult};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use datafusion::datasource::listing::PartitionedFile;
+    use datafusion::execution::object_store::ObjectStoreUrl;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::Column;
+    use datafusion::prelude::{CsvReadOptions, SessionConfig};
+    use datafusion_proto::bytes::physical_plan_to_bytes_with_extension_codec;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn slice_plan_for_partition() -> TestResult<()> {
+        let temp_dir = TempDir::new()?;
+        let dir = temp_dir.path();
+        for i in 0..4 {
+            std::fs::write(
+                dir.join(format!("{i}.csv")),
+                format!("a\n{i}\n{}\n", i + 10),
+            )?;
+        }
+        let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(4));
+        let rt = Runtime::new()?;
+        let plan = rt.block_on(async {
+            ctx.register_csv("t", dir.to_str().unwrap(), CsvReadOptions::new())
+                .await?;
+            ctx.sql("SELECT a * 2 AS b FROM t WHERE a > 0")
+                .await?
+                .create_physical_plan()
+                .await
+        })?;
+        let stage = QueryStage::new(0, plan.clone());
+        assert_eq!(4, stage.get_task_count());
+        let codec = ShuffleCodec {};
+        let full_bytes = physical_plan_to_bytes_with_extension_codec(plan.clone(), &codec)?;
+        for partition in 0..4 {
+            let sliced = stage.plan_for_partition(partition)?;
+            let bytes = physical_plan_to_bytes_with_extension_codec(sliced.clone(), &codec)?;
+            assert!(bytes.len() < full_bytes.len());
+            let sliced = physical_plan_from_bytes_with_extension_codec(&bytes, &ctx, &codec)?;
+            assert_eq!(
+                4,
+                sliced.properties().output_partitioning().partition_count()
+            );
+            let expected = rt.block_on(collect(plan.execute(partition, ctx.task_ctx())?))?;
+            let actual = rt.block_on(collect(sliced.execute(partition, ctx.task_ctx())?))?;
+            assert_eq!(
+                pretty_format_batches(&expected)?.to_string(),
+                pretty_format_batches(&actual)?.to_string()
+            );
+            // the other partitions of the sliced plan read nothing
+            let other = rt.block_on(collect(
+                sliced.execute((partition + 1) % 4, ctx.task_ctx())?,
+            ))?;
+            assert_eq!(0, other.iter().map(|b| b.num_rows()).sum::<usize>());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn preferred_locations_of_shuffles() -> TestResult<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let scan = |url: &str, groups: Vec<Vec<(&str, u64)>>| -> TestResult<_> {
+            let groups = groups
+                .into_iter()
+                .map(|files| {
+                    files
+                        .into_iter()
+                        .map(|(path, size)| PartitionedFile::new(path, size))
+                        .collect()
+                })
+                .collect();
+            let config = FileScanConfig::new(ObjectStoreUrl::parse(url)?, schema.clone())
+                .with_file_groups(groups);
+            Ok(ParquetExec::builder(config).build_arc() as Arc<dyn ExecutionPlan>)
+        };
+        let reader = Arc::new(ShuffleReaderExec::new(
+            1,
+            schema.clone(),
+            Partitioning::Hash(vec![Arc::new(Column::new("a", 0))], 2),
+            Arc::new(LocalShuffleStorage::new("/tmp")),
+            3,
+        ));
+        let union = Arc::new(UnionExec::new(vec![
+            scan(
+                "hdfs://node-a:9000",
+                vec![vec![("x", 10), ("y", 5)], vec![]],
+            )?,
+            scan("hdfs://node-b:9000", vec![vec![("z", 1)]])?,
+            scan("s3://bucket", vec![vec![("w", 100)]])?,
+            reader,
+        ]));
+        let stage = QueryStage::new(0, union);
+        let shuffle_locations = HashMap::from([(
+            1,
+            vec![
+                "node-c".to_string(),
+                "node-b".to_string(),
+                "node-c".to_string(),
+            ],
+        )]);
+        // the hosts of object store URLs are not the nodes holding the files
+        assert!(stage.preferred_locations(&HashMap::new()).is_empty());
+        assert_eq!(
+            vec!["node-c", "node-b"],
+            stage.preferred_locations(&shuffle_locations)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::disk_manager::DiskManagerConfig;
+use datafusion::execution::memory_pool::{FairSpillPool, GreedyMemoryPool, MemoryPool};
+use datafusion::execution::runtime_env::{RuntimeEnv, RuntimeEnvBuilder};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The kind of memory pool used to bound the memory of a single task
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MemoryPoolType {
+    /// Divides the memory limit evenly between spillable operators, see [`FairSpillPool`]
+    #[default]
+    FairSpill,
+    /// First come first served, see [`GreedyMemoryPool`]
+    Greedy,
+}
+
+impl FromStr for MemoryPoolType {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "fair" | "fair_spill" => Ok(Self::FairSpill),
+            "greedy" => Ok(Self::Greedy),
+            other => Err(DataFusionError::Configuration(format!(
+                "Unknown memory pool type '{other}', expected 'fair' or 'greedy'"
+            ))),
+        }
+    }
+}
+
+/// Runtime settings used when executing one partition of a query stage
+#[derive(Debug, Clone, Default)]
+pub struct TaskRuntimeConfig {
+    /// Maximum number of bytes the task may reserve. No limit is applied when `None`
+    pub memory_limit: Option<usize>,
+    /// Pool used to enforce `memory_limit`
+    pub memory_pool: MemoryPoolType,
+    /// Directory for spill files. The OS temp directory is used when `None`
+    pub spill_dir: Option<String>,
+}
+
+impl TaskRuntimeConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_memory_limit(mut self, memory_limit: usize) -> Self {
+        self.memory_limit = Some(memory_limit);
+        self
+    }
+
+    pub fn with_memory_pool(mut self, memory_pool: MemoryPoolType) -> Self {
+        self.memory_pool = memory_pool;
+        self
+    }
+
+    pub fn with_spill_dir(mut self, spill_dir: &str) -> Self {
+        self.spill_dir = Some(spill_dir.to_string());
+        self
+    }
+
+    /// Create the DataFusion runtime for a task using these settings
+    pub fn create_runtime_env(&self) -> Result<Arc<RuntimeEnv>> {
+        let mut builder = RuntimeEnvBuilder::new();
+        if let Some(limit) = self.memory_limit {
+            let pool: Arc<dyn MemoryPool> = match self.memory_pool {
+                MemoryPoolType::FairSpill => Arc::new(FairSpillPool::new(limit)),
+                MemoryPoolType::Greedy => Arc::new(GreedyMemoryPool::new(limit)),
+            };
+            builder = builder.with_memory_pool(pool);
+        }
+        if let Some(dir) = &self.spill_dir {
+            std::fs::create_dir_all(dir)?;
+            builder = builder
+                .with_disk_manager(DiskManagerConfig::new_specified(vec![PathBuf::from(dir)]));
+        }
+        builder.build_arc()
+    }
+}
+
+/// Returns true if the error was caused by a task exceeding its memory limit
+pub fn is_memory_limit_error(e: &DataFusionError) -> bool {
+    matches!(e.find_root(), DataFusionError::ResourcesExhausted(_))
+}
@@ -0,0 +1,452 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Size-aware partitioning of the file scans of leaf query stages.
+//!
+//! DataFusion splits the files of a scan into `target_partitions` file groups, so the number
+//! of tasks of a leaf query stage does not depend on how much data it reads.
+//! [`plan_scan_partitions`] re-splits the file groups of the Parquet scans that feed an
+//! exchange so that each task scans about a target number of bytes. Large files are split
+//! into byte ranges at row group boundaries, and small files are packed together.
+
+use crate::query_stage::with_file_scan_config;
+use datafusion::datasource::listing::{FileRange, PartitionedFile};
+use datafusion::datasource::physical_plan::ParquetExec;
+use datafusion::error::Result;
+use datafusion::execution::runtime_env::RuntimeEnv;
+use datafusion::parquet::arrow::async_reader::{AsyncFileReader, ParquetObjectReader};
+use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+use datafusion::physical_plan::repartition::RepartitionExec;
+use datafusion::physical_plan::sorts::sort_preserving_merge::SortPreservingMergeExec;
+use datafusion::physical_plan::union::UnionExec;
+use datafusion::physical_plan::{
+    with_new_children_if_necessary, Distribution, ExecutionPlan, Partitioning,
+};
+use futures::future::join_all;
+use log::debug;
+use object_store::path::Path;
+use object_store::ObjectStore;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Re-split the file groups of the Parquet scans of the leaf query stages so that each task
+/// scans about `target_bytes`. The row group metadata of the files larger than the target is
+/// read from the object stores of `runtime`, and files whose metadata cannot be read are split
+/// into byte ranges of equal size instead.
+///
+/// Only the scans whose partitions are the partitions of an exchange input are re-split, the
+/// number of partitions of other plans does not change.
+pub async fn plan_scan_partitions(
+    plan: Arc<dyn ExecutionPlan>,
+    target_bytes: usize,
+    runtime: &RuntimeEnv,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let target_bytes = target_bytes.clamp(1, i64::MAX as usize) as i64;
+    let mut large_files = vec![];
+    let plan = map_stage_scans(plan, &mut |scan| {
+        let config = scan.base_config();
+        if config.output_ordering.is_empty() {
+            if let Ok(store) = runtime.object_store(&config.object_store_url) {
+                for file in merge_file_ranges(&config.file_groups) {
+                    if file.range.is_none() && file.object_meta.size as i64 > target_bytes {
+                        large_files.push((store.clone(), file));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    })?;
+
+    let row_groups = join_all(
+        large_files
+            .into_iter()
+            .map(|(store, file)| row_group_offsets(store, file)),
+    )
+    .await
+    .into_iter()
+    .flatten()
+    .collect::<HashMap<_, _>>();
+
+    map_stage_scans(plan, &mut |scan| {
+        let config = scan.base_config();
+        // the files of each group are in order, which packing them together would break
+        if !config.output_ordering.is_empty() {
+            return Ok(None);
+        }
+        let units = merge_file_ranges(&config.file_groups)
+            .into_iter()
+            .flat_map(|file| split_file(file, target_bytes, &row_groups))
+            .collect::<Vec<_>>();
+        let file_groups = pack_files(units, target_bytes);
+        debug!(
+            "Re-split scan of {} file groups into {} file groups",
+            config.file_groups.len(),
+            file_groups.len()
+        );
+        let mut config = config.clone();
+        config.file_groups = file_groups;
+        Ok(Some(with_file_scan_config(scan, config)))
+    })
+}
+
+/// Replaces a scan, or returns `None` to keep it
+type ScanFn<'a> = dyn FnMut(&ParquetExec) -> Result<Option<Arc<dyn ExecutionPlan>>> + 'a;
+
+/// Apply `f` to the Parquet scans whose partitions are the partitions of an exchange input,
+/// which are the tasks of a leaf query stage
+fn map_stage_scans(plan: Arc<dyn ExecutionPlan>, f: &mut ScanFn) -> Result<Arc<dyn ExecutionPlan>> {
+    let exchange = is_exchange(plan.as_ref());
+    let children = plan
+        .children()
+        .into_iter()
+        .map(|child| {
+            if exchange {
+                map_stage_input(child.clone(), f)
+            } else {
+                map_stage_scans(child.clone(), f)
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    with_new_children_if_necessary(plan, children)
+}
+
+/// Apply `f` to the scans of a plan that is the input of an exchange, as long as the
+/// operators above them run each partition of their input in a partition of their own
+fn map_stage_input(plan: Arc<dyn ExecutionPlan>, f: &mut ScanFn) -> Result<Arc<dyn ExecutionPlan>> {
+    if let Some(scan) = plan.as_any().downcast_ref::<ParquetExec>() {
+        return Ok(f(scan)?.unwrap_or(plan));
+    }
+    if is_exchange(plan.as_ref()) {
+        return map_stage_scans(plan, f);
+    }
+    let passed = passes_partitions(plan.as_ref());
+    let children = plan
+        .children()
+        .into_iter()
+        .zip(passed)
+        .map(|(child, passed)| {
+            if passed {
+                map_stage_input(child.clone(), f)
+            } else {
+                map_stage_scans(child.clone(), f)
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    with_new_children_if_necessary(plan, children)
+}
+
+/// Whether a plan becomes an exchange between query stages, see `generate_query_stages`
+fn is_exchange(plan: &dyn ExecutionPlan) -> bool {
+    let any = plan.as_any();
+    if let Some(repart) = any.downcast_ref::<RepartitionExec>() {
+        matches!(repart.partitioning(), Partitioning::Hash(_, _))
+    } else {
+        any.is::<CoalescePartitionsExec>() || any.is::<SortPreservingMergeExec>()
+    }
+}
+
+/// For each child of a plan, whether the plan has a partition for each partition of the
+/// child, so that the child can have any number of partitions
+fn passes_partitions(plan: &dyn ExecutionPlan) -> Vec<bool> {
+    let children = plan.children();
+    let any = plan.as_any();
+    // the other repartitions are removed when the query stages are created
+    if any.is::<UnionExec>() || any.is::<RepartitionExec>() {
+        return vec![true; children.len()];
+    }
+    let count = plan.properties().output_partitioning().partition_count();
+    let passed = children
+        .iter()
+        .zip(plan.required_input_distribution())
+        .map(|(child, distribution)| {
+            matches!(distribution, Distribution::UnspecifiedDistribution)
+                && child.properties().output_partitioning().partition_count() == count
+        })
+        .collect::<Vec<_>>();
+    // the other children must be single partitions read by every partition, such as the
+    // collected build side of a join
+    let single_input = passed.iter().filter(|passed| **passed).count() == 1
+        && children.iter().zip(&passed).all(|(child, passed)| {
+            *passed || child.properties().output_partitioning().partition_count() == 1
+        });
+    if single_input {
+        passed
+    } else {
+        vec![false; children.len()]
+    }
+}
+
+/// The files of some file groups, with the byte ranges that DataFusion split a file into
+/// merged back into the whole file. Ranges that do not cover their file are kept.
+fn merge_file_ranges(file_groups: &[Vec<PartitionedFile>]) -> Vec<PartitionedFile> {
+    let mut locations = vec![];
+    let mut ranges: HashMap<Path, Vec<PartitionedFile>> = HashMap::new();
+    for file in file_groups.iter().flatten() {
+        let location = file.object_meta.location.clone();
+        if !ranges.contains_key(&location) {
+            locations.push(location.clone());
+        }
+        ranges.entry(location).or_default().push(file.clone());
+    }
+    locations
+        .into_iter()
+        .flat_map(|location| {
+            let mut files = ranges.remove(&location).unwrap_or_default();
+            files.sort_by_key(|file| file.range.as_ref().map_or(0, |range| range.start));
+            let size = files[0].object_meta.size as i64;
+            let mut end = 0;
+            let covered = files.iter().all(|file| match &file.range {
+                Some(range) if range.start == end => {
+                    end = range.end;
+                    true
+                }
+                Some(_) => false,
+                None => {
+                    end = size;
+                    true
+                }
+            }) && end >= size;
+            if covered {
+                let mut file = files.swap_remove(0);
+                file.range = None;
+                vec![file]
+            } else {
+                files
+            }
+        })
+        .collect()
+}
+
+/// The offset of each row group of a Parquet file, in file order, or `None` if the metadata
+/// of the file cannot be read
+async fn row_group_offsets(
+    store: Arc<dyn ObjectStore>,
+    file: PartitionedFile,
+) -> Option<(Path, Vec<i64>)> {
+    let location = file.object_meta.location.clone();
+    let mut reader = ParquetObjectReader::new(store, file.object_meta);
+    match reader.get_metadata().await {
+        Ok(metadata) => {
+            let mut offsets = metadata
+                .row_groups()
+                .iter()
+                .map(|row_group| {
+                    // DataFusion scans a row group in the range holding its first page
+                    let column = row_group.column(0);
+                    column
+                        .dictionary_page_offset()
+                        .unwrap_or_else(|| column.data_page_offset())
+                })
+                .collect::<Vec<_>>();
+            offsets.sort_unstable();
+            Some((location, offsets))
+        }
+        Err(e) => {
+            debug!("Failed to read the row groups of {location}: {e}");
+            None
+        }
+    }
+}
+
+/// Split a file larger than `target_bytes` into byte ranges of about `target_bytes`, starting
+/// at row group boundaries if the offsets of its row groups are known
+fn split_file(
+    file: PartitionedFile,
+    target_bytes: i64,
+    row_groups: &HashMap<Path, Vec<i64>>,
+) -> Vec<PartitionedFile> {
+    let size = file.object_meta.size as i64;
+    if file.range.is_some() || size <= target_bytes {
+        return vec![file];
+    }
+    let boundaries = match row_groups.get(&file.object_meta.location) {
+        // the first row group starts the first range, at offset 0
+        Some(offsets) => offsets.iter().skip(1).copied().collect::<Vec<_>>(),
+        None => {
+            let ranges = (size + target_bytes - 1) / target_bytes;
+            (1..ranges).map(|i| size * i / ranges).collect()
+        }
+    };
+    let mut files = vec![];
+    let mut start = 0;
+    for boundary in boundaries.into_iter().chain(std::iter::once(size)) {
+        if boundary - start >= target_bytes || boundary == size {
+            let mut range = file.clone();
+            range.range = Some(FileRange {
+                start,
+                end: boundary,
+            });
+            files.push(range);
+            start = boundary;
+        }
+    }
+    files
+}
+
+/// Pack files and file ranges, in order, into groups of at most `target_bytes`. A file larger
+/// than the target is a group of its own.
+fn pack_files(files: Vec<PartitionedFile>, target_bytes: i64) -> Vec<Vec<PartitionedFile>> {
+    let mut groups: Vec<Vec<PartitionedFile>> = vec![];
+    let mut group_bytes = 0;
+    for file in files {
+        let bytes = file
+            .range
+            .as_ref()
+            .map_or(file.object_meta.size as i64, |range| {
+                range.end - range.start
+            });
+        match groups.last_mut() {
+            Some(group) if group_bytes + bytes <= target_bytes => {
+                group.push(file);
+                group_bytes += bytes;
+            }
+            _ => {
+                groups.push(vec![file]);
+                group_bytes = bytes;
+            }
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::distributed::DistributedContext;
+    use crate::query_stage::file_scan_config;
+    use crate::test_utils::{TempDir, TestResult};
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use datafusion::parquet::arrow::ArrowWriter;
+    use datafusion::parquet::file::properties::WriterProperties;
+    use datafusion::prelude::{ParquetReadOptions, SessionConfig, SessionContext};
+    use std::fs::File;
+    use tokio::runtime::Runtime;
+
+    /// The file groups of the scans of a plan
+    fn scan_file_groups(plan: &Arc<dyn ExecutionPlan>) -> Vec<Vec<Vec<PartitionedFile>>> {
+        let mut groups = vec![];
+        if let Some(config) = file_scan_config(plan.as_ref()) {
+            groups.push(config.file_groups.clone());
+        }
+        for child in plan.children() {
+            groups.extend(scan_file_groups(child));
+        }
+        groups
+    }
+
+    #[test]
+    fn split_scans_by_row_groups() -> TestResult<()> {
+        let data_dir = TempDir::new()?;
+        let dir = data_dir.path();
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        // 4 files of a single row group, listed before a file of 8 row groups
+        let props = WriterProperties::builder()
+            .set_max_row_group_size(1000)
+            .build();
+        let mut writer = ArrowWriter::try_new(
+            File::create(dir.join("b-large.parquet"))?,
+            schema.clone(),
+            Some(props),
+        )?;
+        for i in 0..8 {
+            let values = (0..1000).map(|j| i * 1000 + j).collect::<Vec<i64>>();
+            writer.write(&RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Int64Array::from(values))],
+            )?)?;
+            writer.flush()?;
+        }
+        writer.close()?;
+        for i in 0..4 {
+            let mut writer = ArrowWriter::try_new(
+                File::create(dir.join(format!("a-small-{i}.parquet")))?,
+                schema.clone(),
+                None,
+            )?;
+            writer.write(&RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Int64Array::from(vec![i, i + 1]))],
+            )?)?;
+            writer.close()?;
+        }
+        let large_size = std::fs::metadata(dir.join("b-large.parquet"))?.len() as usize;
+
+        let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(2));
+        let sql = "SELECT a % 7 AS k, count(*) AS c FROM t GROUP BY k ORDER BY k";
+        let rt = Runtime::new()?;
+        let plan = rt.block_on(async {
+            ctx.register_parquet("t", dir.to_str().unwrap(), ParquetReadOptions::default())
+                .await?;
+            ctx.sql(sql).await?.create_physical_plan().await
+        })?;
+        assert_eq!(2, scan_file_groups(&plan)[0].len());
+
+        // about two row groups of the large file per task, and the small files in one task
+        let target = large_size / 4;
+        let split = rt.block_on(plan_scan_partitions(plan, target, &ctx.runtime_env()))?;
+        let groups = scan_file_groups(&split).remove(0);
+        let large = groups
+            .iter()
+            .flatten()
+            .filter(|file| {
+                file.object_meta
+                    .location
+                    .as_ref()
+                    .ends_with("b-large.parquet")
+            })
+            .map(|file| file.range.clone().unwrap())
+            .collect::<Vec<_>>();
+        assert!(large.len() >= 3 && large.len() <= 5, "{large:?}");
+        assert_eq!(0, large[0].start);
+        assert_eq!(large_size as i64, large.last().unwrap().end);
+        assert!(large.windows(2).all(|r| r[0].end == r[1].start));
+        let small = groups
+            .iter()
+            .filter(|group| {
+                group
+                    .iter()
+                    .all(|file| file.object_meta.location.as_ref().contains("a-small-"))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(1, small.len());
+        assert_eq!(4, small[0].len());
+
+        // the query returns the same result when its scans are re-split
+        let expected = rt.block_on(async { ctx.sql(sql).await?.collect().await })?;
+        let shuffle_dir = TempDir::new()?;
+        let ctx = DistributedContext::new(ctx)
+            .with_shuffle_storage(Some(shuffle_dir.path_str()))
+            .with_scan_task_bytes(Some(target));
+        let graph = rt.block_on(ctx.plan_sql(sql))?;
+        let leaf_tasks = graph
+            .query_stages
+            .values()
+            .map(|stage| stage.get_task_count())
+            .max()
+            .unwrap_or_default();
+        assert_eq!(groups.len(), leaf_tasks);
+        let actual = rt.block_on(ctx.execute(&graph))?;
+        assert_eq!(
+            pretty_format_batches(&expected)?.to_string(),
+            pretty_format_batches(&actual)?.to_string()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,884 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use crate::limit::stage_row_limits;
+use crate::planner::ExecutionGraph;
+use crate::query_stage::QueryStage;
+use datafusion::error::{DataFusionError, Result};
+use log::debug;
+use pyo3::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Number of times a task is attempted before the query fails
+pub const DEFAULT_MAX_TASK_ATTEMPTS: usize = 3;
+
+/// Settings for launching a duplicate attempt of a task that runs much longer than the other
+/// tasks of its stage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpeculationConfig {
+    /// Fraction of the tasks of a stage that must have succeeded before speculating
+    pub quantile: f64,
+    /// How many times slower than the median successful task a task must be
+    pub multiplier: f64,
+    /// Tasks that have been running for less than this are never speculated
+    pub min_runtime: Duration,
+}
+
+impl Default for SpeculationConfig {
+    fn default() -> Self {
+        Self {
+            quantile: 0.75,
+            multiplier: 1.5,
+            min_runtime: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Identifies a task, which executes one partition of a query stage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TaskId {
+    pub stage_id: usize,
+    pub partition: usize,
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.stage_id, self.partition)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+    /// Not needed because its stage already produced enough rows for a LIMIT
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageState {
+    /// Waiting for child stages to complete
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug)]
+struct TaskStatus {
+    state: TaskState,
+    /// Number of attempts that have been launched
+    attempts: usize,
+    /// Number of attempts that failed or whose output was lost
+    failures: usize,
+    /// Number of attempts that are currently running
+    running: usize,
+    /// When the first running attempt was launched
+    started: Option<Instant>,
+    /// How long the successful attempt took
+    duration: Option<Duration>,
+    last_error: Option<String>,
+    /// Number of rows written by the successful attempt, if known
+    output_rows: Option<usize>,
+}
+
+#[derive(Debug)]
+struct StageStatus {
+    stage: Arc<QueryStage>,
+    child_stage_ids: Vec<usize>,
+    tasks: Vec<TaskStatus>,
+    /// Number of rows after which the output of this stage is not needed anymore
+    row_limit: Option<usize>,
+}
+
+impl StageStatus {
+    fn state(&self) -> StageState {
+        let count = |state| self.tasks.iter().filter(|t| t.state == state).count();
+        if count(TaskState::Failed) > 0 {
+            StageState::Failed
+        } else if count(TaskState::Succeeded) + count(TaskState::Cancelled) == self.tasks.len() {
+            StageState::Succeeded
+        } else if count(TaskState::Pending) == self.tasks.len() {
+            StageState::Pending
+        } else {
+            StageState::Running
+        }
+    }
+}
+
+/// Tracks the progress of the stages and tasks of an [`ExecutionGraph`] and decides which
+/// tasks to run next.
+///
+/// The scheduler does not launch tasks itself. A frontend repeatedly asks for the runnable
+/// tasks, runs them and reports back whether they succeeded or failed. Failed tasks are
+/// retried until they reach the maximum number of attempts.
+///
+/// With speculation enabled, a task that runs much longer than the other tasks of its stage
+/// gets a second attempt. Whichever attempt finishes first provides the task's output and the
+/// outcome of the other attempt is ignored.
+///
+/// When a task finds that shuffle output it depends on has been lost, the map tasks that
+/// produced it are scheduled again and the task waits until they have succeeded.
+///
+/// When a stage is read by a LIMIT that does not sort its rows, the remaining tasks of the
+/// stage are cancelled once its successful tasks have written enough rows. The frontend takes
+/// the cancelled tasks, stops their running attempts and commits an empty output for them. The
+/// stopped attempts are not counted as running anymore, and any outcome they still report is
+/// ignored.
+#[derive(Debug)]
+pub struct StageScheduler {
+    stages: BTreeMap<usize, StageStatus>,
+    final_stage_id: usize,
+    max_task_attempts: usize,
+    speculation: Option<SpeculationConfig>,
+    /// Tasks that were cancelled since the frontend last asked for them
+    cancelled: Vec<TaskId>,
+}
+
+impl StageScheduler {
+    pub fn try_new(graph: &ExecutionGraph) -> Result<Self> {
+        let row_limits = graph
+            .query_stages
+            .values()
+            .flat_map(|stage| stage_row_limits(stage.plan.as_ref()))
+            .collect::<HashMap<_, _>>();
+        let stages = graph
+            .query_stages
+            .iter()
+            .map(|(id, stage)| {
+                let tasks = (0..stage.get_task_count())
+                    .map(|_| TaskStatus {
+                        state: TaskState::Pending,
+                        attempts: 0,
+                        failures: 0,
+                        running: 0,
+                        started: None,
+                        duration: None,
+                        last_error: None,
+                        output_rows: None,
+                    })
+                    .collect();
+                let status = StageStatus {
+                    stage: stage.clone(),
+                    child_stage_ids: stage.get_child_stage_ids(),
+                    tasks,
+                    row_limit: row_limits.get(id).copied(),
+                };
+                (*id, status)
+            })
+            .collect();
+        Ok(Self {
+            stages,
+            final_stage_id: graph.get_final_query_stage()?.id,
+            max_task_attempts: DEFAULT_MAX_TASK_ATTEMPTS,
+            speculation: None,
+            cancelled: vec![],
+        })
+    }
+
+    pub fn with_max_task_attempts(mut self, max_task_attempts: usize) -> Self {
+        self.max_task_attempts = max_task_attempts.max(1);
+        self
+    }
+
+    pub fn with_speculation(mut self, speculation: SpeculationConfig) -> Self {
+        self.speculation = Some(speculation);
+        self
+    }
+
+    pub fn final_stage_id(&self) -> usize {
+        self.final_stage_id
+    }
+
+    pub fn query_stage(&self, stage_id: usize) -> Result<Arc<QueryStage>> {
+        Ok(self.stage_status(stage_id)?.stage.clone())
+    }
+
+    /// Return the tasks that can be launched now and mark them as running. A task is runnable
+    /// once every stage that its stage reads from has succeeded. When speculation is enabled
+    /// this also returns a second attempt of tasks that are running for too long.
+    pub fn next_runnable_tasks(&mut self) -> Vec<TaskId> {
+        self.runnable_tasks_at(Instant::now())
+    }
+
+    fn runnable_tasks_at(&mut self, now: Instant) -> Vec<TaskId> {
+        let ready_stages = self
+            .stages
+            .iter()
+            .filter(|(_, status)| {
+                status.child_stage_ids.iter().all(|child_id| {
+                    self.stages
+                        .get(child_id)
+                        .map_or(false, |child| child.state() == StageState::Succeeded)
+                })
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        let mut tasks = vec![];
+        for (&stage_id, status) in self
+            .stages
+            .iter_mut()
+            .filter(|(id, _)| ready_stages.contains(id))
+        {
+            for (partition, task) in status.tasks.iter_mut().enumerate() {
+                if task.state == TaskState::Pending {
+                    task.state = TaskState::Running;
+                    task.attempts += 1;
+                    task.running += 1;
+                    task.started = Some(now);
+                    tasks.push(TaskId {
+                        stage_id,
+                        partition,
+                    });
+                }
+            }
+        }
+        if !tasks.is_empty() {
+            debug!("Scheduling tasks {tasks:?}");
+        }
+        if let Some(speculation) = self.speculation {
+            let speculative = self.speculative_tasks(&speculation, now);
+            if !speculative.is_empty() {
+                debug!("Scheduling speculative attempts of tasks {speculative:?}");
+            }
+            tasks.extend(speculative);
+        }
+        tasks
+    }
+
+    /// Launch a second attempt of running tasks that take much longer than the successful
+    /// tasks of their stage
+    fn speculative_tasks(&mut self, speculation: &SpeculationConfig, now: Instant) -> Vec<TaskId> {
+        let mut tasks = vec![];
+        for (stage_id, status) in self.stages.iter_mut() {
+            let mut durations = status
+                .tasks
+                .iter()
+                .filter_map(|t| t.duration)
+                .collect::<Vec<_>>();
+            if durations.is_empty()
+                || (durations.len() as f64) < speculation.quantile * status.tasks.len() as f64
+            {
+                continue;
+            }
+            durations.sort();
+            let threshold = durations[durations.len() / 2]
+                .mul_f64(speculation.multiplier)
+                .max(speculation.min_runtime);
+            for (partition, task) in status.tasks.iter_mut().enumerate() {
+                let slow = task
+                    .started
+                    .map_or(false, |started| now.duration_since(started) > threshold);
+                if task.state == TaskState::Running && task.running == 1 && slow {
+                    task.attempts += 1;
+                    task.running += 1;
+                    tasks.push(TaskId {
+                        stage_id: *stage_id,
+                        partition,
+                    });
+                }
+            }
+        }
+        tasks
+    }
+
+    /// Record that an attempt of a task succeeded. Returns false if another attempt of the
+    /// task already succeeded, in which case the output of this attempt should be ignored.
+    pub fn task_succeeded(&mut self, task: TaskId) -> Result<bool> {
+        let status = self.task_status(task)?;
+        match status.state {
+            TaskState::Running => {
+                status.state = TaskState::Succeeded;
+                status.running -= 1;
+                status.duration = status.started.map(|started| started.elapsed());
+                Ok(true)
+            }
+            TaskState::Succeeded if status.running > 0 => {
+                debug!("Ignoring duplicate attempt of task {task}");
+                status.running -= 1;
+                Ok(false)
+            }
+            TaskState::Cancelled => {
+                debug!("Ignoring attempt of cancelled task {task}");
+                Ok(false)
+            }
+            state => Err(DataFusionError::Internal(format!(
+                "Task {task} completed while {state:?}"
+            ))),
+        }
+    }
+
+    /// Record the number of rows written by a task that succeeded. Once the successful tasks
+    /// of a stage that is read by a LIMIT have written enough rows, its remaining tasks are
+    /// cancelled.
+    pub fn task_output_rows(&mut self, task: TaskId, rows: usize) -> Result<()> {
+        let status = self.task_status(task)?;
+        if status.state != TaskState::Succeeded {
+            return Ok(());
+        }
+        status.output_rows = Some(rows);
+        let status = self.stages.get_mut(&task.stage_id).ok_or_else(|| {
+            DataFusionError::Internal(format!("Query stage #{} not found", task.stage_id))
+        })?;
+        let row_limit = match status.row_limit {
+            Some(row_limit) => row_limit,
+            None => return Ok(()),
+        };
+        let rows = status
+            .tasks
+            .iter()
+            .filter(|t| t.state == TaskState::Succeeded)
+            .filter_map(|t| t.output_rows)
+            .sum::<usize>();
+        if rows < row_limit {
+            return Ok(());
+        }
+        for (partition, task) in status.tasks.iter_mut().enumerate() {
+            if matches!(task.state, TaskState::Pending | TaskState::Running) {
+                task.state = TaskState::Cancelled;
+                // the frontend stops the running attempts without reporting their outcome
+                task.running = 0;
+                self.cancelled.push(TaskId {
+                    stage_id: status.stage.id,
+                    partition,
+                });
+            }
+        }
+        if !self.cancelled.is_empty() {
+            debug!(
+                "Query stage #{} has {rows} of {row_limit} rows, cancelling tasks {:?}",
+                status.stage.id, self.cancelled
+            );
+        }
+        Ok(())
+    }
+
+    /// Take the tasks that were cancelled since the last call. Their running attempts should
+    /// be stopped, and an empty output committed in their place.
+    pub fn take_cancelled_tasks(&mut self) -> Vec<TaskId> {
+        std::mem::take(&mut self.cancelled)
+    }
+
+    /// Record a task failure. The task is rescheduled if it has attempts left, otherwise the
+    /// query fails and an error describing the last failure is returned. The failure of an
+    /// attempt is ignored while another attempt of the same task is running or has succeeded.
+    pub fn task_failed(&mut self, task: TaskId, error: &str) -> Result<()> {
+        let max_task_attempts = self.max_task_attempts;
+        let status = self.task_status(task)?;
+        match status.state {
+            TaskState::Running if status.running > 1 => {
+                debug!("Attempt of task {task} failed, waiting for other attempt: {error}");
+                status.running -= 1;
+                status.last_error = Some(error.to_string());
+                Ok(())
+            }
+            TaskState::Running => {
+                status.running -= 1;
+                Self::record_failure(task, status, error, max_task_attempts)
+            }
+            TaskState::Succeeded if status.running > 0 => {
+                status.running -= 1;
+                Ok(())
+            }
+            TaskState::Cancelled => Ok(()),
+            state => Err(DataFusionError::Internal(format!(
+                "Task {task} failed while {state:?}"
+            ))),
+        }
+    }
+
+    /// Record that a running task could not read the output of some map tasks of one of its
+    /// input stages. The task is rescheduled without counting this as a failure, and the map
+    /// tasks that have already succeeded are rerun so that their output is recomputed.
+    pub fn map_outputs_lost(
+        &mut self,
+        task: TaskId,
+        input_stage_id: usize,
+        map_partitions: &[usize],
+    ) -> Result<()> {
+        let max_task_attempts = self.max_task_attempts;
+        let status = self.stage_status(task.stage_id)?;
+        if !status.child_stage_ids.contains(&input_stage_id) {
+            return Err(DataFusionError::Internal(format!(
+                "Task {task} does not read from query stage #{input_stage_id}"
+            )));
+        }
+        let status = self.task_status(task)?;
+        if status.state == TaskState::Succeeded && status.running > 0 {
+            // another attempt of this task already read the input
+            status.running -= 1;
+            return Ok(());
+        }
+        if status.state == TaskState::Cancelled {
+            // the output of this task is not needed
+            return Ok(());
+        }
+        if status.state != TaskState::Running {
+            return Err(DataFusionError::Internal(format!(
+                "Task {task} lost its input while {:?}",
+                status.state
+            )));
+        }
+        // undo the launch so that waiting for lost input does not use up an attempt
+        status.attempts -= 1;
+        status.running -= 1;
+        if status.running == 0 {
+            status.state = TaskState::Pending;
+        }
+
+        let error = format!("Output of task {task} input stage #{input_stage_id} was lost");
+        for partition in map_partitions {
+            let map_task = TaskId {
+                stage_id: input_stage_id,
+                partition: *partition,
+            };
+            let status = self.task_status(map_task)?;
+            // a map task that is already being rerun will produce its output again, and the
+            // empty output of a cancelled task is recomputed by running it in full
+            if matches!(status.state, TaskState::Succeeded | TaskState::Cancelled) {
+                debug!("Recomputing lost output of task {map_task}");
+                status.state = TaskState::Running;
+                Self::record_failure(map_task, status, &error, max_task_attempts)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn record_failure(
+        task: TaskId,
+        status: &mut TaskStatus,
+        error: &str,
+        max_task_attempts: usize,
+    ) -> Result<()> {
+        status.last_error = Some(error.to_string());
+        status.failures += 1;
+        if status.failures < max_task_attempts {
+            debug!(
+                "Task {task} failed on attempt {}, retrying: {error}",
+                status.attempts
+            );
+            status.state = TaskState::Pending;
+            Ok(())
+        } else {
+            status.state = TaskState::Failed;
+            Err(DataFusionError::Execution(format!(
+                "Task {task} failed after {} attempts: {error}",
+                status.attempts
+            )))
+        }
+    }
+
+    /// Returns true once every task of the final query stage has succeeded
+    pub fn is_complete(&self) -> bool {
+        self.stages[&self.final_stage_id].state() == StageState::Succeeded
+    }
+
+    /// Returns true if any task has exhausted its attempts
+    pub fn is_failed(&self) -> bool {
+        self.stages
+            .values()
+            .any(|status| status.state() == StageState::Failed)
+    }
+
+    pub fn stage_state(&self, stage_id: usize) -> Result<StageState> {
+        Ok(self.stage_status(stage_id)?.state())
+    }
+
+    pub fn task_state(&self, task: TaskId) -> Result<TaskState> {
+        let status = self.stage_status(task.stage_id)?;
+        status
+            .tasks
+            .get(task.partition)
+            .map(|t| t.state)
+            .ok_or_else(|| DataFusionError::Internal(format!("Task {task} not found")))
+    }
+
+    /// Number of attempts that have been launched for a task
+    pub fn task_attempts(&self, task: TaskId) -> Result<usize> {
+        let status = self.stage_status(task.stage_id)?;
+        status
+            .tasks
+            .get(task.partition)
+            .map(|t| t.attempts)
+            .ok_or_else(|| DataFusionError::Internal(format!("Task {task} not found")))
+    }
+
+    fn stage_status(&self, stage_id: usize) -> Result<&StageStatus> {
+        self.stages
+            .get(&stage_id)
+            .ok_or_else(|| DataFusionError::Internal(format!("Query stage #{stage_id} not found")))
+    }
+
+    fn task_status(&mut self, task: TaskId) -> Result<&mut TaskStatus> {
+        self.stages
+            .get_mut(&task.stage_id)
+            .and_then(|status| status.tasks.get_mut(task.partition))
+            .ok_or_else(|| DataFusionError::Internal(format!("Task {task} not found")))
+    }
+}
+
+/// Python wrapper around [`StageScheduler`]. Python launches the tasks returned by
+/// `next_runnable_tasks` on Ray and reports their outcome.
+#[pyclass(name = "StageScheduler", module = "datafusion_ray", subclass)]
+pub struct PyStageScheduler {
+    scheduler: StageScheduler,
+}
+
+impl PyStageScheduler {
+    pub fn new(scheduler: StageScheduler) -> Self {
+        Self { scheduler }
+    }
+}
+
+#[pymethods]
+impl PyStageScheduler {
+    /// Get the (stage_id, partition) pairs that can be launched now
+    pub fn next_runnable_tasks(&mut self) -> Vec<(usize, usize)> {
+        self.scheduler
+            .next_runnable_tasks()
+            .into_iter()
+            .map(|t| (t.stage_id, t.partition))
+            .collect()
+    }
+
+    /// Record that a task succeeded. Returns false if this was a duplicate attempt whose
+    /// output should be ignored.
+    pub fn task_succeeded(&mut self, stage_id: usize, partition: usize) -> PyResult<bool> {
+        Ok(self.scheduler.task_succeeded(TaskId {
+            stage_id,
+            partition,
+        })?)
+    }
+
+    /// Record the number of rows written by a task that succeeded
+    pub fn task_output_rows(
+        &mut self,
+        stage_id: usize,
+        partition: usize,
+        rows: usize,
+    ) -> PyResult<()> {
+        let task = TaskId {
+            stage_id,
+            partition,
+        };
+        Ok(self.scheduler.task_output_rows(task, rows)?)
+    }
+
+    /// Get the (stage_id, partition) pairs of the tasks cancelled since the last call. Their
+    /// running attempts should be cancelled and an empty output committed in their place.
+    pub fn take_cancelled_tasks(&mut self) -> Vec<(usize, usize)> {
+        self.scheduler
+            .take_cancelled_tasks()
+            .into_iter()
+            .map(|t| (t.stage_id, t.partition))
+            .collect()
+    }
+
+    /// Record a task failure. Raises an exception if the task has no attempts left.
+    pub fn task_failed(&mut self, stage_id: usize, partition: usize, error: &str) -> PyResult<()> {
+        let task = TaskId {
+            stage_id,
+            partition,
+        };
+        Ok(self.scheduler.task_failed(task, error)?)
+    }
+
+    /// Record that a task could not read the output of some map tasks of an input stage
+    pub fn map_outputs_lost(
+        &mut self,
+        stage_id: usize,
+        partition: usize,
+        input_stage_id: usize,
+        map_partitions: Vec<usize>,
+    ) -> PyResult<()> {
+        let task = TaskId {
+            stage_id,
+            partition,
+        };
+        Ok(self
+            .scheduler
+            .map_outputs_lost(task, input_stage_id, &map_partitions)?)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.scheduler.is_complete()
+    }
+
+    pub fn final_stage_id(&self) -> usize {
+        self.scheduler.final_stage_id()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shuffle::{LocalShuffleStorage, ShuffleReaderExec, ShuffleWriterExec};
+    use datafusion::arrow::datatypes::Schema;
+    use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+    use datafusion::physical_plan::empty::EmptyExec;
+    use datafusion::physical_plan::limit::GlobalLimitExec;
+    use datafusion::physical_plan::Partitioning;
+
+    /// Two leaf stages with two tasks each, joined by a final stage with one task
+    fn graph() -> ExecutionGraph {
+        let schema = Arc::new(Schema::empty());
+        let storage = Arc::new(LocalShuffleStorage::new(""));
+        let mut graph = ExecutionGraph::new();
+        for id in 0..2 {
+            let input = Arc::new(EmptyExec::new(schema.clone()).with_partitions(2));
+            let writer = ShuffleWriterExec::new(
+                id,
+                input,
+                Partitioning::UnknownPartitioning(2),
+                storage.clone(),
+            );
+            graph
+                .query_stages
+                .insert(id, Arc::new(QueryStage::new(id, Arc::new(writer))));
+        }
+        let left = Arc::new(ShuffleReaderExec::new(
+            0,
+            schema.clone(),
+            Partitioning::UnknownPartitioning(1),
+            storage.clone(),
+            2,
+        ));
+        let right = Arc::new(ShuffleReaderExec::new(
+            1,
+            schema.clone(),
+            Partitioning::UnknownPartitioning(1),
+            storage.clone(),
+            2,
+        ));
+        let union = Arc::new(datafusion::physical_plan::union::UnionExec::new(vec![
+            left, right,
+        ]));
+        let root = Arc::new(
+            datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec::new(union),
+        );
+        graph
+            .query_stages
+            .insert(2, Arc::new(QueryStage::new(2, root)));
+        graph
+    }
+
+    fn task(stage_id: usize, partition: usize) -> TaskId {
+        TaskId {
+            stage_id,
+            partition,
+        }
+    }
+
+    #[test]
+    fn schedule_in_dependency_order() -> Result<()> {
+        let mut scheduler = StageScheduler::try_new(&graph())?;
+        let tasks = scheduler.next_runnable_tasks();
+        assert_eq!(vec![task(0, 0), task(0, 1), task(1, 0), task(1, 1)], tasks);
+        assert!(scheduler.next_runnable_tasks().is_empty());
+        assert_eq!(StageState::Pending, scheduler.stage_state(2)?);
+
+        for t in &tasks[..3] {
+            scheduler.task_succeeded(*t)?;
+        }
+        assert_eq!(StageState::Succeeded, scheduler.stage_state(0)?);
+        assert_eq!(StageState::Running, scheduler.stage_state(1)?);
+        assert!(scheduler.next_runnable_tasks().is_empty());
+
+        scheduler.task_succeeded(tasks[3])?;
+        assert_eq!(vec![task(2, 0)], scheduler.next_runnable_tasks());
+        assert!(!scheduler.is_complete());
+        scheduler.task_succeeded(task(2, 0))?;
+        assert!(scheduler.is_complete());
+        Ok(())
+    }
+
+    #[test]
+    fn retry_failed_tasks() -> Result<()> {
+        let mut scheduler = StageScheduler::try_new(&graph())?.with_max_task_attempts(2);
+        scheduler.next_runnable_tasks();
+        scheduler.task_failed(task(0, 1), "boom")?;
+        assert_eq!(TaskState::Pending, scheduler.task_state(task(0, 1))?);
+        assert_eq!(vec![task(0, 1)], scheduler.next_runnable_tasks());
+        assert_eq!(2, scheduler.task_attempts(task(0, 1))?);
+
+        let err = scheduler.task_failed(task(0, 1), "boom again").unwrap_err();
+        assert!(err.to_string().contains("Task 0/1 failed after 2 attempts"));
+        assert_eq!(StageState::Failed, scheduler.stage_state(0)?);
+        assert!(scheduler.is_failed());
+        Ok(())
+    }
+
+    #[test]
+    fn recompute_lost_map_outputs() -> Result<()> {
+        let mut scheduler = StageScheduler::try_new(&graph())?.with_max_task_attempts(2);
+        for t in scheduler.next_runnable_tasks() {
+            scheduler.task_succeeded(t)?;
+        }
+        assert_eq!(vec![task(2, 0)], scheduler.next_runnable_tasks());
+
+        scheduler.map_outputs_lost(task(2, 0), 0, &[1])?;
+        assert_eq!(TaskState::Pending, scheduler.task_state(task(2, 0))?);
+        assert_eq!(StageState::Running, scheduler.stage_state(0)?);
+        // only the lost map task runs, the reducer waits for it
+        assert_eq!(vec![task(0, 1)], scheduler.next_runnable_tasks());
+        assert!(scheduler.next_runnable_tasks().is_empty());
+        scheduler.task_succeeded(task(0, 1))?;
+
+        assert_eq!(vec![task(2, 0)], scheduler.next_runnable_tasks());
+        assert_eq!(1, scheduler.task_attempts(task(2, 0))?);
+
+        // losing the same output again exhausts the attempts of the map task
+        let err = scheduler.map_outputs_lost(task(2, 0), 0, &[1]).unwrap_err();
+        assert!(err.to_string().contains("Task 0/1 failed after 2 attempts"));
+        assert!(scheduler.is_failed());
+        assert!(scheduler.map_outputs_lost(task(2, 0), 2, &[0]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn speculate_slow_tasks() -> Result<()> {
+        let speculation = SpeculationConfig {
+            quantile: 0.5,
+            multiplier: 2.0,
+            min_runtime: Duration::from_secs(10),
+        };
+        let mut scheduler = StageScheduler::try_new(&graph())?.with_speculation(speculation);
+        let start = Instant::now();
+        assert_eq!(4, scheduler.runnable_tasks_at(start).len());
+        scheduler.task_succeeded(task(0, 0))?;
+        // not slow enough yet
+        assert!(scheduler
+            .runnable_tasks_at(start + Duration::from_secs(5))
+            .is_empty());
+
+        let later = start + Duration::from_secs(20);
+        assert_eq!(vec![task(0, 1)], scheduler.runnable_tasks_at(later));
+        assert!(scheduler.runnable_tasks_at(later).is_empty());
+        assert_eq!(2, scheduler.task_attempts(task(0, 1))?);
+
+        // the first attempt to finish wins, the outcome of the other one is ignored
+        assert!(scheduler.task_succeeded(task(0, 1))?);
+        assert!(!scheduler.task_succeeded(task(0, 1))?);
+        assert_eq!(StageState::Succeeded, scheduler.stage_state(0)?);
+        assert!(scheduler.task_succeeded(task(0, 1)).is_err());
+
+        // a failed attempt does not fail the task while the other attempt is running
+        scheduler.task_succeeded(task(1, 0))?;
+        assert_eq!(vec![task(1, 1)], scheduler.runnable_tasks_at(later));
+        scheduler.task_failed(task(1, 1), "boom")?;
+        assert_eq!(TaskState::Running, scheduler.task_state(task(1, 1))?);
+        assert!(scheduler.task_succeeded(task(1, 1))?);
+        Ok(())
+    }
+
+    /// A leaf stage with four tasks that is read by a LIMIT of 10 rows
+    fn limit_graph() -> ExecutionGraph {
+        let schema = Arc::new(Schema::empty());
+        let storage = Arc::new(LocalShuffleStorage::new(""));
+        let input = Arc::new(EmptyExec::new(schema.clone()).with_partitions(4));
+        let writer = ShuffleWriterExec::new(
+            0,
+            input,
+            Partitioning::UnknownPartitioning(4),
+            storage.clone(),
+        );
+        let reader = Arc::new(ShuffleReaderExec::new(
+            0,
+            schema,
+            Partitioning::UnknownPartitioning(4),
+            storage,
+            4,
+        ));
+        let merge = Arc::new(CoalescePartitionsExec::new(reader));
+        let limit = GlobalLimitExec::new(merge, 0, Some(10));
+        let mut graph = ExecutionGraph::new();
+        graph
+            .query_stages
+            .insert(0, Arc::new(QueryStage::new(0, Arc::new(writer))));
+        graph
+            .query_stages
+            .insert(1, Arc::new(QueryStage::new(1, Arc::new(limit))));
+        graph
+    }
+
+    #[test]
+    fn cancel_tasks_after_row_limit() -> Result<()> {
+        let mut scheduler = StageScheduler::try_new(&limit_graph())?;
+        assert_eq!(4, scheduler.next_runnable_tasks().len());
+        scheduler.task_succeeded(task(0, 0))?;
+        scheduler.task_output_rows(task(0, 0), 6)?;
+        assert!(scheduler.take_cancelled_tasks().is_empty());
+
+        scheduler.task_succeeded(task(0, 2))?;
+        scheduler.task_output_rows(task(0, 2), 4)?;
+        assert_eq!(
+            vec![task(0, 1), task(0, 3)],
+            scheduler.take_cancelled_tasks()
+        );
+        assert!(scheduler.take_cancelled_tasks().is_empty());
+        assert_eq!(TaskState::Cancelled, scheduler.task_state(task(0, 1))?);
+        assert_eq!(StageState::Succeeded, scheduler.stage_state(0)?);
+        assert_eq!(vec![task(1, 0)], scheduler.next_runnable_tasks());
+
+        // the outcome of an attempt of a cancelled task is ignored
+        assert!(!scheduler.task_succeeded(task(0, 1))?);
+        scheduler.task_failed(task(0, 3), "cancelled")?;
+        assert_eq!(StageState::Succeeded, scheduler.stage_state(0)?);
+        Ok(())
+    }
+
+    #[test]
+    fn recompute_lost_output_of_cancelled_task() -> Result<()> {
+        let mut scheduler = StageScheduler::try_new(&limit_graph())?.with_max_task_attempts(3);
+        assert_eq!(4, scheduler.next_runnable_tasks().len());
+        scheduler.task_succeeded(task(0, 0))?;
+        scheduler.task_output_rows(task(0, 0), 10)?;
+        assert_eq!(
+            vec![task(0, 1), task(0, 2), task(0, 3)],
+            scheduler.take_cancelled_tasks()
+        );
+        // the frontend stops the attempts of the cancelled tasks without reporting them
+        assert_eq!(vec![task(1, 0)], scheduler.next_runnable_tasks());
+
+        // the empty output of a cancelled task is lost, so the task runs in full
+        scheduler.map_outputs_lost(task(1, 0), 0, &[1])?;
+        assert_eq!(vec![task(0, 1)], scheduler.next_runnable_tasks());
+
+        // a failure of the only running attempt retries the task instead of waiting for the
+        // stopped one
+        scheduler.task_failed(task(0, 1), "boom")?;
+        assert_eq!(TaskState::Pending, scheduler.task_state(task(0, 1))?);
+        assert_eq!(vec![task(0, 1)], scheduler.next_runnable_tasks());
+        assert!(scheduler.task_succeeded(task(0, 1))?);
+        assert_eq!(vec![task(1, 0)], scheduler.next_runnable_tasks());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unexpected_transitions() -> Result<()> {
+        let mut scheduler = StageScheduler::try_new(&graph())?;
+        assert!(scheduler.task_succeeded(task(0, 0)).is_err());
+        assert!(scheduler.task_succeeded(task(7, 0)).is_err());
+        Ok(())
+    }
+}
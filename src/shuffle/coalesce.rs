@@ -0,0 +1,259 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use datafusion::arrow::compute::concat_batches;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::execution::memory_pool::MemoryReservation;
+use datafusion::physical_plan::coalesce::{BatchCoalescer, CoalescerState};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::SendableRecordBatchStream;
+use futures::StreamExt;
+
+/// Number of bytes at which the batches buffered for an output partition are written, even if
+/// they hold fewer rows than the target batch size
+pub const SHUFFLE_TARGET_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// Maximum number of bytes a shuffle writer buffers across all output partitions
+pub const SHUFFLE_WRITE_BUFFER_BYTES: usize = 64 * 1024 * 1024;
+
+/// Batches of one output partition that have not been written yet
+#[derive(Default)]
+struct PartitionBuffer {
+    batches: Vec<RecordBatch>,
+    num_rows: usize,
+    num_bytes: usize,
+}
+
+/// Buffers the small batches produced by repartitioning until an output partition holds
+/// `target_rows` rows or `target_bytes` bytes, so that shuffle files hold few large batches.
+/// When the buffers of all partitions exceed the memory budget, or the memory pool of the task
+/// cannot grow, the largest buffers are flushed early.
+pub struct ShuffleBatchBuffers {
+    schema: SchemaRef,
+    buffers: Vec<PartitionBuffer>,
+    target_rows: usize,
+    target_bytes: usize,
+    memory_budget: usize,
+    reservation: MemoryReservation,
+}
+
+impl ShuffleBatchBuffers {
+    pub fn new(
+        schema: SchemaRef,
+        partition_count: usize,
+        target_rows: usize,
+        target_bytes: usize,
+        memory_budget: usize,
+        reservation: MemoryReservation,
+    ) -> Self {
+        Self {
+            schema,
+            buffers: (0..partition_count)
+                .map(|_| PartitionBuffer::default())
+                .collect(),
+            target_rows,
+            target_bytes,
+            memory_budget,
+            reservation,
+        }
+    }
+
+    /// Buffer a batch of an output partition. Returns the coalesced batches that are ready to
+    /// be written, by output partition.
+    pub fn push(
+        &mut self,
+        partition: usize,
+        batch: RecordBatch,
+    ) -> Result<Vec<(usize, RecordBatch)>> {
+        let mut ready = vec![];
+        if batch.num_rows() == 0 {
+            return Ok(ready);
+        }
+        // stay within the budget, and within the memory pool of the task, by writing out the
+        // largest buffers first
+        let num_bytes = batch.get_array_memory_size();
+        while self.reservation.size() + num_bytes > self.memory_budget
+            || self.reservation.try_grow(num_bytes).is_err()
+        {
+            match self.largest() {
+                Some(partition) => ready.push((partition, self.flush(partition)?)),
+                None => {
+                    // nothing left to write out, so a single batch is buffered regardless
+                    self.reservation.grow(num_bytes);
+                    break;
+                }
+            }
+        }
+
+        let buffer = &mut self.buffers[partition];
+        buffer.num_rows += batch.num_rows();
+        buffer.num_bytes += num_bytes;
+        buffer.batches.push(batch);
+        if buffer.num_rows >= self.target_rows || buffer.num_bytes >= self.target_bytes {
+            ready.push((partition, self.flush(partition)?));
+        }
+        Ok(ready)
+    }
+
+    /// Write out the remaining buffered batches, by output partition
+    pub fn finish(&mut self) -> Result<Vec<(usize, RecordBatch)>> {
+        let mut ready = vec![];
+        for partition in 0..self.buffers.len() {
+            if !self.buffers[partition].batches.is_empty() {
+                ready.push((partition, self.flush(partition)?));
+            }
+        }
+        Ok(ready)
+    }
+
+    /// Partition with the most buffered bytes, if any
+    fn largest(&self) -> Option<usize> {
+        self.buffers
+            .iter()
+            .enumerate()
+            .filter(|(_, buffer)| !buffer.batches.is_empty())
+            .max_by_key(|(_, buffer)| buffer.num_bytes)
+            .map(|(partition, _)| partition)
+    }
+
+    fn flush(&mut self, partition: usize) -> Result<RecordBatch> {
+        let buffer = std::mem::take(&mut self.buffers[partition]);
+        self.reservation.shrink(buffer.num_bytes);
+        Ok(concat_batches(&self.schema, &buffer.batches)?)
+    }
+}
+
+/// Combine the batches of a stream into batches of at least `batch_size` rows, except for the
+/// last one
+pub fn coalesce_stream(
+    stream: SendableRecordBatchStream,
+    batch_size: usize,
+) -> SendableRecordBatchStream {
+    let schema = stream.schema();
+    let coalescer = BatchCoalescer::new(schema.clone(), batch_size, None);
+    let batches = futures::stream::unfold(
+        (stream, coalescer, false),
+        |(mut stream, mut coalescer, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                match stream.next().await {
+                    Some(Ok(batch)) => {
+                        if let CoalescerState::TargetReached = coalescer.push_batch(batch) {
+                            let batch = coalescer.finish_batch();
+                            return Some((batch, (stream, coalescer, false)));
+                        }
+                    }
+                    Some(Err(e)) => return Some((Err(e), (stream, coalescer, true))),
+                    None if coalescer.is_empty() => return None,
+                    None => {
+                        let batch = coalescer.finish_batch();
+                        return Some((batch, (stream, coalescer, true)));
+                    }
+                }
+            }
+        },
+    );
+    Box::pin(RecordBatchStreamAdapter::new(schema, batches))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::TestResult;
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::execution::memory_pool::{GreedyMemoryPool, MemoryConsumer, MemoryPool};
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::memory::MemoryStream;
+    use std::sync::Arc;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn buffer_until_target_rows() -> TestResult<()> {
+        let mut buffers = create_buffers(2, 100, usize::MAX)?;
+        let mut written = vec![];
+        for i in 0..30 {
+            written.extend(buffers.push(i % 2, create_batch(10)?)?);
+        }
+        written.extend(buffers.finish()?);
+        let rows = written
+            .iter()
+            .map(|(partition, batch)| (*partition, batch.num_rows()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(0, 100), (1, 100), (0, 50), (1, 50)], rows);
+        Ok(())
+    }
+
+    #[test]
+    fn flush_largest_buffer_over_budget() -> TestResult<()> {
+        let batch_bytes = create_batch(10)?.get_array_memory_size();
+        let mut buffers = create_buffers(2, 1000, batch_bytes * 3)?;
+        assert!(buffers.push(0, create_batch(10)?)?.is_empty());
+        assert!(buffers.push(0, create_batch(10)?)?.is_empty());
+        assert!(buffers.push(1, create_batch(10)?)?.is_empty());
+        let written = buffers.push(1, create_batch(10)?)?;
+        assert_eq!(1, written.len());
+        assert_eq!(0, written[0].0);
+        assert_eq!(20, written[0].1.num_rows());
+        Ok(())
+    }
+
+    #[test]
+    fn coalesce_small_batches() -> TestResult<()> {
+        let batches = (0..25)
+            .map(|_| create_batch(10))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let stream = MemoryStream::try_new(batches, schema(), None)?;
+        let stream = coalesce_stream(Box::pin(stream), 64);
+        let batches = Runtime::new()?.block_on(collect(stream))?;
+        let rows = batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>();
+        assert_eq!(vec![70, 70, 70, 40], rows);
+        Ok(())
+    }
+
+    fn create_buffers(
+        partitions: usize,
+        target_rows: usize,
+        memory_budget: usize,
+    ) -> TestResult<ShuffleBatchBuffers> {
+        let pool: Arc<dyn MemoryPool> = Arc::new(GreedyMemoryPool::new(usize::MAX / 2));
+        let reservation = MemoryConsumer::new("test").register(&pool);
+        Ok(ShuffleBatchBuffers::new(
+            schema(),
+            partitions,
+            target_rows,
+            usize::MAX,
+            memory_budget,
+            reservation,
+        ))
+    }
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]))
+    }
+
+    fn create_batch(rows: i32) -> TestResult<RecordBatch> {
+        Ok(RecordBatch::try_new(
+            schema(),
+            vec![Arc::new(Int32Array::from((0..rows).collect::<Vec<_>>()))],
+        )?)
+    }
+}
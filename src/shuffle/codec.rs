@@ -15,16 +15,40 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use crate::error::RayError;
 use crate::protobuf::ray_sql_exec_node::PlanType;
-use crate::protobuf::{RaySqlExecNode, ShuffleReaderExecNode, ShuffleWriterExecNode};
-use crate::shuffle::{ShuffleReaderExec, ShuffleWriterExec};
+use crate::protobuf::sink_writer_exec_node::Sink;
+use crate::protobuf::FileScanFormat as protobuf_file_scan_format;
+use crate::protobuf::ShuffleMode as protobuf_shuffle_mode;
+use crate::protobuf::ShuffleStorageBackend as protobuf_storage_backend;
+use crate::protobuf::{
+    FileScanExecNode, MemoryShuffleReaderExecNode, RaySqlExecNode, ShuffleProjection,
+    ShuffleReaderExecNode, ShuffleStorageNode, ShuffleWriterExecNode, SinkCommitExecNode,
+    SinkWriterExecNode,
+};
+use crate::shuffle::{
+    create_shuffle_storage, MemoryShuffleReaderExec, ShuffleMode, ShuffleReaderExec,
+    ShuffleStorage, ShuffleStorageBackend, ShuffleWriterExec,
+};
+use crate::sink::{SinkCommitExec, SinkWriterExec};
 use datafusion::arrow::datatypes::SchemaRef;
 use datafusion::common::{DataFusionError, Result};
+use datafusion::datasource::file_format::csv::CsvSink;
+use datafusion::datasource::file_format::file_compression_type::FileCompressionType;
+use datafusion::datasource::file_format::json::JsonSink;
+use datafusion::datasource::file_format::parquet::ParquetSink;
+use datafusion::datasource::listing::ListingTableUrl;
+use datafusion::datasource::physical_plan::{ArrowExec, FileScanConfig, NdJsonExec};
 use datafusion::execution::runtime_env::RuntimeEnv;
 use datafusion::execution::FunctionRegistry;
+use datafusion::physical_plan::insert::DataSink;
 use datafusion::physical_plan::{ExecutionPlan, Partitioning};
-use datafusion_proto::physical_plan::from_proto::parse_protobuf_hash_partitioning;
-use datafusion_proto::physical_plan::to_proto::serialize_physical_expr;
+use datafusion_proto::physical_plan::from_proto::{
+    parse_physical_expr, parse_protobuf_file_scan_config, parse_protobuf_hash_partitioning,
+};
+use datafusion_proto::physical_plan::to_proto::{
+    serialize_file_scan_config, serialize_physical_expr,
+};
 use datafusion_proto::physical_plan::PhysicalExtensionCodec;
 use datafusion_proto::physical_plan::{AsExecutionPlan, DefaultPhysicalExtensionCodec};
 use datafusion_proto::protobuf::{self, PhysicalHashRepartition, PhysicalPlanNode};
@@ -38,50 +62,122 @@ impl PhysicalExtensionCodec for ShuffleCodec {
     fn try_decode(
         &self,
         buf: &[u8],
-        _inputs: &[Arc<dyn ExecutionPlan>],
+        inputs: &[Arc<dyn ExecutionPlan>],
         registry: &dyn FunctionRegistry,
     ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
         // decode bytes to protobuf struct
         let node = RaySqlExecNode::decode(buf)
-            .map_err(|e| DataFusionError::Internal(format!("failed to decode plan: {e:?}")))?;
+            .map_err(|e| RayError::Codec(format!("failed to decode plan: {e:?}")))?;
         let extension_codec = DefaultPhysicalExtensionCodec {};
         match node.plan_type {
             Some(PlanType::ShuffleReader(reader)) => {
-                let schema = reader.schema.as_ref().unwrap();
-                let schema: SchemaRef = Arc::new(schema.try_into().unwrap());
+                let schema = decode_schema(reader.schema.as_ref())?;
                 let hash_part = parse_protobuf_hash_partitioning(
                     reader.partitioning.as_ref(),
                     registry,
                     &schema,
                     &extension_codec,
                 )?;
-                Ok(Arc::new(ShuffleReaderExec::new(
+                let mut exec = ShuffleReaderExec::new(
                     reader.stage_id as usize,
                     schema,
-                    hash_part.unwrap(),
-                    &reader.shuffle_dir,
-                )))
+                    hash_part.ok_or_else(|| missing("Shuffle partitioning"))?,
+                    decode_storage(reader.storage.as_ref())?,
+                    reader.map_partition_count as usize,
+                )
+                .with_mmap(reader.mmap)
+                .with_deterministic(reader.deterministic);
+                if let Some(projection) = &reader.projection {
+                    let columns = projection
+                        .columns
+                        .iter()
+                        .map(|i| *i as usize)
+                        .collect::<Vec<_>>();
+                    exec = exec.with_projection(&columns)?;
+                }
+                if let Some(filter) = &reader.filter {
+                    let filter =
+                        parse_physical_expr(filter, registry, &exec.schema(), &extension_codec)?;
+                    exec = exec.with_filter(filter);
+                }
+                Ok(Arc::new(exec))
             }
-            Some(PlanType::ShuffleWriter(writer)) => {
-                let plan = writer.plan.unwrap().try_into_physical_plan(
+            Some(PlanType::MemoryShuffleReader(reader)) => {
+                let schema = decode_schema(reader.schema.as_ref())?;
+                let hash_part = parse_protobuf_hash_partitioning(
+                    reader.partitioning.as_ref(),
                     registry,
-                    &RuntimeEnv::default(),
-                    self,
+                    &schema,
+                    &extension_codec,
                 )?;
+                Ok(Arc::new(MemoryShuffleReaderExec::new(
+                    reader.stage_id as usize,
+                    schema,
+                    hash_part.ok_or_else(|| missing("Shuffle partitioning"))?,
+                    reader.map_partition_count as usize,
+                )))
+            }
+            Some(PlanType::ShuffleWriter(writer)) => {
+                let shuffle_mode = writer.shuffle_mode();
+                let plan = writer
+                    .plan
+                    .ok_or_else(|| missing("Shuffle writer input"))?
+                    .try_into_physical_plan(registry, &RuntimeEnv::default(), self)?;
                 let hash_part = parse_protobuf_hash_partitioning(
                     writer.partitioning.as_ref(),
                     registry,
                     plan.schema().as_ref(),
                     &extension_codec,
                 )?;
-                Ok(Arc::new(ShuffleWriterExec::new(
-                    writer.stage_id as usize,
-                    plan,
-                    hash_part.unwrap(),
-                    &writer.shuffle_dir,
-                )))
+                let stage_id = writer.stage_id as usize;
+                let partitioning = hash_part.ok_or_else(|| missing("Shuffle partitioning"))?;
+                let storage = decode_storage(writer.storage.as_ref())?;
+                Ok(Arc::new(match shuffle_mode {
+                    protobuf_shuffle_mode::Disk => {
+                        ShuffleWriterExec::new(stage_id, plan, partitioning, storage)
+                    }
+                    protobuf_shuffle_mode::Memory => {
+                        ShuffleWriterExec::new_in_memory(stage_id, plan, partitioning)
+                    }
+                    protobuf_shuffle_mode::Flight => {
+                        ShuffleWriterExec::new_flight(stage_id, plan, partitioning, storage.url())
+                    }
+                }))
+            }
+            Some(PlanType::FileScan(scan)) => {
+                let format = scan.format();
+                let conf = scan
+                    .base_conf
+                    .as_ref()
+                    .ok_or_else(|| missing("File scan configuration"))?;
+                let conf = parse_protobuf_file_scan_config(conf, registry, self)?;
+                Ok(match format {
+                    protobuf_file_scan_format::Ndjson => {
+                        let compression = file_compression(&conf);
+                        Arc::new(NdJsonExec::new(conf, compression))
+                    }
+                    protobuf_file_scan_format::Arrow => Arc::new(ArrowExec::new(conf)),
+                })
+            }
+            Some(PlanType::SinkWriter(writer)) => {
+                let sink: Arc<dyn DataSink> = match writer.sink.as_ref() {
+                    Some(Sink::Parquet(sink)) => Arc::new(ParquetSink::try_from(sink)?),
+                    Some(Sink::Csv(sink)) => Arc::new(CsvSink::try_from(sink)?),
+                    Some(Sink::Json(sink)) => Arc::new(JsonSink::try_from(sink)?),
+                    None => return Err(missing("Sink")),
+                };
+                Ok(Arc::new(SinkWriterExec::try_new(
+                    single_input(inputs)?,
+                    sink,
+                    ListingTableUrl::parse(&writer.staging_url)?,
+                )?))
             }
-            _ => unreachable!(),
+            Some(PlanType::SinkCommit(commit)) => Ok(Arc::new(SinkCommitExec::new(
+                single_input(inputs)?,
+                ListingTableUrl::parse(&commit.target_url)?,
+                ListingTableUrl::parse(&commit.staging_url)?,
+            ))),
+            None => Err(missing("Plan type")),
         }
     }
 
@@ -91,16 +187,41 @@ impl PhysicalExtensionCodec for ShuffleCodec {
         buf: &mut Vec<u8>,
     ) -> Result<(), DataFusionError> {
         let plan = if let Some(reader) = node.as_any().downcast_ref::<ShuffleReaderExec>() {
-            let schema: protobuf::Schema = reader.schema().try_into().unwrap();
-            let partitioning =
-                encode_partitioning_scheme(reader.properties().output_partitioning())?;
+            let schema = encode_schema(&reader.shuffle_schema())?;
+            let partitioning = encode_partitioning_scheme(reader.shuffle_partitioning())?;
+            let projection = reader.projection.as_ref().map(|columns| ShuffleProjection {
+                columns: columns.iter().map(|i| *i as u32).collect(),
+            });
+            let filter = match &reader.filter {
+                Some(filter) => Some(serialize_physical_expr(
+                    filter,
+                    &DefaultPhysicalExtensionCodec {},
+                )?),
+                None => None,
+            };
             let reader = ShuffleReaderExecNode {
                 stage_id: reader.stage_id as u32,
                 schema: Some(schema),
                 partitioning: Some(partitioning),
-                shuffle_dir: reader.shuffle_dir.clone(),
+                map_partition_count: reader.map_partitions as u32,
+                storage: Some(encode_storage(reader.storage.as_ref())),
+                mmap: reader.mmap,
+                projection,
+                filter,
+                deterministic: reader.deterministic,
             };
             PlanType::ShuffleReader(reader)
+        } else if let Some(reader) = node.as_any().downcast_ref::<MemoryShuffleReaderExec>() {
+            let schema = encode_schema(&reader.schema())?;
+            let partitioning =
+                encode_partitioning_scheme(reader.properties().output_partitioning())?;
+            let reader = MemoryShuffleReaderExecNode {
+                stage_id: reader.stage_id as u32,
+                schema: Some(schema),
+                partitioning: Some(partitioning),
+                map_partition_count: reader.map_partitions as u32,
+            };
+            PlanType::MemoryShuffleReader(reader)
         } else if let Some(writer) = node.as_any().downcast_ref::<ShuffleWriterExec>() {
             let plan = PhysicalPlanNode::try_from_physical_plan(writer.plan.clone(), self)?;
             let partitioning =
@@ -109,17 +230,127 @@ impl PhysicalExtensionCodec for ShuffleCodec {
                 stage_id: writer.stage_id as u32,
                 plan: Some(plan),
                 partitioning: Some(partitioning),
-                shuffle_dir: writer.shuffle_dir.clone(),
+                storage: Some(encode_storage(writer.storage.as_ref())),
+                shuffle_mode: match writer.shuffle_mode {
+                    ShuffleMode::Disk => protobuf_shuffle_mode::Disk,
+                    ShuffleMode::Memory => protobuf_shuffle_mode::Memory,
+                    ShuffleMode::Flight => protobuf_shuffle_mode::Flight,
+                } as i32,
             };
             PlanType::ShuffleWriter(writer)
+        } else if let Some(scan) = node.as_any().downcast_ref::<NdJsonExec>() {
+            PlanType::FileScan(FileScanExecNode {
+                base_conf: Some(serialize_file_scan_config(scan.base_config(), self)?),
+                format: protobuf_file_scan_format::Ndjson as i32,
+            })
+        } else if let Some(scan) = node.as_any().downcast_ref::<ArrowExec>() {
+            PlanType::FileScan(FileScanExecNode {
+                base_conf: Some(serialize_file_scan_config(scan.base_config(), self)?),
+                format: protobuf_file_scan_format::Arrow as i32,
+            })
+        } else if let Some(writer) = node.as_any().downcast_ref::<SinkWriterExec>() {
+            let sink = writer.sink().as_any();
+            let sink = if let Some(sink) = sink.downcast_ref::<ParquetSink>() {
+                Sink::Parquet(sink.try_into()?)
+            } else if let Some(sink) = sink.downcast_ref::<CsvSink>() {
+                Sink::Csv(sink.try_into()?)
+            } else if let Some(sink) = sink.downcast_ref::<JsonSink>() {
+                Sink::Json(sink.try_into()?)
+            } else {
+                return Err(RayError::Codec(format!(
+                    "Unsupported sink in the shuffle codec: {:?}",
+                    writer.sink()
+                ))
+                .into());
+            };
+            PlanType::SinkWriter(SinkWriterExecNode {
+                sink: Some(sink),
+                staging_url: writer.staging_url.to_string(),
+            })
+        } else if let Some(commit) = node.as_any().downcast_ref::<SinkCommitExec>() {
+            PlanType::SinkCommit(SinkCommitExecNode {
+                target_url: commit.target_url.to_string(),
+                staging_url: commit.staging_url.to_string(),
+            })
         } else {
-            unreachable!()
+            return Err(RayError::Codec(format!(
+                "Unsupported plan in the shuffle codec: {}",
+                node.name()
+            ))
+            .into());
         };
         plan.encode(buf);
         Ok(())
     }
 }
 
+fn single_input(inputs: &[Arc<dyn ExecutionPlan>]) -> Result<Arc<dyn ExecutionPlan>> {
+    match inputs {
+        [input] => Ok(input.clone()),
+        _ => Err(RayError::Codec(format!("Expected one input, got {}", inputs.len())).into()),
+    }
+}
+
+fn encode_storage(storage: &dyn ShuffleStorage) -> ShuffleStorageNode {
+    let backend = match storage.backend() {
+        ShuffleStorageBackend::Local => protobuf_storage_backend::Local,
+        ShuffleStorageBackend::Memory => protobuf_storage_backend::Memory,
+        ShuffleStorageBackend::ObjectStore => protobuf_storage_backend::ObjectStore,
+    };
+    ShuffleStorageNode {
+        backend: backend as i32,
+        url: storage.url().to_string(),
+    }
+}
+
+fn decode_storage(node: Option<&ShuffleStorageNode>) -> Result<Arc<dyn ShuffleStorage>> {
+    let node = node.ok_or_else(|| missing("Shuffle storage"))?;
+    let backend = match node.backend() {
+        protobuf_storage_backend::Local => ShuffleStorageBackend::Local,
+        protobuf_storage_backend::Memory => ShuffleStorageBackend::Memory,
+        protobuf_storage_backend::ObjectStore => ShuffleStorageBackend::ObjectStore,
+    };
+    create_shuffle_storage(backend, &node.url)
+}
+
+/// Compression of the files of a JSON or CSV scan, which the scan does not expose, from the
+/// extension of its files
+pub(crate) fn file_compression(conf: &FileScanConfig) -> FileCompressionType {
+    let path = conf
+        .file_groups
+        .iter()
+        .flatten()
+        .next()
+        .map(|file| file.object_meta.location.to_string())
+        .unwrap_or_default();
+    match path.rsplit('.').next() {
+        Some("gz") => FileCompressionType::GZIP,
+        Some("bz2") => FileCompressionType::BZIP2,
+        Some("xz") => FileCompressionType::XZ,
+        Some("zst") => FileCompressionType::ZSTD,
+        _ => FileCompressionType::UNCOMPRESSED,
+    }
+}
+
+fn encode_schema(schema: &SchemaRef) -> Result<protobuf::Schema> {
+    schema
+        .as_ref()
+        .try_into()
+        .map_err(|e| RayError::Codec(format!("failed to encode schema: {e}")).into())
+}
+
+fn decode_schema(schema: Option<&protobuf::Schema>) -> Result<SchemaRef> {
+    let schema = schema.ok_or_else(|| missing("Shuffle schema"))?;
+    let schema = schema
+        .try_into()
+        .map_err(|e| RayError::Codec(format!("failed to decode schema: {e}")))?;
+    Ok(Arc::new(schema))
+}
+
+fn missing(field: &str) -> DataFusionError {
+    RayError::Codec(format!("{field} is missing from the plan")).into()
+}
+
 fn encode_partitioning_scheme(partitioning: &Partitioning) -> Result<PhysicalHashRepartition> {
     match partitioning {
         Partitioning::Hash(expr, partition_count) => Ok(protobuf::PhysicalHashRepartition {
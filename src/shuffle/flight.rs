@@ -0,0 +1,491 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Arrow Flight service that serves the shuffle files written on a node to the shuffle
+//! readers of other nodes, so that query stages can exchange data without a shared filesystem.
+
+use crate::protobuf::{ShuffleMapOutput, ShuffleTicket};
+use crate::shuffle::{
+    find_shuffle_fetch_failure, read_map_output, LocalShuffleStorage, ShuffleFetchFailed,
+    ShuffleFile, ShuffleFileReader, ShuffleStorage, SHUFFLE_ROOT_DIR,
+};
+use arrow_flight::encode::FlightDataEncoderBuilder;
+use arrow_flight::error::FlightError;
+use arrow_flight::flight_service_server::{FlightService, FlightServiceServer};
+use arrow_flight::{
+    Action, ActionType, Criteria, Empty, FlightClient, FlightData, FlightDescriptor, FlightInfo,
+    HandshakeRequest, HandshakeResponse, PollInfo, PutResult, SchemaResult, Ticket,
+};
+use datafusion::arrow::array::{Array, BinaryArray};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::SendableRecordBatchStream;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use log::debug;
+use prost::Message;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use tokio::sync::{mpsc, oneshot, OnceCell};
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Endpoint, Server};
+use tonic::{Request, Response, Status, Streaming};
+
+/// The shuffle server of this process, started by the first task that needs it
+static SHUFFLE_SERVER: OnceCell<ShuffleServer> = OnceCell::const_new();
+
+/// Schema of the batch returned by a shuffle writer task in flight mode. The batch has a single
+/// row holding the encoded [`ShuffleMapOutput`] of the task, including the address of the
+/// shuffle server that serves its files.
+pub(crate) fn map_output_location_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![Field::new(
+        "map_output",
+        DataType::Binary,
+        false,
+    )]))
+}
+
+/// Encode the output of a shuffle writer task into a single batch
+pub(crate) fn encode_map_output_location(output: &ShuffleMapOutput) -> Result<RecordBatch> {
+    let data = BinaryArray::from_iter_values([output.encode_to_vec()]);
+    Ok(RecordBatch::try_new(
+        map_output_location_schema(),
+        vec![Arc::new(data)],
+    )?)
+}
+
+/// Decode the outputs of shuffle writer tasks returned by [`encode_map_output_location`]
+pub(crate) fn decode_map_output_locations(
+    batches: &[RecordBatch],
+) -> Result<Vec<ShuffleMapOutput>> {
+    let mut outputs = vec![];
+    for batch in batches {
+        let data = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<BinaryArray>()
+            .ok_or_else(|| {
+                DataFusionError::Internal("Unexpected schema for a shuffle map output".to_string())
+            })?;
+        for i in 0..data.len() {
+            let output = ShuffleMapOutput::decode(data.value(i)).map_err(|e| {
+                DataFusionError::Internal(format!("failed to decode shuffle map output: {e:?}"))
+            })?;
+            outputs.push(output);
+        }
+    }
+    Ok(outputs)
+}
+
+/// Start the shuffle server of this process on the given host, unless it is already running.
+/// A port of 0 lets the operating system pick a free port. Returns the port the server is
+/// listening on.
+pub fn start_shuffle_server(host: &str, port: u16) -> Result<u16> {
+    if let Some(server) = SHUFFLE_SERVER.get() {
+        return Ok(server.port());
+    }
+    // if another thread started a server first, the one started here is shut down again
+    let _ = SHUFFLE_SERVER.set(ShuffleServer::start(SHUFFLE_ROOT_DIR, host, port)?);
+    SHUFFLE_SERVER
+        .get()
+        .map(|server| server.port())
+        .ok_or_else(|| DataFusionError::Internal("The shuffle server is not running".to_string()))
+}
+
+/// Host and port of the shuffle server of this process, if it has been started
+pub fn shuffle_server_address() -> Option<(String, u16)> {
+    SHUFFLE_SERVER
+        .get()
+        .map(|server| (server.host().to_string(), server.port()))
+}
+
+/// An Arrow Flight server running [`ShuffleFlightService`] on a background thread. The server
+/// shuts down when this is dropped.
+pub struct ShuffleServer {
+    host: String,
+    port: u16,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ShuffleServer {
+    /// Start a server that serves the shuffle files under `root_dir`
+    pub fn start(root_dir: &str, host: &str, port: u16) -> Result<Self> {
+        let service = ShuffleFlightService::try_new(root_dir)?;
+        let listener = std::net::TcpListener::bind((host, port))?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let incoming = {
+            let _guard = rt.enter();
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            TcpIncoming::from_listener(listener, true, None).map_err(DataFusionError::External)?
+        };
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let server = Server::builder()
+                .add_service(FlightServiceServer::new(service))
+                .serve_with_incoming_shutdown(incoming, async {
+                    signal.await.ok();
+                });
+            if let Err(e) = rt.block_on(server) {
+                log::error!("Shuffle server failed: {e}");
+            }
+        });
+        debug!("Started shuffle server on {host}:{port}");
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for ShuffleServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Flight service that serves shuffle files with `DoGet`. The ticket is an encoded
+/// [`ShuffleTicket`] identifying one output partition of a shuffle writer task. Only files
+/// recorded in the committed output of a task under the root directory are served.
+#[derive(Debug)]
+pub struct ShuffleFlightService {
+    root_dir: PathBuf,
+}
+
+impl ShuffleFlightService {
+    pub fn try_new(root_dir: &str) -> Result<Self> {
+        Ok(Self {
+            root_dir: Path::new(root_dir).canonicalize()?,
+        })
+    }
+
+    /// Open the shuffle file for a ticket. The reader checks that the file is intact once it
+    /// reaches its end.
+    #[allow(clippy::result_large_err)]
+    async fn open_shuffle_file(
+        &self,
+        ticket: &ShuffleTicket,
+    ) -> std::result::Result<ShuffleFileReader<Box<dyn ShuffleFile>>, Status> {
+        let dir = Path::new(&ticket.shuffle_dir)
+            .canonicalize()
+            .map_err(|_| Status::not_found(format!("{} does not exist", ticket.shuffle_dir)))?;
+        if !dir.starts_with(&self.root_dir) {
+            return Err(Status::permission_denied(format!(
+                "{} is not a shuffle directory",
+                ticket.shuffle_dir
+            )));
+        }
+        let internal = |e: DataFusionError| Status::internal(e.to_string());
+        let storage = LocalShuffleStorage::new(&ticket.shuffle_dir);
+        let output = read_map_output(
+            &storage,
+            ticket.stage_id as usize,
+            ticket.map_partition as usize,
+        )
+        .await
+        .map_err(internal)?
+        .ok_or_else(|| {
+            Status::not_found(format!(
+                "No output for query stage {} map partition {}",
+                ticket.stage_id, ticket.map_partition
+            ))
+        })?;
+        let output = output
+            .partitions
+            .into_iter()
+            .find(|p| p.partition == ticket.partition)
+            .ok_or_else(|| {
+                Status::not_found(format!(
+                    "No output for partition {} of query stage {} map partition {}",
+                    ticket.partition, ticket.stage_id, ticket.map_partition
+                ))
+            })?;
+        if !storage.exists(&output.path).await.map_err(internal)? {
+            return Err(Status::not_found(format!("{} does not exist", output.path)));
+        }
+        debug!("Shuffle server sending {}", output.path);
+        let file = storage.open(&output.path).await.map_err(internal)?;
+        ShuffleFileReader::try_new(
+            file,
+            None,
+            ticket.stage_id as usize,
+            ticket.map_partition as usize,
+            &output,
+        )
+        .map_err(shuffle_file_status)
+    }
+}
+
+/// Status for an error reading a shuffle file, which is data loss if the file is corrupt
+fn shuffle_file_status(e: DataFusionError) -> Status {
+    if find_shuffle_fetch_failure(&e).is_some() {
+        Status::data_loss(e.to_string())
+    } else {
+        Status::internal(e.to_string())
+    }
+}
+
+#[tonic::async_trait]
+impl FlightService for ShuffleFlightService {
+    type HandshakeStream = BoxStream<'static, std::result::Result<HandshakeResponse, Status>>;
+    type ListFlightsStream = BoxStream<'static, std::result::Result<FlightInfo, Status>>;
+    type DoGetStream = BoxStream<'static, std::result::Result<FlightData, Status>>;
+    type DoPutStream = BoxStream<'static, std::result::Result<PutResult, Status>>;
+    type DoExchangeStream = BoxStream<'static, std::result::Result<FlightData, Status>>;
+    type DoActionStream = BoxStream<'static, std::result::Result<arrow_flight::Result, Status>>;
+    type ListActionsStream = BoxStream<'static, std::result::Result<ActionType, Status>>;
+
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> std::result::Result<Response<Self::DoGetStream>, Status> {
+        let ticket = ShuffleTicket::decode(request.into_inner().ticket)
+            .map_err(|e| Status::invalid_argument(format!("invalid shuffle ticket: {e:?}")))?;
+        let reader = self.open_shuffle_file(&ticket).await?;
+        let schema = reader.schema();
+        // the file is read on a blocking thread, which sends the batches as they are decoded
+        let (tx, rx) = mpsc::channel(2);
+        tokio::task::spawn_blocking(move || {
+            for batch in reader {
+                if tx.blocking_send(batch).is_err() {
+                    // the client is gone
+                    break;
+                }
+            }
+        });
+        let batches = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|batch| (batch, rx))
+        })
+        .map_err(|e| FlightError::Tonic(shuffle_file_status(e)));
+        let stream = FlightDataEncoderBuilder::new()
+            .with_schema(schema)
+            .build(batches)
+            .map_err(Status::from);
+        Ok(Response::new(stream.boxed()))
+    }
+
+    async fn handshake(
+        &self,
+        _request: Request<Streaming<HandshakeRequest>>,
+    ) -> std::result::Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented("handshake"))
+    }
+
+    async fn list_flights(
+        &self,
+        _request: Request<Criteria>,
+    ) -> std::result::Result<Response<Self::ListFlightsStream>, Status> {
+        Err(Status::unimplemented("list_flights"))
+    }
+
+    async fn get_flight_info(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> std::result::Result<Response<FlightInfo>, Status> {
+        Err(Status::unimplemented("get_flight_info"))
+    }
+
+    async fn poll_flight_info(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> std::result::Result<Response<PollInfo>, Status> {
+        Err(Status::unimplemented("poll_flight_info"))
+    }
+
+    async fn get_schema(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> std::result::Result<Response<SchemaResult>, Status> {
+        Err(Status::unimplemented("get_schema"))
+    }
+
+    async fn do_put(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> std::result::Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented("do_put"))
+    }
+
+    async fn do_exchange(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> std::result::Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented("do_exchange"))
+    }
+
+    async fn do_action(
+        &self,
+        _request: Request<Action>,
+    ) -> std::result::Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("do_action"))
+    }
+
+    async fn list_actions(
+        &self,
+        _request: Request<Empty>,
+    ) -> std::result::Result<Response<Self::ListActionsStream>, Status> {
+        Err(Status::unimplemented("list_actions"))
+    }
+}
+
+/// Fetch one output partition of a shuffle writer task from the shuffle server that serves it.
+/// Any failure to fetch the data is reported as [`ShuffleFetchFailed`] so that the scheduler
+/// recomputes the output of the task.
+pub(crate) fn fetch_partition(
+    schema: SchemaRef,
+    shuffle_dir: &str,
+    output: &ShuffleMapOutput,
+    partition: usize,
+) -> SendableRecordBatchStream {
+    let stage_id = output.stage_id as usize;
+    let map_partition = output.map_partition as usize;
+    let fetch_failed = move |e: FlightError| {
+        debug!(
+            "Failed to fetch partition {partition} of query stage {stage_id} \
+             map partition {map_partition}: {e}"
+        );
+        DataFusionError::from(ShuffleFetchFailed {
+            stage_id,
+            map_partitions: vec![map_partition],
+        })
+    };
+    let ticket = ShuffleTicket {
+        shuffle_dir: shuffle_dir.to_string(),
+        stage_id: output.stage_id,
+        map_partition: output.map_partition,
+        partition: partition as u32,
+    };
+    let url = format!("http://{}:{}", output.host, output.port);
+    let stream = async move {
+        let channel = Endpoint::from_shared(url)
+            .map_err(|e| FlightError::ExternalError(Box::new(e)))?
+            .connect()
+            .await
+            .map_err(|e| FlightError::ExternalError(Box::new(e)))?;
+        let mut client = FlightClient::new(channel);
+        client.do_get(Ticket::new(ticket.encode_to_vec())).await
+    };
+    let stream = futures::stream::once(stream)
+        .try_flatten()
+        .map_err(fetch_failed);
+    Box::pin(RecordBatchStreamAdapter::new(schema, stream))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shuffle::{find_shuffle_fetch_failure, ShuffleReaderExec, ShuffleWriterExec};
+    use crate::test_utils::{TempDir, TestResult};
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::{ExecutionPlan, Partitioning};
+    use datafusion::prelude::SessionContext;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn fetch_from_several_servers() -> TestResult<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let partitions = (0..3)
+            .map(|p| {
+                let batch = RecordBatch::try_new(
+                    schema.clone(),
+                    vec![Arc::new(Int64Array::from(vec![p, p + 10]))],
+                )?;
+                Ok(vec![batch])
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let input = Arc::new(MemoryExec::try_new(&partitions, schema.clone(), None)?);
+        let partitioning = Partitioning::UnknownPartitioning(1);
+
+        // all nodes write to the same shuffle directory path, and serve the files they wrote
+        let root = std::env::temp_dir().to_string_lossy().to_string();
+        let dir = TempDir::new()?;
+        let nodes = [
+            (ShuffleServer::start(&root, "127.0.0.1", 0)?, vec![0, 1]),
+            (ShuffleServer::start(&root, "127.0.0.1", 0)?, vec![2]),
+        ];
+        let rt = Runtime::new()?;
+        let ctx = SessionContext::new().task_ctx();
+        let storage = Arc::new(LocalShuffleStorage::new(dir.path_str()));
+        let writer = ShuffleWriterExec::new(0, input, partitioning.clone(), storage.clone());
+        let mut outputs = vec![];
+        for (server, map_partitions) in &nodes {
+            for &map_partition in map_partitions {
+                rt.block_on(collect(writer.execute(map_partition, ctx.clone())?))?;
+                let mut output = rt
+                    .block_on(read_map_output(storage.as_ref(), 0, map_partition))?
+                    .unwrap();
+                output.host = server.host().to_string();
+                output.port = server.port() as u32;
+                outputs.push(encode_map_output_location(&output)?);
+            }
+        }
+
+        // the reader fetches every map output from the server of the node that wrote it
+        let reader = ShuffleReaderExec::new(0, schema, partitioning, storage, 3)
+            .with_map_outputs(&outputs)?;
+        let batches = rt.block_on(collect(reader.execute(0, ctx.clone())?))?;
+        let mut values = batches
+            .iter()
+            .flat_map(|b| {
+                let a = b.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+                a.values().to_vec()
+            })
+            .collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(vec![0, 1, 2, 10, 11, 12], values);
+
+        // losing a node loses the output of the map tasks that ran on it
+        let [first, second] = nodes;
+        drop(second);
+        let Err(err) = rt.block_on(collect(reader.execute(0, ctx)?)) else {
+            panic!("expected the shuffle read to fail");
+        };
+        assert_eq!(
+            ShuffleFetchFailed {
+                stage_id: 0,
+                map_partitions: vec![2],
+            },
+            find_shuffle_fetch_failure(&err).expect("shuffle fetch failure")
+        );
+        drop(first);
+        Ok(())
+    }
+}
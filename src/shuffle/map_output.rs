@@ -0,0 +1,372 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use crate::protobuf::{ShuffleMapOutput, ShufflePartitionOutput};
+use crate::shuffle::ShuffleStorage;
+use datafusion::arrow::datatypes::{Schema, SchemaRef};
+use datafusion::arrow::ipc::reader::StreamReader;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use prost::Message;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufReader, Chain, Cursor, Read, Write};
+use std::sync::Arc;
+
+/// Path of the metadata file that marks the output of a shuffle writer task as committed
+pub fn map_output_path(stage_id: usize, map_partition: usize) -> String {
+    format!("shuffle_{stage_id}_{map_partition}.meta")
+}
+
+/// Commit the output of one attempt of a shuffle writer task. The metadata is only stored if
+/// no other attempt of the same task has committed already. Returns false if the output of the
+/// other attempt was kept, in which case the files written by this attempt are removed.
+pub async fn commit_map_output(
+    storage: &dyn ShuffleStorage,
+    output: &ShuffleMapOutput,
+) -> Result<bool> {
+    let path = map_output_path(output.stage_id as usize, output.map_partition as usize);
+    let data = output.encode_to_vec();
+    if storage.put_if_absent(&path, data.clone().into()).await? {
+        return Ok(true);
+    }
+    let existing = read_map_output(
+        storage,
+        output.stage_id as usize,
+        output.map_partition as usize,
+    )
+    .await?;
+    let complete = match &existing {
+        Some(existing) => is_complete(storage, existing).await?,
+        None => false,
+    };
+    if complete {
+        for partition in &output.partitions {
+            storage.delete(&partition.path).await?;
+        }
+        Ok(false)
+    } else {
+        // the committed output has been lost, so this attempt is recomputing it
+        storage.put(&path, data.into()).await?;
+        Ok(true)
+    }
+}
+
+/// Returns true if all the files of a shuffle writer task still exist
+async fn is_complete(storage: &dyn ShuffleStorage, output: &ShuffleMapOutput) -> Result<bool> {
+    for partition in &output.partitions {
+        if !storage.exists(&partition.path).await? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Magic bytes at the start of an Arrow IPC file, padded to 8 bytes
+const ARROW_FILE_MAGIC: &[u8; 8] = b"ARROW1\0\0";
+
+/// Largest alignment of the buffers of an Arrow IPC file, which the magic bytes are padded to
+const MAX_IPC_ALIGNMENT: usize = 64;
+
+/// Marker that precedes the length of each message of an Arrow IPC stream
+const CONTINUATION_MARKER: [u8; 4] = [0xff; 4];
+
+/// Computes the CRC32 checksum of the bytes written through it
+pub struct ChecksumWriter<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    /// Checksum of the bytes written so far
+    pub fn checksum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Computes the CRC32 checksum of the bytes read through it
+pub struct ChecksumReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    /// Read the rest of the data and return the checksum of all of it
+    pub fn finish(&mut self) -> io::Result<u32> {
+        io::copy(self, &mut io::sink())?;
+        Ok(self.hasher.clone().finalize())
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+type ShuffleFileStream<R> = StreamReader<Chain<Cursor<Vec<u8>>, BufReader<ChecksumReader<R>>>>;
+
+/// Reads the batches of a shuffle file in a single pass, checking once the end of the file is
+/// reached that it matches the checksum recorded by the map task that wrote it. The messages
+/// of an Arrow IPC file are read in order, as a stream, instead of through the footer.
+pub struct ShuffleFileReader<R: Read> {
+    /// Unset once the checksum has been verified
+    reader: Option<ShuffleFileStream<R>>,
+    schema: SchemaRef,
+    stage_id: usize,
+    map_partition: usize,
+    path: String,
+    expected: u32,
+}
+
+impl<R: Read> ShuffleFileReader<R> {
+    /// Start reading the file written by `map_partition` of `stage_id` for `output`, decoding
+    /// only the columns in `projection` if set
+    pub fn try_new(
+        file: R,
+        projection: Option<Vec<usize>>,
+        stage_id: usize,
+        map_partition: usize,
+        output: &ShufflePartitionOutput,
+    ) -> Result<Self> {
+        let mut file = BufReader::new(ChecksumReader::new(file));
+        // the schema message is read and decoded separately so that a file whose header is
+        // corrupt can still be read to the end and reported as corrupt
+        let header = read_schema_message(&mut file).and_then(|header| {
+            let schema = StreamReader::try_new(header.as_slice(), None)?.schema();
+            Ok((header, schema))
+        });
+        let mut reader = Self {
+            reader: None,
+            schema: Arc::new(Schema::empty()),
+            stage_id,
+            map_partition,
+            path: output.path.clone(),
+            expected: output.checksum,
+        };
+        match header {
+            Ok((header, schema)) => {
+                reader.schema = schema;
+                reader.reader = Some(StreamReader::try_new(
+                    Cursor::new(header).chain(file),
+                    projection,
+                )?);
+                Ok(reader)
+            }
+            Err(e) => {
+                reader.verify(file.get_mut())?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Schema of the file, which the projection applies to
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    /// Read the rest of the file, failing if it does not match its checksum
+    fn verify(&self, file: &mut ChecksumReader<R>) -> Result<()> {
+        let actual = file.finish()?;
+        if actual != self.expected {
+            return Err(ShuffleFileCorrupted {
+                stage_id: self.stage_id,
+                map_partition: self.map_partition,
+                path: self.path.clone(),
+                expected: self.expected,
+                actual,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self.reader.take() {
+            Some(mut reader) => self.verify(reader.get_mut().get_mut().1.get_mut()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R: Read> Iterator for ShuffleFileReader<R> {
+    type Item = Result<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.as_mut()?.next() {
+            Some(Ok(batch)) => Some(Ok(batch)),
+            // a corrupt file may fail to decode before its end is reached
+            Some(Err(e)) => Some(self.finish().and(Err(e.into()))),
+            None => self.finish().err().map(Err),
+        }
+    }
+}
+
+/// Read the magic bytes at the start of an Arrow IPC file and the schema message that follows
+/// them, returning the message. Only the bytes that the file holds are allocated, whatever
+/// length the message claims to have.
+fn read_schema_message(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let not_ipc_file =
+        || DataFusionError::Execution("Shuffle file is not an Arrow IPC file".into());
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != ARROW_FILE_MAGIC {
+        return Err(not_ipc_file());
+    }
+    // the padding of the magic bytes to the alignment of the file is followed by the message
+    let mut marker = [0; 4];
+    let mut offset = magic.len();
+    while marker != CONTINUATION_MARKER {
+        reader.read_exact(&mut marker)?;
+        offset += marker.len();
+        if marker != CONTINUATION_MARKER && (marker != [0; 4] || offset > MAX_IPC_ALIGNMENT) {
+            return Err(not_ipc_file());
+        }
+    }
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut message = [marker, len].concat();
+    let len = u32::from_le_bytes(len) as u64;
+    reader.take(len).read_to_end(&mut message)?;
+    if message.len() as u64 != 8 + len {
+        return Err(DataFusionError::Execution(
+            "Shuffle file ends within its schema".to_string(),
+        ));
+    }
+    Ok(message)
+}
+
+/// Read the metadata of a shuffle writer task, returning `None` if it does not exist
+pub async fn read_map_output(
+    storage: &dyn ShuffleStorage,
+    stage_id: usize,
+    map_partition: usize,
+) -> Result<Option<ShuffleMapOutput>> {
+    let path = map_output_path(stage_id, map_partition);
+    let Some(bytes) = storage.get(&path).await? else {
+        return Ok(None);
+    };
+    let output = ShuffleMapOutput::decode(bytes).map_err(|e| {
+        DataFusionError::Internal(format!("failed to decode shuffle metadata {path}: {e:?}"))
+    })?;
+    Ok(Some(output))
+}
+
+/// Raised by a shuffle reader when the output of some map tasks is missing, for example
+/// because the node that wrote them was lost. The scheduler recomputes these map tasks
+/// before running the reader again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShuffleFetchFailed {
+    /// Stage that wrote the missing shuffle output
+    pub stage_id: usize,
+    /// Map tasks of that stage whose output is missing
+    pub map_partitions: Vec<usize>,
+}
+
+impl fmt::Display for ShuffleFetchFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Shuffle output of query stage {} is missing for map partitions {:?}",
+            self.stage_id, self.map_partitions
+        )
+    }
+}
+
+impl Error for ShuffleFetchFailed {}
+
+/// Raised by a shuffle reader when the checksum of a shuffle file does not match the checksum
+/// recorded by the map task that wrote it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShuffleFileCorrupted {
+    pub stage_id: usize,
+    pub map_partition: usize,
+    pub path: String,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl fmt::Display for ShuffleFileCorrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Shuffle file {} written by query stage {} map partition {} is corrupt: \
+             expected checksum {:08x} but found {:08x}",
+            self.path, self.stage_id, self.map_partition, self.expected, self.actual
+        )
+    }
+}
+
+impl Error for ShuffleFileCorrupted {}
+
+impl From<ShuffleFileCorrupted> for DataFusionError {
+    fn from(e: ShuffleFileCorrupted) -> Self {
+        DataFusionError::External(Box::new(e))
+    }
+}
+
+impl From<ShuffleFetchFailed> for DataFusionError {
+    fn from(e: ShuffleFetchFailed) -> Self {
+        DataFusionError::External(Box::new(e))
+    }
+}
+
+/// Find the shuffle output that a task could not read in the chain of causes of an error. A
+/// corrupt shuffle file is reported as lost output of the map task that wrote it.
+pub fn find_shuffle_fetch_failure(e: &DataFusionError) -> Option<ShuffleFetchFailed> {
+    let mut error: &dyn Error = e;
+    loop {
+        if let Some(failure) = error.downcast_ref::<ShuffleFetchFailed>() {
+            return Some(failure.clone());
+        }
+        if let Some(corrupted) = error.downcast_ref::<ShuffleFileCorrupted>() {
+            return Some(ShuffleFetchFailed {
+                stage_id: corrupted.stage_id,
+                map_partitions: vec![corrupted.map_partition],
+            });
+        }
+        error = error.source()?;
+    }
+}
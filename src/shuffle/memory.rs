@@ -0,0 +1,276 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use crate::shuffle::coalesce::coalesce_stream;
+use crate::shuffle::{shuffle_partitioning, ShuffleReaderExec};
+use datafusion::arrow::array::{Array, BinaryArray, UInt32Array, UInt64Array};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::ipc::reader::StreamReader;
+use datafusion::arrow::ipc::writer::StreamWriter;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::common::tree_node::{Transformed, TreeNode};
+use datafusion::common::Statistics;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_expr::EquivalenceProperties;
+use datafusion::physical_plan::memory::MemoryStream;
+use datafusion::physical_plan::{
+    DisplayAs, DisplayFormatType, ExecutionPlan, Partitioning, PlanProperties,
+    SendableRecordBatchStream,
+};
+use log::debug;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::Formatter;
+use std::io::Cursor;
+use std::sync::Arc;
+
+/// Schema of the batch returned by a shuffle writer task in memory mode. Each row holds the
+/// Arrow IPC stream of one output partition and its number of rows.
+pub(crate) fn map_output_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("partition", DataType::UInt32, false),
+        Field::new("data", DataType::Binary, false),
+        Field::new("num_rows", DataType::UInt64, false),
+    ]))
+}
+
+/// Encode the output partitions of a shuffle writer task into a single batch
+pub(crate) fn encode_map_output(
+    schema: &SchemaRef,
+    partitions: Vec<Vec<RecordBatch>>,
+) -> Result<RecordBatch> {
+    let mut ids = vec![];
+    let mut data = vec![];
+    let mut num_rows = vec![];
+    for (partition, batches) in partitions.into_iter().enumerate() {
+        if batches.is_empty() {
+            continue;
+        }
+        let mut writer = StreamWriter::try_new(vec![], schema)?;
+        for batch in &batches {
+            writer.write(batch)?;
+        }
+        writer.finish()?;
+        ids.push(partition as u32);
+        data.push(writer.into_inner()?);
+        num_rows.push(batches.iter().map(|b| b.num_rows() as u64).sum::<u64>());
+    }
+    let data = BinaryArray::from_iter_values(data);
+    Ok(RecordBatch::try_new(
+        map_output_schema(),
+        vec![
+            Arc::new(UInt32Array::from(ids)),
+            Arc::new(data),
+            Arc::new(UInt64Array::from(num_rows)),
+        ],
+    )?)
+}
+
+/// Reads the shuffle output of a query stage that ran in memory mode. The outputs of the map
+/// tasks are not part of the serialized plan and are set with [`with_shuffle_inputs`] before
+/// the plan is executed.
+#[derive(Debug)]
+pub struct MemoryShuffleReaderExec {
+    /// Query stage to read from
+    pub stage_id: usize,
+    /// The output schema of the query stage being read from
+    schema: SchemaRef,
+    properties: PlanProperties,
+    /// Number of map tasks in the query stage being read from
+    pub map_partitions: usize,
+    /// One batch per map task, in map partition order
+    inputs: Option<Vec<RecordBatch>>,
+}
+
+impl MemoryShuffleReaderExec {
+    pub fn new(
+        stage_id: usize,
+        schema: SchemaRef,
+        partitioning: Partitioning,
+        map_partitions: usize,
+    ) -> Self {
+        let properties = PlanProperties::new(
+            EquivalenceProperties::new(schema.clone()),
+            shuffle_partitioning(partitioning),
+            datafusion::physical_plan::ExecutionMode::Unbounded,
+        );
+        Self {
+            stage_id,
+            schema,
+            properties,
+            map_partitions,
+            inputs: None,
+        }
+    }
+
+    /// Create a copy of this reader that reads from the given map task outputs
+    pub fn with_inputs(&self, inputs: Vec<RecordBatch>) -> Result<Self> {
+        if inputs.len() != self.map_partitions {
+            return Err(DataFusionError::Execution(format!(
+                "Expected output of {} map tasks of query stage {} but got {}",
+                self.map_partitions,
+                self.stage_id,
+                inputs.len()
+            )));
+        }
+        let expected = map_output_schema();
+        if inputs.iter().any(|batch| batch.schema() != expected) {
+            return Err(unexpected_schema(self.stage_id));
+        }
+        Ok(Self {
+            stage_id: self.stage_id,
+            schema: self.schema.clone(),
+            properties: self.properties.clone(),
+            map_partitions: self.map_partitions,
+            inputs: Some(inputs),
+        })
+    }
+
+    fn read_partition(&self, inputs: &[RecordBatch], partition: usize) -> Result<Vec<RecordBatch>> {
+        let mut batches = vec![];
+        for input in inputs {
+            let ids = input
+                .column(0)
+                .as_any()
+                .downcast_ref::<UInt32Array>()
+                .ok_or_else(|| unexpected_schema(self.stage_id))?;
+            let data = input
+                .column(1)
+                .as_any()
+                .downcast_ref::<BinaryArray>()
+                .ok_or_else(|| unexpected_schema(self.stage_id))?;
+            for i in 0..input.num_rows() {
+                if ids.value(i) as usize != partition {
+                    continue;
+                }
+                let reader = StreamReader::try_new(Cursor::new(data.value(i)), None)?;
+                if reader.schema() != self.schema {
+                    return Err(DataFusionError::Internal(
+                        "Not all shuffle outputs have the same schema".to_string(),
+                    ));
+                }
+                for batch in reader {
+                    batches.push(batch?);
+                }
+            }
+        }
+        Ok(batches)
+    }
+}
+
+impl ExecutionPlan for MemoryShuffleReaderExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(self)
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let Some(inputs) = &self.inputs else {
+            return Err(DataFusionError::Execution(format!(
+                "No shuffle inputs were provided for query stage {}",
+                self.stage_id
+            )));
+        };
+        debug!(
+            "MemoryShuffleReaderExec partition {} reading from stage {}",
+            partition, self.stage_id
+        );
+        let batches = self.read_partition(inputs, partition)?;
+        let stream = MemoryStream::try_new(batches, self.schema.clone(), None)?;
+        // each map task writes its last batch of a partition before it reaches the batch size
+        Ok(coalesce_stream(
+            Box::pin(stream),
+            context.session_config().batch_size(),
+        ))
+    }
+
+    fn statistics(&self) -> Result<Statistics> {
+        Ok(Statistics::new_unknown(&self.schema))
+    }
+
+    fn name(&self) -> &str {
+        "memory shuffle reader"
+    }
+
+    fn properties(&self) -> &PlanProperties {
+        &self.properties
+    }
+}
+
+impl DisplayAs for MemoryShuffleReaderExec {
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "MemoryShuffleReaderExec(stage_id={}, input_partitioning={:?})",
+            self.stage_id,
+            self.properties().partitioning
+        )
+    }
+}
+
+fn unexpected_schema(stage_id: usize) -> DataFusionError {
+    DataFusionError::Internal(format!(
+        "Unexpected schema for the shuffle output of query stage {stage_id}"
+    ))
+}
+
+/// Set the map task outputs read by each shuffle reader in a plan, for the shuffle modes that
+/// pass the results of the map tasks to the readers. `inputs` holds the outputs of the map
+/// tasks by query stage. In flight mode these are the locations of the shuffle files.
+pub fn with_shuffle_inputs(
+    plan: Arc<dyn ExecutionPlan>,
+    inputs: &HashMap<usize, Vec<RecordBatch>>,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let stage_inputs = |stage_id: usize| {
+        inputs.get(&stage_id).ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "No shuffle inputs were provided for query stage {stage_id}"
+            ))
+        })
+    };
+    plan.transform_up(|node| {
+        if let Some(reader) = node.as_any().downcast_ref::<MemoryShuffleReaderExec>() {
+            let reader = reader.with_inputs(stage_inputs(reader.stage_id)?.clone())?;
+            Ok(Transformed::yes(Arc::new(reader) as Arc<dyn ExecutionPlan>))
+        } else if let Some(reader) = node.as_any().downcast_ref::<ShuffleReaderExec>() {
+            let reader = reader.with_map_outputs(stage_inputs(reader.stage_id)?)?;
+            Ok(Transformed::yes(Arc::new(reader) as Arc<dyn ExecutionPlan>))
+        } else {
+            Ok(Transformed::no(node))
+        }
+    })
+    .map(|transformed| transformed.data)
+}
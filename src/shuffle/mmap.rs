@@ -0,0 +1,192 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use datafusion::arrow::buffer::Buffer;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::ipc::convert::fb_to_schema;
+use datafusion::arrow::ipc::reader::{read_footer_length, FileDecoder};
+use datafusion::arrow::ipc::{root_as_footer, Block};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::RecordBatchStream;
+use futures::Stream;
+use memmap2::Mmap;
+use std::fs::File;
+use std::pin::Pin;
+use std::ptr::NonNull;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Length of the footer length and magic bytes at the end of an Arrow IPC file
+const TRAILER_LEN: usize = 10;
+
+/// Reads a shuffle file by memory-mapping it. The arrays of the batches point into the mapping
+/// instead of being copied into newly allocated memory, which is possible because shuffle files
+/// are written uncompressed with aligned buffers. Unaligned buffers are copied.
+pub struct MmapShuffleStream {
+    /// Schema of the file
+    file_schema: SchemaRef,
+    /// Schema of the batches, which the projection applies to
+    schema: SchemaRef,
+    decoder: FileDecoder,
+    /// The whole file, which stays mapped as long as any array points into it
+    buffer: Buffer,
+    blocks: Vec<Block>,
+    next_block: usize,
+}
+
+impl MmapShuffleStream {
+    /// Map a shuffle file on the local filesystem, decoding only the columns in `projection`
+    /// if set. Returns the stream and the CRC32 checksum of the file.
+    pub fn try_new(path: &str, projection: Option<Vec<usize>>) -> Result<(Self, u32)> {
+        let file = File::open(path)?;
+        // SAFETY: shuffle files are renamed into place once complete and never modified
+        let mmap = unsafe { Mmap::map(&file)? };
+        let checksum = crc32fast::hash(&mmap);
+        if mmap.len() < TRAILER_LEN {
+            return Err(invalid_file(path));
+        }
+        let len = mmap.len();
+        let ptr = NonNull::new(mmap.as_ptr() as *mut u8).ok_or_else(|| invalid_file(path))?;
+        // SAFETY: the pointer and length describe the mapping, which the buffer keeps alive
+        let buffer = unsafe { Buffer::from_custom_allocation(ptr, len, Arc::new(mmap)) };
+
+        let trailer: [u8; TRAILER_LEN] = buffer[len - TRAILER_LEN..].try_into().unwrap();
+        let footer_len = read_footer_length(trailer)?;
+        let footer_start = (len - TRAILER_LEN)
+            .checked_sub(footer_len)
+            .ok_or_else(|| invalid_file(path))?;
+        let footer = root_as_footer(&buffer[footer_start..len - TRAILER_LEN])
+            .map_err(|e| DataFusionError::Execution(format!("{}: {e}", invalid_file(path))))?;
+        let fb_schema = footer.schema().ok_or_else(|| invalid_file(path))?;
+        let file_schema = Arc::new(fb_to_schema(fb_schema));
+
+        let mut decoder = FileDecoder::new(file_schema.clone(), footer.version());
+        let schema = match projection {
+            Some(projection) => {
+                let schema = Arc::new(file_schema.project(&projection)?);
+                decoder = decoder.with_projection(projection);
+                schema
+            }
+            None => file_schema.clone(),
+        };
+        for block in footer.dictionaries().iter().flatten() {
+            decoder.read_dictionary(block, &block_buffer(&buffer, block))?;
+        }
+        let blocks = footer
+            .recordBatches()
+            .map(|blocks| blocks.iter().copied().collect())
+            .unwrap_or_default();
+        let stream = Self {
+            file_schema,
+            schema,
+            decoder,
+            buffer,
+            blocks,
+            next_block: 0,
+        };
+        Ok((stream, checksum))
+    }
+
+    pub fn file_schema(&self) -> SchemaRef {
+        self.file_schema.clone()
+    }
+}
+
+/// The bytes of a message of the file, without copying
+fn block_buffer(buffer: &Buffer, block: &Block) -> Buffer {
+    let len = block.metaDataLength() as usize + block.bodyLength() as usize;
+    buffer.slice_with_length(block.offset() as usize, len)
+}
+
+fn invalid_file(path: &str) -> DataFusionError {
+    DataFusionError::Execution(format!("Shuffle file {path} is not a valid Arrow IPC file"))
+}
+
+impl Stream for MmapShuffleStream {
+    type Item = Result<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while self.next_block < self.blocks.len() {
+            let block = self.blocks[self.next_block];
+            self.next_block += 1;
+            let data = block_buffer(&self.buffer, &block);
+            match self.decoder.read_record_batch(&block, &data) {
+                Ok(Some(batch)) => return Poll::Ready(Some(Ok(batch))),
+                Ok(None) => continue,
+                Err(e) => return Poll::Ready(Some(Err(e.into()))),
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl RecordBatchStream for MmapShuffleStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{TempDir, TestResult};
+    use datafusion::arrow::array::{Array, Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::ipc::writer::FileWriter;
+    use datafusion::physical_plan::common::collect;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn read_batches_from_mapping() -> TestResult<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, false),
+            Field::new("b", DataType::Utf8, true),
+        ]));
+        let batches = (0..3)
+            .map(|i| {
+                RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(Int64Array::from(vec![i, i + 1, i + 2])),
+                        Arc::new(StringArray::from(vec![Some("x"), None, Some("yz")])),
+                    ],
+                )
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let dir = TempDir::new()?;
+        let path = dir.path().join("data.arrow");
+        let mut writer = FileWriter::try_new(File::create(&path)?, &schema)?;
+        for batch in &batches {
+            writer.write(batch)?;
+        }
+        writer.finish()?;
+        let path = path.to_string_lossy().to_string();
+
+        let (stream, actual) = MmapShuffleStream::try_new(&path, None)?;
+        assert_eq!(crc32fast::hash(&std::fs::read(&path)?), actual);
+        assert_eq!(schema, stream.schema());
+        let mapping = stream.buffer.as_ptr_range();
+        let read = Runtime::new()?.block_on(collect(Box::pin(stream)))?;
+        assert_eq!(batches, read);
+
+        // the arrays point into the mapping rather than into copies
+        let values = read[1].column(0).to_data().buffers()[0].as_ptr();
+        assert!(mapping.contains(&values));
+        Ok(())
+    }
+}
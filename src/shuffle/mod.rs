@@ -19,19 +19,104 @@ use arrow::record_batch::RecordBatch;
 use datafusion::arrow;
 use datafusion::arrow::datatypes::SchemaRef;
 use datafusion::common::Result;
-use datafusion::physical_plan::{RecordBatchStream, SendableRecordBatchStream};
+use datafusion::error::DataFusionError;
+use datafusion::physical_expr::expressions::UnKnownColumn;
+use datafusion::physical_plan::{Partitioning, RecordBatchStream, SendableRecordBatchStream};
 use futures::Stream;
+use std::fmt;
 use std::pin::Pin;
+use std::str::FromStr;
 use std::task::{Context, Poll};
 use tokio::macros::support::thread_rng_n;
 
+mod coalesce;
 mod codec;
+mod flight;
+mod map_output;
+mod memory;
+mod mmap;
+mod pushdown;
 mod reader;
+mod storage;
 mod writer;
 
+pub(crate) use codec::file_compression;
 pub use codec::ShuffleCodec;
+pub use flight::{
+    shuffle_server_address, start_shuffle_server, ShuffleFlightService, ShuffleServer,
+};
+pub use map_output::{
+    commit_map_output, find_shuffle_fetch_failure, map_output_path, read_map_output,
+    ChecksumReader, ChecksumWriter, ShuffleFetchFailed, ShuffleFileCorrupted, ShuffleFileReader,
+};
+pub use memory::{with_shuffle_inputs, MemoryShuffleReaderExec};
+pub use pushdown::ShuffleReaderPushdown;
 pub use reader::ShuffleReaderExec;
-pub use writer::ShuffleWriterExec;
+pub use storage::{
+    create_shuffle_storage, parse_shuffle_storage, LocalShuffleStorage, ObjectStoreShuffleStorage,
+    ShuffleFile, ShuffleStorage, ShuffleStorageBackend,
+};
+pub use writer::{map_task_rows, ShuffleWriterExec};
+
+/// Directory under which the planner creates the shuffle directories of query stages when no
+/// shuffle storage is configured, and which the shuffle server of each process serves files from
+pub const SHUFFLE_ROOT_DIR: &str = "/tmp";
+
+/// How query stages exchange data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShuffleMode {
+    /// Shuffle writers write Arrow IPC files to a shuffle storage that all readers can access
+    #[default]
+    Disk,
+    /// Shuffle writers return the Arrow IPC bytes of each output partition as task results,
+    /// which are passed to the reading tasks, for example through the Ray object store
+    Memory,
+    /// Shuffle writers write Arrow IPC files to a directory on their own node, and readers
+    /// fetch them from the Arrow Flight shuffle server of that node
+    Flight,
+}
+
+impl fmt::Display for ShuffleMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disk => write!(f, "disk"),
+            Self::Memory => write!(f, "memory"),
+            Self::Flight => write!(f, "flight"),
+        }
+    }
+}
+
+impl FromStr for ShuffleMode {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "disk" => Ok(Self::Disk),
+            "memory" => Ok(Self::Memory),
+            "flight" => Ok(Self::Flight),
+            other => Err(DataFusionError::Configuration(format!(
+                "Unknown shuffle mode '{other}', expected 'disk', 'memory' or 'flight'"
+            ))),
+        }
+    }
+}
+
+/// Normalize the partitioning of a shuffle exchange
+pub(crate) fn shuffle_partitioning(partitioning: Partitioning) -> Partitioning {
+    match partitioning {
+        Partitioning::Hash(expr, n) if expr.is_empty() => Partitioning::UnknownPartitioning(n),
+        Partitioning::Hash(expr, n) => {
+            // workaround for DataFusion bug https://github.com/apache/arrow-datafusion/issues/5184
+            Partitioning::Hash(
+                expr.into_iter()
+                    .filter(|e| e.as_any().downcast_ref::<UnKnownColumn>().is_none())
+                    .collect(),
+                n,
+            )
+        }
+        _ => partitioning,
+    }
+}
 
 /// CombinedRecordBatchStream can be used to combine a Vec of SendableRecordBatchStreams into one
 pub struct CombinedRecordBatchStream {
@@ -39,12 +124,28 @@ pub struct CombinedRecordBatchStream {
     schema: SchemaRef,
     /// Stream entries
     entries: Vec<SendableRecordBatchStream>,
+    /// Whether the entries are read one after the other, in order
+    deterministic: bool,
 }
 
 impl CombinedRecordBatchStream {
     /// Create an CombinedRecordBatchStream
     pub fn new(schema: SchemaRef, entries: Vec<SendableRecordBatchStream>) -> Self {
-        Self { schema, entries }
+        Self {
+            schema,
+            entries,
+            deterministic: false,
+        }
+    }
+
+    /// Create an CombinedRecordBatchStream that reads each entry to the end before the next
+    /// one, so that batches are returned in the same order on every run
+    pub fn new_deterministic(schema: SchemaRef, entries: Vec<SendableRecordBatchStream>) -> Self {
+        Self {
+            schema,
+            entries,
+            deterministic: true,
+        }
     }
 }
 
@@ -60,6 +161,18 @@ impl Stream for CombinedRecordBatchStream {
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         use Poll::*;
 
+        if self.deterministic {
+            while let Some(stream) = self.entries.first_mut() {
+                match Pin::new(stream).poll_next(cx) {
+                    Ready(None) => {
+                        self.entries.remove(0);
+                    }
+                    other => return other,
+                }
+            }
+            return Ready(None);
+        }
+
         let start = thread_rng_n(self.entries.len() as u32) as usize;
         let mut idx = start;
 
@@ -0,0 +1,277 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use crate::shuffle::reader::project_expr;
+use crate::shuffle::ShuffleReaderExec;
+use datafusion::common::config::ConfigOptions;
+use datafusion::common::tree_node::{Transformed, TreeNode};
+use datafusion::error::Result;
+use datafusion::physical_expr::expressions::Column;
+use datafusion::physical_expr::utils::collect_columns;
+use datafusion::physical_expr::PhysicalExpr;
+use datafusion::physical_optimizer::PhysicalOptimizerRule;
+use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
+use datafusion::physical_plan::filter::FilterExec;
+use datafusion::physical_plan::projection::ProjectionExec;
+use datafusion::physical_plan::ExecutionPlan;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+/// Physical optimizer rule that pushes projections and filters into the
+/// [`ShuffleReaderExec`] below them, so that readers only decode the columns that are used and
+/// drop rows as they are read. Projections and filters are also pushed through a
+/// [`CoalesceBatchesExec`] directly above a reader.
+#[derive(Debug, Default)]
+pub struct ShuffleReaderPushdown {}
+
+impl ShuffleReaderPushdown {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl PhysicalOptimizerRule for ShuffleReaderPushdown {
+    fn optimize(
+        &self,
+        plan: Arc<dyn ExecutionPlan>,
+        _config: &ConfigOptions,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        plan.transform_up(|node| {
+            let pushed = if let Some(filter) = node.as_any().downcast_ref::<FilterExec>() {
+                with_reader(filter.input(), |reader| push_filter(filter, reader))?
+            } else if let Some(projection) = node.as_any().downcast_ref::<ProjectionExec>() {
+                with_reader(projection.input(), |reader| {
+                    push_projection(projection.expr(), reader)
+                })?
+            } else {
+                None
+            };
+            Ok(match pushed {
+                Some(plan) => Transformed::yes(plan),
+                None => Transformed::no(node),
+            })
+        })
+        .map(|transformed| transformed.data)
+    }
+
+    fn name(&self) -> &str {
+        "shuffle_reader_pushdown"
+    }
+
+    fn schema_check(&self) -> bool {
+        true
+    }
+}
+
+/// Apply `push` to the shuffle reader that is `input`, or the input of a `CoalesceBatchesExec`
+/// that is `input`, keeping the `CoalesceBatchesExec` above the result. Returns the plan that
+/// replaces the parent of `input`, if anything was pushed.
+fn with_reader(
+    input: &Arc<dyn ExecutionPlan>,
+    push: impl FnOnce(&ShuffleReaderExec) -> Result<Option<Arc<dyn ExecutionPlan>>>,
+) -> Result<Option<Arc<dyn ExecutionPlan>>> {
+    if let Some(reader) = input.as_any().downcast_ref::<ShuffleReaderExec>() {
+        return push(reader);
+    } else if let Some(coalesce) = input.as_any().downcast_ref::<CoalesceBatchesExec>() {
+        if let Some(reader) = coalesce
+            .input()
+            .as_any()
+            .downcast_ref::<ShuffleReaderExec>()
+        {
+            if let Some(plan) = push(reader)? {
+                return Ok(Some(input.clone().with_new_children(vec![plan])?));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Move the predicate of a filter into the reader, keeping the projection of the filter
+fn push_filter(
+    filter: &FilterExec,
+    reader: &ShuffleReaderExec,
+) -> Result<Option<Arc<dyn ExecutionPlan>>> {
+    let reader = reader.with_filter(filter.predicate().clone());
+    let Some(projection) = filter.projection() else {
+        return Ok(Some(Arc::new(reader)));
+    };
+    let schema = reader.schema();
+    let expr = projection
+        .iter()
+        .map(|i| {
+            let name = schema.field(*i).name();
+            (
+                Arc::new(Column::new(name, *i)) as Arc<dyn PhysicalExpr>,
+                name.to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+    match push_projection(&expr, &reader)? {
+        Some(plan) => Ok(Some(plan)),
+        None => Ok(Some(Arc::new(ProjectionExec::try_new(
+            expr,
+            Arc::new(reader),
+        )?))),
+    }
+}
+
+/// Restrict the reader to the columns used by a projection or by the filter of the reader.
+/// The projection is kept above the reader unless it only selects those columns in order.
+/// Returns `None` if the projection uses every column.
+fn push_projection(
+    expr: &[(Arc<dyn PhysicalExpr>, String)],
+    reader: &ShuffleReaderExec,
+) -> Result<Option<Arc<dyn ExecutionPlan>>> {
+    let mut columns = BTreeSet::new();
+    for (e, _) in expr {
+        columns.extend(collect_columns(e).iter().map(|c| c.index()));
+    }
+    if let Some(filter) = &reader.filter {
+        columns.extend(collect_columns(filter).iter().map(|c| c.index()));
+    }
+    if columns.len() == reader.schema().fields().len() {
+        return Ok(None);
+    }
+    let columns = columns.into_iter().collect::<Vec<_>>();
+    let reader = Arc::new(reader.with_projection(&columns)?);
+
+    let expr = expr
+        .iter()
+        .map(|(e, name)| Ok((project_expr(e.clone(), &columns)?, name.clone())))
+        .collect::<Result<Vec<_>>>()?;
+    let schema = reader.schema();
+    let is_identity = expr.len() == schema.fields().len()
+        && expr.iter().enumerate().all(|(i, (e, name))| {
+            e.as_any()
+                .downcast_ref::<Column>()
+                .map_or(false, |c| c.index() == i && c.name() == name)
+                && schema.field(i).name() == name
+        });
+    if is_identity {
+        Ok(Some(reader))
+    } else {
+        Ok(Some(Arc::new(ProjectionExec::try_new(expr, reader)?)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shuffle::{LocalShuffleStorage, ShuffleWriterExec};
+    use crate::test_utils::{TempDir, TestResult};
+    use datafusion::arrow::array::{Array, Int64Array};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::logical_expr::Operator;
+    use datafusion::physical_expr::expressions::{binary, col, lit};
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::displayable;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::Partitioning;
+    use datafusion::prelude::SessionContext;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn push_projection_and_filter_into_reader() -> TestResult<()> {
+        let dir = TempDir::new()?;
+        let (writer, reader) = create_exchange(&dir)?;
+        let schema = reader.schema();
+        let predicate = binary(col("b", &schema)?, Operator::Gt, lit(15i64), &schema)?;
+        let filter = Arc::new(FilterExec::try_new(predicate, reader)?);
+        let filter_schema = filter.schema();
+        let projection = Arc::new(ProjectionExec::try_new(
+            vec![(col("a", &filter_schema)?, "a".to_string())],
+            filter,
+        )?);
+        let plan = ShuffleReaderPushdown::new().optimize(projection, &ConfigOptions::new())?;
+        assert_eq!(
+            "ProjectionExec: expr=[a@0 as a]\
+             \n  ShuffleReaderExec(stage_id=0, input_partitioning=UnknownPartitioning(1), \
+             projection=[a, b], filter=b@1 > 15)\n",
+            displayable(plan.as_ref()).indent(false).to_string()
+        );
+
+        let rt = Runtime::new()?;
+        let ctx = SessionContext::new().task_ctx();
+        for map_partition in 0..2 {
+            rt.block_on(collect(writer.execute(map_partition, ctx.clone())?))?;
+        }
+        let batches = rt.block_on(collect(plan.execute(0, ctx)?))?;
+        let mut values = batches
+            .iter()
+            .flat_map(|batch| {
+                assert_eq!(1, batch.num_columns());
+                let array = batch.column(0).as_any().downcast_ref::<Int64Array>();
+                array.unwrap().values().to_vec()
+            })
+            .collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(vec![6, 7, 8, 9], values);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_projections_of_all_columns() -> TestResult<()> {
+        let dir = TempDir::new()?;
+        let (_, reader) = create_exchange(&dir)?;
+        let schema = reader.schema();
+        let projection = Arc::new(ProjectionExec::try_new(
+            vec![
+                (col("c", &schema)?, "c".to_string()),
+                (col("b", &schema)?, "b".to_string()),
+                (col("a", &schema)?, "a".to_string()),
+            ],
+            reader,
+        )?);
+        let plan =
+            ShuffleReaderPushdown::new().optimize(projection.clone(), &ConfigOptions::new())?;
+        assert!(plan.as_any().downcast_ref::<ProjectionExec>().is_some());
+        Ok(())
+    }
+
+    /// Shuffle writer with two map tasks of five rows each with columns `a`, `b` and `c`, and
+    /// the matching reader, exchanging shuffle files in `dir`
+    fn create_exchange(dir: &TempDir) -> TestResult<(ShuffleWriterExec, Arc<dyn ExecutionPlan>)> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, false),
+            Field::new("b", DataType::Int64, false),
+            Field::new("c", DataType::Int64, false),
+        ]));
+        let partitions = (0..2)
+            .map(|p| {
+                let a = (p * 5..p * 5 + 5).collect::<Vec<_>>();
+                let b = a.iter().map(|a| a + 10).collect::<Vec<_>>();
+                let c = a.iter().map(|a| a + 20).collect::<Vec<_>>();
+                let batch = RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(Int64Array::from(a)),
+                        Arc::new(Int64Array::from(b)),
+                        Arc::new(Int64Array::from(c)),
+                    ],
+                )?;
+                Ok(vec![batch])
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let input = Arc::new(MemoryExec::try_new(&partitions, schema.clone(), None)?);
+        let storage = Arc::new(LocalShuffleStorage::new(dir.path_str()));
+        let partitioning = Partitioning::UnknownPartitioning(1);
+        let writer = ShuffleWriterExec::new(0, input, partitioning.clone(), storage.clone());
+        let reader = Arc::new(ShuffleReaderExec::new(0, schema, partitioning, storage, 2));
+        Ok((writer, reader))
+    }
+}
@@ -15,39 +15,66 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use crate::shuffle::CombinedRecordBatchStream;
+use crate::protobuf::{ShuffleMapOutput, ShufflePartitionOutput};
+use crate::shuffle::coalesce::coalesce_stream;
+use crate::shuffle::flight::{decode_map_output_locations, fetch_partition};
+use crate::shuffle::mmap::MmapShuffleStream;
+use crate::shuffle::{
+    read_map_output, shuffle_partitioning, CombinedRecordBatchStream, ShuffleFetchFailed,
+    ShuffleFile, ShuffleFileCorrupted, ShuffleFileReader, ShuffleStorage,
+};
 use datafusion::arrow::datatypes::SchemaRef;
-use datafusion::arrow::ipc::reader::FileReader;
 use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::common::tree_node::{Transformed, TreeNode};
 use datafusion::common::Statistics;
 use datafusion::error::{DataFusionError, Result};
 use datafusion::execution::context::TaskContext;
-use datafusion::physical_expr::expressions::UnKnownColumn;
-use datafusion::physical_expr::EquivalenceProperties;
+use datafusion::logical_expr::Operator;
+use datafusion::physical_expr::equivalence::ProjectionMapping;
+use datafusion::physical_expr::expressions::{BinaryExpr, Column};
+use datafusion::physical_expr::{EquivalenceProperties, PhysicalExpr};
+use datafusion::physical_plan::filter::batch_filter;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
 use datafusion::physical_plan::{
     DisplayAs, DisplayFormatType, ExecutionPlan, Partitioning, PlanProperties, RecordBatchStream,
     SendableRecordBatchStream,
 };
-use futures::Stream;
-use glob::glob;
+use futures::{Stream, StreamExt, TryStreamExt};
 use log::debug;
 use std::any::Any;
 use std::fmt::Formatter;
-use std::fs::File;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use tokio::sync::mpsc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ShuffleReaderExec {
     /// Query stage to read from
     pub stage_id: usize,
     /// The output schema of the query stage being read from
     schema: SchemaRef,
+    /// Partitioning of the shuffle output, in terms of `schema`
+    partitioning: Partitioning,
+    /// Columns of `schema` that are decoded, all of them if not set
+    pub projection: Option<Vec<usize>>,
+    /// Predicate on the projected columns that rows must satisfy
+    pub filter: Option<Arc<dyn PhysicalExpr>>,
 
     properties: PlanProperties,
-    /// Directory to read shuffle files from
-    pub shuffle_dir: String,
+    /// Storage to read shuffle files from
+    pub storage: Arc<dyn ShuffleStorage>,
+    /// Number of map tasks in the query stage being read from
+    pub map_partitions: usize,
+    /// Whether shuffle files on the local filesystem are memory-mapped instead of read into
+    /// newly allocated buffers
+    pub mmap: bool,
+    /// Whether the output of the map tasks is read in map partition order, one map task after
+    /// the other, so that rows are returned in the same order on every run
+    pub deterministic: bool,
+    /// Output of each map task in flight mode, in map partition order. The files are fetched
+    /// from the shuffle server of the node that wrote them instead of read from `storage`.
+    map_outputs: Option<Vec<ShuffleMapOutput>>,
 }
 
 impl ShuffleReaderExec {
@@ -55,34 +82,273 @@ impl ShuffleReaderExec {
         stage_id: usize,
         schema: SchemaRef,
         partitioning: Partitioning,
-        shuffle_dir: &str,
+        storage: Arc<dyn ShuffleStorage>,
+        map_partitions: usize,
     ) -> Self {
-        let partitioning = match partitioning {
-            Partitioning::Hash(expr, n) if expr.is_empty() => Partitioning::UnknownPartitioning(n),
-            Partitioning::Hash(expr, n) => {
-                // workaround for DataFusion bug https://github.com/apache/arrow-datafusion/issues/5184
-                Partitioning::Hash(
-                    expr.into_iter()
-                        .filter(|e| e.as_any().downcast_ref::<UnKnownColumn>().is_none())
-                        .collect(),
-                    n,
-                )
-            }
-            _ => partitioning,
-        };
+        let partitioning = shuffle_partitioning(partitioning);
 
         let properties = PlanProperties::new(
             EquivalenceProperties::new(schema.clone()),
-            partitioning,
+            partitioning.clone(),
             datafusion::physical_plan::ExecutionMode::Unbounded,
         );
 
         Self {
             stage_id,
             schema,
+            partitioning,
+            projection: None,
+            filter: None,
             properties,
-            shuffle_dir: shuffle_dir.to_string(),
+            storage,
+            map_partitions,
+            mmap: false,
+            deterministic: false,
+            map_outputs: None,
+        }
+    }
+
+    /// The schema of the shuffle files, before the projection
+    pub fn shuffle_schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    /// The partitioning of the shuffle output, in terms of [`Self::shuffle_schema`]
+    pub fn shuffle_partitioning(&self) -> &Partitioning {
+        &self.partitioning
+    }
+
+    /// Create a copy of this reader that memory-maps shuffle files stored on the local
+    /// filesystem. Other storages are read as usual.
+    pub fn with_mmap(&self, mmap: bool) -> Self {
+        Self {
+            mmap,
+            ..self.clone()
+        }
+    }
+
+    /// Create a copy of this reader that reads the output of the map tasks in a fixed order
+    pub fn with_deterministic(&self, deterministic: bool) -> Self {
+        Self {
+            deterministic,
+            ..self.clone()
+        }
+    }
+
+    /// Create a copy of this reader that only decodes some of its output columns.
+    /// `projection` holds indices into the current output schema, and must keep the columns
+    /// the filter refers to.
+    pub fn with_projection(&self, projection: &[usize]) -> Result<Self> {
+        let columns = match &self.projection {
+            Some(columns) => projection.iter().map(|i| columns[*i]).collect(),
+            None => projection.to_vec(),
+        };
+        let filter = match &self.filter {
+            Some(filter) => Some(project_expr(filter.clone(), projection)?),
+            None => None,
+        };
+        let mapping = ProjectionMapping::from_indices(&columns, &self.schema)?;
+        let eq_properties = EquivalenceProperties::new(self.schema.clone());
+        let projected_schema = Arc::new(self.schema.project(&columns)?);
+        let properties = PlanProperties::new(
+            eq_properties.project(&mapping, projected_schema),
+            self.partitioning.project(&mapping, &eq_properties),
+            datafusion::physical_plan::ExecutionMode::Unbounded,
+        );
+        Ok(Self {
+            projection: Some(columns),
+            filter,
+            properties,
+            ..self.clone()
+        })
+    }
+
+    /// Create a copy of this reader that only returns the rows satisfying `predicate`, in
+    /// addition to any filter it already has. The predicate refers to the output columns.
+    pub fn with_filter(&self, predicate: Arc<dyn PhysicalExpr>) -> Self {
+        let filter = match &self.filter {
+            Some(filter) => Arc::new(BinaryExpr::new(filter.clone(), Operator::And, predicate)),
+            None => predicate,
+        };
+        Self {
+            filter: Some(filter),
+            ..self.clone()
+        }
+    }
+
+    /// Create a copy of this reader that fetches the output of the map tasks from shuffle
+    /// servers. `inputs` holds the batches returned by the map tasks in flight mode.
+    pub fn with_map_outputs(&self, inputs: &[RecordBatch]) -> Result<Self> {
+        let mut outputs = decode_map_output_locations(inputs)?;
+        outputs.sort_by_key(|output| output.map_partition);
+        let map_partitions = outputs
+            .iter()
+            .map(|output| output.map_partition as usize)
+            .collect::<Vec<_>>();
+        if map_partitions != (0..self.map_partitions).collect::<Vec<_>>() {
+            return Err(DataFusionError::Execution(format!(
+                "Expected output of {} map tasks of query stage {} but got {:?}",
+                self.map_partitions, self.stage_id, map_partitions
+            )));
+        }
+        Ok(Self {
+            map_outputs: Some(outputs),
+            ..self.clone()
+        })
+    }
+
+    /// Fetch an output partition from the shuffle servers that serve the map outputs
+    fn fetch(&self, outputs: &[ShuffleMapOutput], partition: usize) -> SendableRecordBatchStream {
+        let mut streams: Vec<SendableRecordBatchStream> = vec![];
+        for output in outputs {
+            if !output
+                .partitions
+                .iter()
+                .any(|p| p.partition as usize == partition)
+            {
+                continue;
+            }
+            debug!(
+                "ShuffleReaderExec partition {} fetching from stage {} map partition {} at {}:{}",
+                partition, self.stage_id, output.map_partition, output.host, output.port
+            );
+            let stream =
+                fetch_partition(self.schema.clone(), self.storage.url(), output, partition);
+            let stream: SendableRecordBatchStream = match &self.projection {
+                // the shuffle server sends all columns
+                Some(projection) => {
+                    let projection = projection.clone();
+                    Box::pin(RecordBatchStreamAdapter::new(
+                        self.schema(),
+                        stream.map(move |batch| Ok(batch?.project(&projection)?)),
+                    ))
+                }
+                None => stream,
+            };
+            streams.push(stream);
+        }
+        Box::pin(combine_streams(self.schema(), streams, self.deterministic))
+    }
+}
+
+/// Find the shuffle files for an output partition using the metadata written by each map task.
+/// Fails with [`ShuffleFetchFailed`] if the output of any map task is missing.
+async fn shuffle_files(
+    storage: &dyn ShuffleStorage,
+    stage_id: usize,
+    map_partitions: usize,
+    partition: usize,
+) -> Result<Vec<(usize, ShufflePartitionOutput)>> {
+    let mut files = vec![];
+    let mut lost = vec![];
+    for map_partition in 0..map_partitions {
+        match read_map_output(storage, stage_id, map_partition).await? {
+            Some(output) => {
+                let mut complete = true;
+                for output in output.partitions {
+                    if output.partition as usize != partition {
+                        continue;
+                    }
+                    complete &= storage.exists(&output.path).await?;
+                    files.push((map_partition, output));
+                }
+                if !complete {
+                    lost.push(map_partition);
+                }
+            }
+            None => lost.push(map_partition),
+        }
+    }
+    if lost.is_empty() {
+        Ok(files)
+    } else {
+        Err(ShuffleFetchFailed {
+            stage_id,
+            map_partitions: lost,
+        }
+        .into())
+    }
+}
+
+/// Open the shuffle files for an output partition, in map partition order. The streams fail
+/// with [`ShuffleFileCorrupted`] if a file does not match its recorded checksum.
+async fn open_shuffle_files(
+    storage: Arc<dyn ShuffleStorage>,
+    schema: SchemaRef,
+    stage_id: usize,
+    map_partitions: usize,
+    partition: usize,
+    projection: Option<Vec<usize>>,
+    mmap: bool,
+) -> Result<Vec<SendableRecordBatchStream>> {
+    let projected_schema = match &projection {
+        Some(projection) => Arc::new(schema.project(projection)?),
+        None => schema.clone(),
+    };
+    let mut streams: Vec<SendableRecordBatchStream> = vec![];
+    let files = shuffle_files(storage.as_ref(), stage_id, map_partitions, partition).await?;
+    for (map_partition, output) in files {
+        debug!(
+            "ShuffleReaderExec partition {} reading from stage {} file {}",
+            partition, stage_id, output.path
+        );
+        let (stream, file_schema): (SendableRecordBatchStream, SchemaRef) = match storage
+            .local_path(&output.path)
+        {
+            Some(path) if mmap => {
+                let projection = projection.clone();
+                // the checksum is computed over the whole mapping
+                let (stream, actual) = tokio::task::spawn_blocking(move || {
+                    MmapShuffleStream::try_new(&path, projection)
+                })
+                .await
+                .map_err(|e| DataFusionError::External(Box::new(e)))??;
+                if actual != output.checksum {
+                    return Err(ShuffleFileCorrupted {
+                        stage_id,
+                        map_partition,
+                        path: output.path,
+                        expected: output.checksum,
+                        actual,
+                    }
+                    .into());
+                }
+                let file_schema = stream.file_schema();
+                (Box::pin(stream), file_schema)
+            }
+            _ => {
+                // the checksum is verified once the end of the file is read
+                let file = storage.open(&output.path).await?;
+                let projection = projection.clone();
+                let reader = tokio::task::spawn_blocking(move || {
+                    ShuffleFileReader::try_new(file, projection, stage_id, map_partition, &output)
+                })
+                .await
+                .map_err(|e| DataFusionError::External(Box::new(e)))??;
+                let file_schema = reader.schema();
+                let stream = LocalShuffleStream::new(reader, projected_schema.clone());
+                (Box::pin(stream), file_schema)
+            }
+        };
+        if schema != file_schema {
+            return Err(DataFusionError::Internal(
+                "Not all shuffle files have the same schema".to_string(),
+            ));
         }
+        streams.push(stream);
+    }
+    Ok(streams)
+}
+
+fn combine_streams(
+    schema: SchemaRef,
+    streams: Vec<SendableRecordBatchStream>,
+    deterministic: bool,
+) -> CombinedRecordBatchStream {
+    if deterministic {
+        CombinedRecordBatchStream::new_deterministic(schema, streams)
+    } else {
+        CombinedRecordBatchStream::new(schema, streams)
     }
 }
 
@@ -92,7 +358,7 @@ impl ExecutionPlan for ShuffleReaderExec {
     }
 
     fn schema(&self) -> SchemaRef {
-        self.schema.clone()
+        self.properties.eq_properties.schema().clone()
     }
 
     fn children(&self) -> Vec<&Arc<dyn ExecutionPlan>> {
@@ -109,38 +375,49 @@ impl ExecutionPlan for ShuffleReaderExec {
     fn execute(
         &self,
         partition: usize,
-        _context: Arc<TaskContext>,
+        context: Arc<TaskContext>,
     ) -> datafusion::common::Result<SendableRecordBatchStream> {
-        let pattern = format!(
-            "/{}/shuffle_{}_*_{partition}.arrow",
-            self.shuffle_dir, self.stage_id
-        );
-        let mut streams: Vec<SendableRecordBatchStream> = vec![];
-        for entry in glob(&pattern).expect("Failed to read glob pattern") {
-            let file = entry.unwrap();
-            debug!(
-                "ShuffleReaderExec partition {} reading from stage {} file {}",
-                partition,
-                self.stage_id,
-                file.display()
-            );
-            let reader = FileReader::try_new(File::open(&file)?, None)?;
-            let stream = LocalShuffleStream::new(reader);
-            if self.schema != stream.schema() {
-                return Err(DataFusionError::Internal(
-                    "Not all shuffle files have the same schema".to_string(),
-                ));
+        // each map task writes its last batch of a partition before it reaches the batch size
+        let batch_size = context.session_config().batch_size();
+        let stream = match &self.map_outputs {
+            Some(outputs) => self.fetch(outputs, partition),
+            None => {
+                let stream = open_shuffle_files(
+                    self.storage.clone(),
+                    self.schema.clone(),
+                    self.stage_id,
+                    self.map_partitions,
+                    partition,
+                    self.projection.clone(),
+                    self.mmap,
+                );
+                let schema = self.schema();
+                let deterministic = self.deterministic;
+                let stream = async move {
+                    let streams = stream.await?;
+                    Ok::<_, DataFusionError>(combine_streams(schema, streams, deterministic))
+                };
+                Box::pin(RecordBatchStreamAdapter::new(
+                    self.schema(),
+                    futures::stream::once(stream).try_flatten(),
+                ))
             }
-            streams.push(Box::pin(stream));
-        }
-        Ok(Box::pin(CombinedRecordBatchStream::new(
-            self.schema.clone(),
-            streams,
-        )))
+        };
+        let stream = match &self.filter {
+            Some(filter) => {
+                let filter = filter.clone();
+                Box::pin(RecordBatchStreamAdapter::new(
+                    self.schema(),
+                    stream.map(move |batch| batch_filter(&batch?, &filter)),
+                ))
+            }
+            None => stream,
+        };
+        Ok(coalesce_stream(stream, batch_size))
     }
 
     fn statistics(&self) -> Result<Statistics> {
-        Ok(Statistics::new_unknown(&self.schema))
+        Ok(Statistics::new_unknown(&self.schema()))
     }
 
     fn name(&self) -> &str {
@@ -156,36 +433,326 @@ impl DisplayAs for ShuffleReaderExec {
     fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
         write!(
             f,
-            "ShuffleReaderExec(stage_id={}, input_partitioning={:?})",
+            "ShuffleReaderExec(stage_id={}, input_partitioning={:?}",
             self.stage_id,
             self.properties().partitioning
-        )
+        )?;
+        if let Some(projection) = &self.projection {
+            let names = projection
+                .iter()
+                .map(|i| self.schema.field(*i).name().as_str())
+                .collect::<Vec<_>>();
+            write!(f, ", projection=[{}]", names.join(", "))?;
+        }
+        if let Some(filter) = &self.filter {
+            write!(f, ", filter={filter}")?;
+        }
+        if self.deterministic {
+            write!(f, ", deterministic=true")?;
+        }
+        write!(f, ")")
     }
 }
 
+/// Rewrite the columns of an expression on the output of a projection with `projection`
+/// holding the indices of the projected columns
+pub(crate) fn project_expr(
+    expr: Arc<dyn PhysicalExpr>,
+    projection: &[usize],
+) -> Result<Arc<dyn PhysicalExpr>> {
+    expr.transform(|node| {
+        let Some(column) = node.as_any().downcast_ref::<Column>() else {
+            return Ok(Transformed::no(node));
+        };
+        let index = projection
+            .iter()
+            .position(|i| *i == column.index())
+            .ok_or_else(|| {
+                DataFusionError::Internal(format!(
+                    "Projection {projection:?} removes column {column} of the shuffle reader filter"
+                ))
+            })?;
+        Ok(Transformed::yes(
+            Arc::new(Column::new(column.name(), index)) as Arc<dyn PhysicalExpr>,
+        ))
+    })
+    .map(|transformed| transformed.data)
+}
+
+/// Reads a shuffle file on a blocking thread, which is started when the stream is first polled
+/// and sends the batches as they are decoded
 struct LocalShuffleStream {
-    reader: FileReader<File>,
+    /// Reader of the file until the blocking thread takes it
+    reader: Option<ShuffleFileReader<Box<dyn ShuffleFile>>>,
+    batches: Option<mpsc::Receiver<Result<RecordBatch>>>,
+    /// Schema of the batches, which the projection of the reader applies to
+    schema: SchemaRef,
 }
 
 impl LocalShuffleStream {
-    pub fn new(reader: FileReader<File>) -> Self {
-        LocalShuffleStream { reader }
+    pub fn new(reader: ShuffleFileReader<Box<dyn ShuffleFile>>, schema: SchemaRef) -> Self {
+        LocalShuffleStream {
+            reader: Some(reader),
+            batches: None,
+            schema,
+        }
     }
 }
 
 impl Stream for LocalShuffleStream {
     type Item = datafusion::error::Result<RecordBatch>;
 
-    fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if let Some(batch) = self.reader.next() {
-            return Poll::Ready(Some(batch.map_err(|e| e.into())));
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(reader) = self.reader.take() {
+            let (tx, rx) = mpsc::channel(2);
+            tokio::task::spawn_blocking(move || {
+                for batch in reader {
+                    if tx.blocking_send(batch).is_err() {
+                        // the stream was dropped
+                        break;
+                    }
+                }
+            });
+            self.batches = Some(rx);
+        }
+        match self.batches.as_mut() {
+            Some(batches) => batches.poll_recv(cx),
+            None => Poll::Ready(None),
         }
-        Poll::Ready(None)
     }
 }
 
 impl RecordBatchStream for LocalShuffleStream {
     fn schema(&self) -> SchemaRef {
-        self.reader.schema()
+        self.schema.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shuffle::{
+        find_shuffle_fetch_failure, map_output_path, parse_shuffle_storage, LocalShuffleStorage,
+        ShuffleWriterExec,
+    };
+    use crate::test_utils::{TempDir, TestResult};
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::prelude::SessionContext;
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    #[test]
+    fn report_lost_map_outputs() -> TestResult<()> {
+        let dir = TempDir::new()?;
+        let (storage, writer, reader) = create_exchange(&dir)?;
+        let rt = Runtime::new()?;
+        let ctx = SessionContext::new().task_ctx();
+        for map_partition in 0..3 {
+            rt.block_on(collect(writer.execute(map_partition, ctx.clone())?))?;
+        }
+        let batches = rt.block_on(collect(reader.execute(0, ctx.clone())?))?;
+        assert_eq!(6, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+
+        let output = rt
+            .block_on(read_map_output(storage.as_ref(), 0, 2))?
+            .unwrap();
+        std::fs::remove_file(storage.path(&output.partitions[0].path))?;
+        std::fs::remove_file(storage.path(&map_output_path(0, 0)))?;
+        let Err(err) = rt.block_on(collect(reader.execute(0, ctx)?)) else {
+            panic!("expected the shuffle read to fail");
+        };
+        let lost = find_shuffle_fetch_failure(&err).expect("shuffle fetch failure");
+        assert_eq!(
+            ShuffleFetchFailed {
+                stage_id: 0,
+                map_partitions: vec![0, 2],
+            },
+            lost
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keep_output_of_first_attempt() -> TestResult<()> {
+        let dir = TempDir::new()?;
+        let (storage, writer, reader) = create_exchange(&dir)?;
+        let rt = Runtime::new()?;
+        let ctx = SessionContext::new().task_ctx();
+        for map_partition in [0, 1, 1, 2, 1] {
+            rt.block_on(collect(writer.execute(map_partition, ctx.clone())?))?;
+        }
+        let first = rt
+            .block_on(read_map_output(storage.as_ref(), 0, 1))?
+            .unwrap();
+        let files = std::fs::read_dir(storage.url())?
+            .map(|entry| Ok(entry?.file_name()))
+            .filter(|name| {
+                name.as_ref()
+                    .map_or(true, |n| n.to_string_lossy().ends_with(".arrow"))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(3, files.len());
+        let first_file = &first.partitions[0].path;
+        assert!(files
+            .iter()
+            .any(|name| name.to_string_lossy() == *first_file));
+
+        let batches = rt.block_on(collect(reader.execute(0, ctx.clone())?))?;
+        assert_eq!(6, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+
+        // an attempt that recomputes lost output replaces the committed output
+        std::fs::remove_file(storage.path(first_file))?;
+        rt.block_on(collect(writer.execute(1, ctx.clone())?))?;
+        let batches = rt.block_on(collect(reader.execute(0, ctx)?))?;
+        assert_eq!(6, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+        Ok(())
+    }
+
+    #[test]
+    fn detect_corrupt_files() -> TestResult<()> {
+        let dir = TempDir::new()?;
+        let (storage, writer, reader) = create_exchange(&dir)?;
+        let rt = Runtime::new()?;
+        let ctx = SessionContext::new().task_ctx();
+        for map_partition in 0..3 {
+            rt.block_on(collect(writer.execute(map_partition, ctx.clone())?))?;
+        }
+        let output = rt
+            .block_on(read_map_output(storage.as_ref(), 0, 1))?
+            .unwrap();
+        let path = &output.partitions[0].path;
+        let bytes = std::fs::read(storage.path(path))?;
+        // the header, a batch and the footer
+        for offset in [66, bytes.len() / 2, bytes.len() - 20] {
+            let mut corrupt = bytes.clone();
+            corrupt[offset] ^= 0xff;
+            std::fs::write(storage.path(path), corrupt)?;
+
+            let Err(err) = rt.block_on(collect(reader.execute(0, ctx.clone())?)) else {
+                panic!("expected the shuffle read to fail");
+            };
+            assert!(err.to_string().contains(&format!("Shuffle file {path}")));
+            assert!(err.to_string().contains("map partition 1 is corrupt"));
+            assert_eq!(
+                ShuffleFetchFailed {
+                    stage_id: 0,
+                    map_partitions: vec![1],
+                },
+                find_shuffle_fetch_failure(&err).expect("shuffle fetch failure")
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mmap_local_files() -> TestResult<()> {
+        let dir = TempDir::new()?;
+        let (_, writer, reader) = create_exchange(&dir)?;
+        let rt = Runtime::new()?;
+        let ctx = SessionContext::new().task_ctx();
+        for map_partition in 0..3 {
+            rt.block_on(collect(writer.execute(map_partition, ctx.clone())?))?;
+        }
+        let reader = reader.with_mmap(true);
+        let batches = rt.block_on(collect(reader.execute(0, ctx)?))?;
+        let mut values = batches
+            .iter()
+            .flat_map(|batch| {
+                let array = batch.column(0).as_any().downcast_ref::<Int64Array>();
+                array.unwrap().values().to_vec()
+            })
+            .collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(vec![0, 1, 2, 10, 11, 12], values);
+        Ok(())
+    }
+
+    #[test]
+    fn read_map_outputs_in_order() -> TestResult<()> {
+        let dir = TempDir::new()?;
+        let (_, writer, reader) = create_exchange(&dir)?;
+        let rt = Runtime::new()?;
+        let ctx = SessionContext::new().task_ctx();
+        for map_partition in [2, 0, 1] {
+            rt.block_on(collect(writer.execute(map_partition, ctx.clone())?))?;
+        }
+        let reader = reader.with_deterministic(true);
+        for _ in 0..5 {
+            let batches = rt.block_on(collect(reader.execute(0, ctx.clone())?))?;
+            let values = batches
+                .iter()
+                .flat_map(|batch| {
+                    let array = batch.column(0).as_any().downcast_ref::<Int64Array>();
+                    array.unwrap().values().to_vec()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(vec![0, 10, 1, 11, 2, 12], values);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn exchange_through_object_stores() -> TestResult<()> {
+        let dir = TempDir::new()?;
+        let urls = [
+            format!("memory://ray-sql-test-{}/stage-0", Uuid::new_v4()),
+            format!("file://{}", dir.path_str()),
+        ];
+        let rt = Runtime::new()?;
+        let ctx = SessionContext::new().task_ctx();
+        for url in urls {
+            let storage = parse_shuffle_storage(&url)?;
+            let (input, partitioning) = create_input()?;
+            let writer =
+                ShuffleWriterExec::new(0, input.clone(), partitioning.clone(), storage.clone());
+            let reader = ShuffleReaderExec::new(0, input.schema(), partitioning, storage, 3);
+            for map_partition in [0, 1, 2, 1] {
+                rt.block_on(collect(writer.execute(map_partition, ctx.clone())?))?;
+            }
+            let batches = rt.block_on(collect(reader.execute(0, ctx.clone())?))?;
+            assert_eq!(6, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+        }
+        // only the output of the first attempt of the map task that ran twice is kept
+        let files = std::fs::read_dir(dir.path())?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(3, files.iter().filter(|f| f.ends_with(".arrow")).count());
+        Ok(())
+    }
+
+    /// Three map tasks of two rows each, written to a single output partition
+    fn create_input() -> TestResult<(Arc<dyn ExecutionPlan>, Partitioning)> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let partitions = (0..3)
+            .map(|p| {
+                let batch = RecordBatch::try_new(
+                    schema.clone(),
+                    vec![Arc::new(Int64Array::from(vec![p, p + 10]))],
+                )?;
+                Ok(vec![batch])
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let input = Arc::new(MemoryExec::try_new(&partitions, schema, None)?);
+        Ok((input, Partitioning::UnknownPartitioning(1)))
+    }
+
+    /// Shuffle writer with three map tasks of two rows each, and the matching reader, exchanging
+    /// data through `dir`
+    fn create_exchange(
+        dir: &TempDir,
+    ) -> TestResult<(
+        Arc<LocalShuffleStorage>,
+        ShuffleWriterExec,
+        ShuffleReaderExec,
+    )> {
+        let (input, partitioning) = create_input()?;
+        let storage = Arc::new(LocalShuffleStorage::new(dir.path_str()));
+        let schema = input.schema();
+        let writer = ShuffleWriterExec::new(0, input, partitioning.clone(), storage.clone());
+        let reader = ShuffleReaderExec::new(0, schema, partitioning, storage.clone(), 3);
+        Ok((storage, writer, reader))
     }
 }
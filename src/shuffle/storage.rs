@@ -0,0 +1,379 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use datafusion::error::{DataFusionError, Result};
+use object_store::memory::InMemory;
+use object_store::path::Path as ObjectPath;
+
+use futures::StreamExt;
+use object_store::{ObjectStore, PutMode, PutOptions, WriteMultipart};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{ErrorKind, Read, Seek};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::OnceCell;
+use url::Url;
+use uuid::Uuid;
+
+/// Size of the chunks in which staged shuffle files are uploaded to an object store
+const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// In-memory object stores by URL, so that the writers and readers of a plan running in the
+/// same process share the store
+static MEMORY_STORES: OnceCell<Mutex<HashMap<String, Arc<InMemory>>>> = OnceCell::const_new();
+
+/// A shuffle file opened for reading
+pub trait ShuffleFile: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ShuffleFile for T {}
+
+/// The kinds of [`ShuffleStorage`], which are encoded in serialized plans together with the
+/// URL of the storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShuffleStorageBackend {
+    /// A directory on the local filesystem, or on a filesystem shared by all workers
+    Local,
+    /// An in-memory object store that only lives as long as the process, for tests
+    Memory,
+    /// Any other object store, such as S3
+    ObjectStore,
+}
+
+/// Where shuffle writers store their files and metadata, and shuffle readers read them from.
+/// Paths are relative to the root of the storage.
+#[async_trait]
+pub trait ShuffleStorage: Debug + Send + Sync {
+    fn backend(&self) -> ShuffleStorageBackend;
+
+    /// URL of the storage, from which it is recreated with [`create_shuffle_storage`]
+    fn url(&self) -> &str;
+
+    /// Path of a local file to write a shuffle file to before it is stored under `path` with
+    /// [`ShuffleStorage::put_file`]
+    fn staging_path(&self, path: &str) -> String;
+
+    /// Store the local file at `staging_path` under `path`, removing the local file
+    async fn put_file(&self, staging_path: &str, path: &str) -> Result<()>;
+
+    /// Store `data` under `path`, replacing any existing file
+    async fn put(&self, path: &str, data: Bytes) -> Result<()>;
+
+    /// Store `data` under `path` unless a file already exists there. Returns false if it does.
+    async fn put_if_absent(&self, path: &str, data: Bytes) -> Result<bool>;
+
+    /// Read a whole file, returning `None` if it does not exist
+    async fn get(&self, path: &str) -> Result<Option<Bytes>>;
+
+    async fn exists(&self, path: &str) -> Result<bool>;
+
+    async fn delete(&self, path: &str) -> Result<()>;
+
+    /// Open a shuffle file for reading
+    async fn open(&self, path: &str) -> Result<Box<dyn ShuffleFile>>;
+
+    /// Path of a shuffle file on the local filesystem, if the storage keeps files there
+    fn local_path(&self, _path: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Create the shuffle storage of a backend from its URL
+pub fn create_shuffle_storage(
+    backend: ShuffleStorageBackend,
+    url: &str,
+) -> Result<Arc<dyn ShuffleStorage>> {
+    Ok(match backend {
+        ShuffleStorageBackend::Local => Arc::new(LocalShuffleStorage::new(url)),
+        ShuffleStorageBackend::Memory => Arc::new(ObjectStoreShuffleStorage::in_memory(url)?),
+        ShuffleStorageBackend::ObjectStore => Arc::new(ObjectStoreShuffleStorage::try_new(url)?),
+    })
+}
+
+/// Create a shuffle storage from a path or URL. A path names a directory on the local
+/// filesystem, `memory://` URLs name an in-memory store and any other URL, such as
+/// `file:///tmp/shuffle` or `s3://bucket/prefix`, is opened with `object_store`.
+pub fn parse_shuffle_storage(url: &str) -> Result<Arc<dyn ShuffleStorage>> {
+    let backend = match Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "memory" => ShuffleStorageBackend::Memory,
+        // a bare path fails to parse, while a Windows drive letter parses as a scheme
+        Ok(parsed) if parsed.scheme().len() > 1 => ShuffleStorageBackend::ObjectStore,
+        _ => ShuffleStorageBackend::Local,
+    };
+    create_shuffle_storage(backend, url)
+}
+
+/// Shuffle files in a directory on the local filesystem. Files are written in place and
+/// committed with a rename, and metadata is committed with a hard link, which fails if another
+/// attempt of the task already committed.
+#[derive(Debug)]
+pub struct LocalShuffleStorage {
+    dir: String,
+}
+
+impl LocalShuffleStorage {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: dir.to_string(),
+        }
+    }
+
+    /// Path of a shuffle file on the local filesystem
+    pub fn path(&self, path: &str) -> String {
+        format!("{}/{path}", self.dir)
+    }
+
+    /// Write `data` to a temporary file next to `path`, returning the name of the file
+    async fn write_temp_file(&self, path: &str, data: &[u8]) -> Result<String> {
+        fs::create_dir_all(&self.dir).await?;
+        let temp_path = format!("{}.{}", self.path(path), Uuid::new_v4().simple());
+        fs::write(&temp_path, data).await?;
+        Ok(temp_path)
+    }
+}
+
+#[async_trait]
+impl ShuffleStorage for LocalShuffleStorage {
+    fn backend(&self) -> ShuffleStorageBackend {
+        ShuffleStorageBackend::Local
+    }
+
+    fn url(&self) -> &str {
+        &self.dir
+    }
+
+    fn staging_path(&self, path: &str) -> String {
+        format!("{}.inprogress", self.path(path))
+    }
+
+    async fn put_file(&self, staging_path: &str, path: &str) -> Result<()> {
+        Ok(fs::rename(staging_path, self.path(path)).await?)
+    }
+
+    async fn put(&self, path: &str, data: Bytes) -> Result<()> {
+        let temp_path = self.write_temp_file(path, &data).await?;
+        Ok(fs::rename(temp_path, self.path(path)).await?)
+    }
+
+    async fn put_if_absent(&self, path: &str, data: Bytes) -> Result<bool> {
+        let temp_path = self.write_temp_file(path, &data).await?;
+        let result = fs::hard_link(&temp_path, self.path(path)).await;
+        fs::remove_file(&temp_path).await?;
+        match result {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, path: &str) -> Result<Option<Bytes>> {
+        match fs::read(self.path(path)).await {
+            Ok(data) => Ok(Some(data.into())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        Ok(fs::try_exists(self.path(path)).await?)
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        Ok(fs::remove_file(self.path(path)).await?)
+    }
+
+    async fn open(&self, path: &str) -> Result<Box<dyn ShuffleFile>> {
+        Ok(Box::new(
+            fs::File::open(self.path(path)).await?.into_std().await,
+        ))
+    }
+
+    fn local_path(&self, path: &str) -> Option<String> {
+        Some(self.path(path))
+    }
+}
+
+/// Shuffle files in an [`ObjectStore`]. Files are written to a local staging file and uploaded
+/// once complete. Metadata is committed with a conditional put where the store supports it.
+/// For S3 this requires setting `AWS_CONDITIONAL_PUT=etag`.
+#[derive(Debug)]
+pub struct ObjectStoreShuffleStorage {
+    backend: ShuffleStorageBackend,
+    url: String,
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+}
+
+impl ObjectStoreShuffleStorage {
+    /// Open the object store at a URL. Store options, such as credentials, are read from the
+    /// `AWS_*` environment variables, as S3 is the only object store this crate is built with.
+    pub fn try_new(url: &str) -> Result<Self> {
+        let parsed = Url::parse(url)
+            .map_err(|e| DataFusionError::Configuration(format!("Invalid URL {url}: {e}")))?;
+        let options = std::env::vars()
+            .filter(|(key, _)| key.starts_with("AWS_"))
+            .map(|(key, value)| (key.to_lowercase(), value));
+        let (store, prefix) = object_store::parse_url_opts(&parsed, options)?;
+        Ok(Self {
+            backend: ShuffleStorageBackend::ObjectStore,
+            url: url.to_string(),
+            store: Arc::from(store),
+            prefix,
+        })
+    }
+
+    /// Open the in-memory store with the given `memory://` URL, creating it if this process
+    /// has not used it yet
+    pub fn in_memory(url: &str) -> Result<Self> {
+        let parsed = Url::parse(url)
+            .map_err(|e| DataFusionError::Configuration(format!("Invalid URL {url}: {e}")))?;
+        let _ = MEMORY_STORES.set(Mutex::default());
+        let store = MEMORY_STORES
+            .get()
+            .ok_or_else(|| DataFusionError::Internal("No in-memory stores".to_string()))?
+            .lock()
+            .map_err(|_| DataFusionError::Internal("In-memory stores are poisoned".to_string()))?
+            .entry(url.to_string())
+            .or_default()
+            .clone();
+        Ok(Self {
+            backend: ShuffleStorageBackend::Memory,
+            url: url.to_string(),
+            store,
+            prefix: ObjectPath::from(parsed.path()),
+        })
+    }
+
+    fn location(&self, path: &str) -> ObjectPath {
+        self.prefix.child(path)
+    }
+}
+
+#[async_trait]
+impl ShuffleStorage for ObjectStoreShuffleStorage {
+    fn backend(&self) -> ShuffleStorageBackend {
+        self.backend
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn staging_path(&self, path: &str) -> String {
+        let name = format!("{}.{path}.inprogress", Uuid::new_v4().simple());
+        staging_dir().join(name).to_string_lossy().to_string()
+    }
+
+    async fn put_file(&self, staging_path: &str, path: &str) -> Result<()> {
+        let upload = self.store.put_multipart(&self.location(path)).await?;
+        let mut writer = WriteMultipart::new_with_chunk_size(upload, UPLOAD_CHUNK_SIZE);
+        let mut file = fs::File::open(staging_path).await?;
+        let mut buf = vec![0; UPLOAD_CHUNK_SIZE];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            writer.wait_for_capacity(4).await?;
+            writer.write(&buf[..n]);
+        }
+        writer.finish().await?;
+        Ok(fs::remove_file(staging_path).await?)
+    }
+
+    async fn put(&self, path: &str, data: Bytes) -> Result<()> {
+        self.store.put(&self.location(path), data.into()).await?;
+        Ok(())
+    }
+
+    async fn put_if_absent(&self, path: &str, data: Bytes) -> Result<bool> {
+        let location = self.location(path);
+        let options = PutOptions {
+            mode: PutMode::Create,
+            ..Default::default()
+        };
+        match self
+            .store
+            .put_opts(&location, data.clone().into(), options)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::AlreadyExists { .. }) => Ok(false),
+            Err(object_store::Error::NotImplemented) => {
+                // without conditional puts two attempts that finish at the same time may both
+                // commit, and readers see the output of either one
+                if self.exists(path).await? {
+                    return Ok(false);
+                }
+                self.store.put(&location, data.into()).await?;
+                Ok(true)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, path: &str) -> Result<Option<Bytes>> {
+        match self.store.get(&self.location(path)).await {
+            Ok(result) => Ok(Some(result.bytes().await?)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        match self.store.head(&self.location(path)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        Ok(self.store.delete(&self.location(path)).await?)
+    }
+
+    async fn open(&self, path: &str) -> Result<Box<dyn ShuffleFile>> {
+        // the object is downloaded to a local file rather than held in memory
+        let mut stream = self.store.get(&self.location(path)).await?.into_stream();
+        fs::create_dir_all(staging_dir()).await?;
+        let local_path = staging_dir().join(format!("{}.download", Uuid::new_v4().simple()));
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&local_path)
+            .await?;
+        // the file stays readable through its handle, and is freed once that is closed
+        fs::remove_file(&local_path).await?;
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+        file.rewind().await?;
+        Ok(Box::new(file.into_std().await))
+    }
+}
+
+/// Directory of the local files that shuffle files are written to before they are uploaded to
+/// an object store, and downloaded to before they are read
+fn staging_dir() -> PathBuf {
+    std::env::temp_dir().join("ray-sql-shuffle-staging")
+}
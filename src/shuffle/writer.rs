@@ -15,16 +15,26 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use datafusion::arrow::array::Int32Array;
+use crate::protobuf::{ShuffleMapOutput, ShufflePartitionOutput};
+use crate::shuffle::coalesce::{
+    ShuffleBatchBuffers, SHUFFLE_TARGET_BATCH_BYTES, SHUFFLE_WRITE_BUFFER_BYTES,
+};
+use crate::shuffle::flight::{
+    decode_map_output_locations, encode_map_output_location, map_output_location_schema,
+    shuffle_server_address,
+};
+use crate::shuffle::map_output::{commit_map_output, read_map_output, ChecksumWriter};
+use crate::shuffle::memory::{encode_map_output, map_output_schema};
+use crate::shuffle::{shuffle_partitioning, LocalShuffleStorage, ShuffleMode, ShuffleStorage};
+use datafusion::arrow::array::{Array, Int32Array, UInt64Array};
 use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
 use datafusion::arrow::ipc::writer::FileWriter;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::arrow::util::pretty::pretty_format_batches;
-use datafusion::common::{Result, Statistics};
+use datafusion::common::{DataFusionError, Result, Statistics};
 use datafusion::execution::context::TaskContext;
-use datafusion::physical_expr::expressions::UnKnownColumn;
+use datafusion::execution::memory_pool::MemoryConsumer;
 use datafusion::physical_expr::EquivalenceProperties;
-use datafusion::physical_plan::common::IPCWriter;
 use datafusion::physical_plan::memory::MemoryStream;
 use datafusion::physical_plan::metrics::{ExecutionPlanMetricsSet, MetricBuilder};
 use datafusion::physical_plan::repartition::BatchPartitioner;
@@ -40,9 +50,11 @@ use log::debug;
 use std::any::Any;
 use std::fmt::Formatter;
 use std::fs::File;
+use std::io::BufWriter;
 use std::path::Path;
 use std::pin::Pin;
 use std::sync::Arc;
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct ShuffleWriterExec {
@@ -50,8 +62,10 @@ pub struct ShuffleWriterExec {
     pub(crate) plan: Arc<dyn ExecutionPlan>,
     /// Output partitioning
     properties: PlanProperties,
-    /// Directory to write shuffle files from
-    pub shuffle_dir: String,
+    /// Storage to write shuffle files to
+    pub storage: Arc<dyn ShuffleStorage>,
+    /// Whether the output is written to shuffle files or returned as the task result
+    pub shuffle_mode: ShuffleMode,
     /// Metrics
     pub metrics: ExecutionPlanMetricsSet,
 }
@@ -61,21 +75,9 @@ impl ShuffleWriterExec {
         stage_id: usize,
         plan: Arc<dyn ExecutionPlan>,
         partitioning: Partitioning,
-        shuffle_dir: &str,
+        storage: Arc<dyn ShuffleStorage>,
     ) -> Self {
-        let partitioning = match partitioning {
-            Partitioning::Hash(expr, n) if expr.is_empty() => Partitioning::UnknownPartitioning(n),
-            Partitioning::Hash(expr, n) => {
-                // workaround for DataFusion bug https://github.com/apache/arrow-datafusion/issues/5184
-                Partitioning::Hash(
-                    expr.into_iter()
-                        .filter(|e| e.as_any().downcast_ref::<UnKnownColumn>().is_none())
-                        .collect(),
-                    n,
-                )
-            }
-            _ => partitioning,
-        };
+        let partitioning = shuffle_partitioning(partitioning);
         let properties = PlanProperties::new(
             EquivalenceProperties::new(plan.schema()),
             partitioning,
@@ -86,10 +88,128 @@ impl ShuffleWriterExec {
             stage_id,
             plan,
             properties,
-            shuffle_dir: shuffle_dir.to_string(),
+            storage,
+            shuffle_mode: ShuffleMode::Disk,
             metrics: ExecutionPlanMetricsSet::new(),
         }
     }
+
+    /// Create a shuffle writer that returns its output as a single batch instead of writing
+    /// shuffle files. The batch holds the Arrow IPC bytes of each output partition.
+    pub fn new_in_memory(
+        stage_id: usize,
+        plan: Arc<dyn ExecutionPlan>,
+        partitioning: Partitioning,
+    ) -> Self {
+        Self {
+            shuffle_mode: ShuffleMode::Memory,
+            ..Self::new(
+                stage_id,
+                plan,
+                partitioning,
+                Arc::new(LocalShuffleStorage::new("")),
+            )
+        }
+    }
+
+    /// Create a shuffle writer that writes shuffle files to a directory local to the node it
+    /// runs on. The files are served by the shuffle server of the process, and the task returns
+    /// a single batch holding the location of its output.
+    pub fn new_flight(
+        stage_id: usize,
+        plan: Arc<dyn ExecutionPlan>,
+        partitioning: Partitioning,
+        shuffle_dir: &str,
+    ) -> Self {
+        Self {
+            shuffle_mode: ShuffleMode::Flight,
+            ..Self::new(
+                stage_id,
+                plan,
+                partitioning,
+                Arc::new(LocalShuffleStorage::new(shuffle_dir)),
+            )
+        }
+    }
+
+    /// Commit an empty output for a map task that is not run, such as a task whose rows a query
+    /// with a LIMIT no longer needs, so that the readers of the stage read no rows from it.
+    /// Returns the batch that the task would have returned. If an attempt of the task has
+    /// already committed its output, that output is kept.
+    pub async fn commit_empty_output(&self, input_partition: usize) -> Result<RecordBatch> {
+        let output = ShuffleMapOutput {
+            stage_id: self.stage_id as u32,
+            map_partition: input_partition as u32,
+            ..Default::default()
+        };
+        match self.shuffle_mode {
+            ShuffleMode::Disk => {
+                commit_map_output(self.storage.as_ref(), &output).await?;
+                map_task_summary(0, 0, 0)
+            }
+            ShuffleMode::Memory => encode_map_output(&self.plan.schema(), vec![]),
+            // the output has no partitions, so readers never fetch it from a shuffle server
+            ShuffleMode::Flight => encode_map_output_location(&output),
+        }
+    }
+
+    fn execute_in_memory(
+        &self,
+        input_partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let batch_size = context.session_config().batch_size();
+        let reservation = MemoryConsumer::new(format!("ShuffleWriterExec[{input_partition}]"))
+            .register(context.memory_pool());
+        let mut stream = self.plan.execute(input_partition, context)?;
+        let repart_time =
+            MetricBuilder::new(&self.metrics).subset_time("repart_time", input_partition);
+        let partitioning = self.properties().output_partitioning().to_owned();
+        let schema = self.plan.schema();
+
+        let results = async move {
+            let mut partitions = vec![vec![]; partitioning.partition_count()];
+            match &partitioning {
+                Partitioning::Hash(_, _) => {
+                    let mut partitioner =
+                        BatchPartitioner::try_new(partitioning.clone(), repart_time)?;
+                    let mut buffers = ShuffleBatchBuffers::new(
+                        schema.clone(),
+                        partitioning.partition_count(),
+                        batch_size,
+                        SHUFFLE_TARGET_BATCH_BYTES,
+                        SHUFFLE_WRITE_BUFFER_BYTES,
+                        reservation,
+                    );
+                    while let Some(result) = stream.next().await {
+                        partitioner.partition(result?, |output_partition, output_batch| {
+                            for (output_partition, batch) in
+                                buffers.push(output_partition, output_batch)?
+                            {
+                                partitions[output_partition].push(batch);
+                            }
+                            Ok(())
+                        })?;
+                    }
+                    for (output_partition, batch) in buffers.finish()? {
+                        partitions[output_partition].push(batch);
+                    }
+                }
+                _ => {
+                    // preserve the input partitioning
+                    while let Some(result) = stream.next().await {
+                        partitions[0].push(result?);
+                    }
+                }
+            }
+            let batch = encode_map_output(&schema, partitions)?;
+            MemoryStream::try_new(vec![batch], map_output_schema(), None)
+        };
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            map_output_schema(),
+            futures::stream::once(results).try_flatten(),
+        )))
+    }
 }
 
 impl ExecutionPlan for ShuffleWriterExec {
@@ -107,9 +227,16 @@ impl ExecutionPlan for ShuffleWriterExec {
 
     fn with_new_children(
         self: Arc<Self>,
-        _: Vec<Arc<dyn ExecutionPlan>>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
     ) -> Result<Arc<dyn ExecutionPlan>> {
-        unimplemented!()
+        Ok(Arc::new(Self {
+            stage_id: self.stage_id,
+            plan: children[0].clone(),
+            properties: self.properties.clone(),
+            storage: self.storage.clone(),
+            shuffle_mode: self.shuffle_mode,
+            metrics: ExecutionPlanMetricsSet::new(),
+        }))
     }
 
     fn execute(
@@ -121,7 +248,13 @@ impl ExecutionPlan for ShuffleWriterExec {
             "ShuffleWriterExec[stage={}].execute(input_partition={input_partition})",
             self.stage_id
         );
+        if self.shuffle_mode == ShuffleMode::Memory {
+            return self.execute_in_memory(input_partition, context);
+        }
 
+        let batch_size = context.session_config().batch_size();
+        let reservation = MemoryConsumer::new(format!("ShuffleWriterExec[{input_partition}]"))
+            .register(context.memory_pool());
         let mut stream = self.plan.execute(input_partition, context)?;
         let write_time =
             MetricBuilder::new(&self.metrics).subset_time("write_time", input_partition);
@@ -131,34 +264,96 @@ impl ExecutionPlan for ShuffleWriterExec {
         let stage_id = self.stage_id;
         let partitioning = self.properties().output_partitioning().to_owned();
         let partition_count = partitioning.partition_count();
-        let shuffle_dir = self.shuffle_dir.clone();
+        let storage = self.storage.clone();
+        let shuffle_mode = self.shuffle_mode;
+        // several attempts of the same task may run at once, so each attempt writes its own
+        // files and the first one to finish commits its output
+        let attempt_id = Uuid::new_v4().simple().to_string();
 
         let results = async move {
+            let mut outputs = vec![];
+            // dropped with this future, removing the files that were not stored
+            let mut staging = StagingFiles::default();
             match &partitioning {
                 Partitioning::RoundRobinBatch(_) => {
-                    unimplemented!()
+                    return Err(DataFusionError::NotImplemented(
+                        "Shuffle writer with round robin partitioning".to_string(),
+                    ));
                 }
                 Partitioning::UnknownPartitioning(_) => {
                     // stream the results from the query, preserving the input partitioning
-                    let file =
-                        format!("/{shuffle_dir}/shuffle_{stage_id}_{input_partition}_0.arrow");
-                    debug!("Executing query and writing results to {file}");
-                    let stats = write_stream_to_disk(&mut stream, &file, &write_time).await?;
+                    let file = shuffle_file_path(stage_id, input_partition, 0, &attempt_id);
+                    // the file is stored once it is complete
+                    let staging_path = staging.add(storage.staging_path(&file));
+                    debug!("Executing query and writing results to {staging_path}");
+                    let (stats, checksum) =
+                        write_stream_to_disk(&mut stream, &staging_path, &write_time).await?;
                     debug!(
                         "Query completed. Shuffle write time: {}. Rows: {}.",
                         write_time, stats.num_rows
                     );
+                    storage.put_file(&staging_path, &file).await?;
+                    outputs.push(ShufflePartitionOutput {
+                        partition: 0,
+                        path: file,
+                        num_rows: stats.num_rows as u64,
+                        num_batches: stats.num_batches as u64,
+                        num_bytes: stats.num_bytes as u64,
+                        checksum,
+                    });
                 }
                 Partitioning::Hash(_, _) => {
                     // we won't necessary produce output for every possible partition, so we
                     // create writers on demand
-                    let mut writers: Vec<Option<IPCWriter>> = vec![];
+                    let mut writers: Vec<Option<ShuffleFileWriter>> = vec![];
                     for _ in 0..partition_count {
                         writers.push(None);
                     }
+                    let schema = stream.schema();
+                    let write = |writers: &mut Vec<Option<ShuffleFileWriter>>,
+                                 staging: &mut StagingFiles,
+                                 output_partition: usize,
+                                 output_batch: &RecordBatch|
+                     -> Result<()> {
+                        match &mut writers[output_partition] {
+                            Some(w) => {
+                                w.write(output_batch)?;
+                            }
+                            None => {
+                                let path = shuffle_file_path(
+                                    stage_id,
+                                    input_partition,
+                                    output_partition,
+                                    &attempt_id,
+                                );
+                                // the file is stored once it is complete
+                                let path = staging.add(storage.staging_path(&path));
+                                debug!(
+                                    "ShuffleWriterExec[stage={}] Writing results to {:?}",
+                                    stage_id, path
+                                );
+
+                                let mut writer = ShuffleFileWriter::try_new(path, schema.as_ref())?;
+
+                                writer.write(output_batch)?;
+                                writers[output_partition] = Some(writer);
+                            }
+                        }
+                        Ok(())
+                    };
 
                     let mut partitioner =
                         BatchPartitioner::try_new(partitioning.clone(), repart_time.clone())?;
+                    // repartitioning slices each input batch into many small batches, which
+                    // are buffered so that the files hold fewer, larger batches
+                    let mut buffers = ShuffleBatchBuffers::new(
+                        schema.clone(),
+                        partition_count,
+                        batch_size,
+                        SHUFFLE_TARGET_BATCH_BYTES,
+                        SHUFFLE_WRITE_BUFFER_BYTES,
+                        reservation,
+                    );
 
                     let mut rows = 0;
 
@@ -169,48 +364,46 @@ impl ExecutionPlan for ShuffleWriterExec {
                         debug!(
                             "ShuffleWriterExec[stage={}] writing batch:\n{}",
                             stage_id,
-                            pretty_format_batches(&[input_batch.clone()])?
+                            pretty_format_batches(std::slice::from_ref(&input_batch))?
                         );
 
                         //write_metrics.input_rows.add(input_batch.num_rows());
 
                         partitioner.partition(input_batch, |output_partition, output_batch| {
-                            match &mut writers[output_partition] {
-                                Some(w) => {
-                                    w.write(&output_batch)?;
-                                }
-                                None => {
-                                    let path = format!(
-                                        "/{shuffle_dir}/shuffle_{stage_id}_{input_partition}_{output_partition}.arrow",
-                                    );
-                                    let path = Path::new(&path);
-                                    debug!("ShuffleWriterExec[stage={}] Writing results to {:?}", stage_id, path);
-
-                                    let mut writer = IPCWriter::new(path, stream.schema().as_ref())?;
-
-                                    writer.write(&output_batch)?;
-                                    writers[output_partition] = Some(writer);
-                                }
+                            for (output_partition, batch) in
+                                buffers.push(output_partition, output_batch)?
+                            {
+                                write(&mut writers, &mut staging, output_partition, &batch)?;
                             }
                             Ok(())
                         })?;
                     }
+                    for (output_partition, batch) in buffers.finish()? {
+                        write(&mut writers, &mut staging, output_partition, &batch)?;
+                    }
 
                     for (i, w) in writers.iter_mut().enumerate() {
-                        match w {
-                            Some(w) => {
-                                w.finish()?;
-                                debug!(
-                                        "ShuffleWriterExec[stage={}] Finished writing shuffle partition {} at {:?}. Batches: {}. Rows: {}. Bytes: {}.",
-                                        stage_id,
-                                        i,
-                                        w.path(),
-                                        w.num_batches,
-                                        w.num_rows,
-                                        w.num_bytes
-                                    );
-                            }
-                            None => {}
+                        if let Some(w) = w {
+                            let checksum = w.finish()?;
+                            let path = shuffle_file_path(stage_id, input_partition, i, &attempt_id);
+                            storage.put_file(&w.path, &path).await?;
+                            debug!(
+                                    "ShuffleWriterExec[stage={}] Finished writing shuffle partition {} at {:?}. Batches: {}. Rows: {}. Bytes: {}.",
+                                    stage_id,
+                                    i,
+                                    path,
+                                    w.num_batches,
+                                    w.num_rows,
+                                    w.num_bytes
+                                );
+                            outputs.push(ShufflePartitionOutput {
+                                partition: i as u32,
+                                path,
+                                num_rows: w.num_rows as u64,
+                                num_batches: w.num_batches as u64,
+                                num_bytes: w.num_bytes as u64,
+                                checksum,
+                            });
                         }
                     }
                     debug!(
@@ -220,23 +413,54 @@ impl ExecutionPlan for ShuffleWriterExec {
                 }
             }
 
-            // create a dummy batch to return - later this could be metadata about the
-            // shuffle partitions that were written out
-            let schema = Arc::new(Schema::new(vec![
-                Field::new("shuffle_repart_time", DataType::Int32, true),
-                Field::new("shuffle_write_time", DataType::Int32, true),
-            ]));
-            let arr_repart_time = Int32Array::from(vec![repart_time.value() as i32]);
-            let arr_write_time = Int32Array::from(vec![write_time.value() as i32]);
-            let batch = RecordBatch::try_new(
-                schema.clone(),
-                vec![Arc::new(arr_repart_time), Arc::new(arr_write_time)],
-            )?;
+            // record which files this task wrote so that readers can detect lost output
+            let output = ShuffleMapOutput {
+                stage_id: stage_id as u32,
+                map_partition: input_partition as u32,
+                partitions: outputs,
+                ..Default::default()
+            };
+            if !commit_map_output(storage.as_ref(), &output).await? {
+                debug!(
+                    "ShuffleWriterExec[stage={}] discarded output of partition {input_partition}, another attempt committed first",
+                    stage_id
+                );
+            }
+
+            if shuffle_mode == ShuffleMode::Flight {
+                // return the committed output, which may be that of another attempt
+                let (host, port) = shuffle_server_address().ok_or_else(|| {
+                    DataFusionError::Execution(
+                        "The shuffle server of this process has not been started".to_string(),
+                    )
+                })?;
+                let mut output = read_map_output(storage.as_ref(), stage_id, input_partition)
+                    .await?
+                    .ok_or_else(|| {
+                        DataFusionError::Internal(format!(
+                            "Committed output of query stage {stage_id} map partition \
+                             {input_partition} is missing"
+                        ))
+                    })?;
+                output.host = host;
+                output.port = port as u32;
+                let batch = encode_map_output_location(&output)?;
+                return MemoryStream::try_new(vec![batch], map_output_location_schema(), None);
+            }
+
+            // return a summary of the shuffle partitions that were written out
+            let num_rows = output.partitions.iter().map(|p| p.num_rows).sum();
+            let schema = map_task_summary_schema();
+            let batch = map_task_summary(repart_time.value(), write_time.value(), num_rows)?;
 
             // return as a stream
             MemoryStream::try_new(vec![batch], schema, None)
         };
-        let schema = self.schema();
+        let schema = if self.shuffle_mode == ShuffleMode::Flight {
+            map_output_location_schema()
+        } else {
+            self.schema()
+        };
 
         Ok(Box::pin(RecordBatchStreamAdapter::new(
             schema,
@@ -268,43 +492,263 @@ impl DisplayAs for ShuffleWriterExec {
     }
 }
 
-/// Stream data to disk in Arrow IPC format
+/// Schema of the batch returned by a shuffle writer task that writes to a shuffle storage
+fn map_task_summary_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("shuffle_repart_time", DataType::Int32, true),
+        Field::new("shuffle_write_time", DataType::Int32, true),
+        Field::new("num_rows", DataType::UInt64, false),
+    ]))
+}
+
+fn map_task_summary(repart_time: usize, write_time: usize, num_rows: u64) -> Result<RecordBatch> {
+    Ok(RecordBatch::try_new(
+        map_task_summary_schema(),
+        vec![
+            Arc::new(Int32Array::from(vec![repart_time as i32])),
+            Arc::new(Int32Array::from(vec![write_time as i32])),
+            Arc::new(UInt64Array::from(vec![num_rows])),
+        ],
+    )?)
+}
+
+/// Number of rows written by a shuffle writer task, from the batches it returned in any
+/// shuffle mode
+pub fn map_task_rows(batches: &[RecordBatch]) -> Option<usize> {
+    let schema = batches.first()?.schema();
+    if schema == map_output_location_schema() {
+        let outputs = decode_map_output_locations(batches).ok()?;
+        return Some(
+            outputs
+                .iter()
+                .flat_map(|output| &output.partitions)
+                .map(|p| p.num_rows as usize)
+                .sum(),
+        );
+    }
+    let index = schema.index_of("num_rows").ok()?;
+    let mut rows = 0;
+    for batch in batches {
+        let num_rows = batch.column(index).as_any().downcast_ref::<UInt64Array>()?;
+        rows += num_rows.values().iter().sum::<u64>() as usize;
+    }
+    Some(rows)
+}
+
+/// Path of a shuffle file written by one attempt of a shuffle writer task, relative to the
+/// shuffle storage
+fn shuffle_file_path(
+    stage_id: usize,
+    input_partition: usize,
+    output_partition: usize,
+    attempt_id: &str,
+) -> String {
+    format!("shuffle_{stage_id}_{input_partition}_{output_partition}.{attempt_id}.arrow")
+}
+
+/// The staging files of a map task attempt. Dropping it removes the files that are still
+/// there, which are those of an attempt that failed or was cancelled before storing them.
+#[derive(Debug, Default)]
+struct StagingFiles {
+    paths: Vec<String>,
+}
+
+impl StagingFiles {
+    fn add(&mut self, path: String) -> String {
+        self.paths.push(path.clone());
+        path
+    }
+}
+
+impl Drop for StagingFiles {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Create the directory of a local file, which may not exist yet on this node
+fn create_parent_dir(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// Writes the batches of a shuffle file in Arrow IPC format, computing the checksum of the
+/// file as it is written
+struct ShuffleFileWriter {
+    path: String,
+    writer: FileWriter<ChecksumWriter<BufWriter<File>>>,
+    num_batches: usize,
+    num_rows: usize,
+    num_bytes: usize,
+}
+
+impl ShuffleFileWriter {
+    fn try_new(path: String, schema: &Schema) -> Result<Self> {
+        create_parent_dir(Path::new(&path))?;
+        let file = ChecksumWriter::new(BufWriter::new(File::create(&path)?));
+        Ok(Self {
+            path,
+            writer: FileWriter::try_new(file, schema)?,
+            num_batches: 0,
+            num_rows: 0,
+            num_bytes: 0,
+        })
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        self.writer.write(batch)?;
+        self.num_batches += 1;
+        self.num_rows += batch.num_rows();
+        self.num_bytes += batch.get_array_memory_size();
+        Ok(())
+    }
+
+    /// Write the footer of the file and return the checksum of the file
+    fn finish(&mut self) -> Result<u32> {
+        self.writer.finish()?;
+        Ok(self.writer.get_ref().checksum())
+    }
+}
+
+/// Stream data to disk in Arrow IPC format, returning the statistics and the checksum of the
+/// file
 pub async fn write_stream_to_disk(
     stream: &mut Pin<Box<dyn RecordBatchStream + Send>>,
     path: &str,
     disk_write_metric: &metrics::Time,
-) -> Result<PartitionStats> {
-    let file = File::create(path).unwrap();
-
-    /*.map_err(|e| {
-        error!("Failed to create partition file at {}: {:?}", path, e);
-        BallistaError::IoError(e)
-    })?;*/
-
-    let mut num_rows = 0;
-    let mut num_batches = 0;
-    let mut num_bytes = 0;
-    let mut writer = FileWriter::try_new(file, stream.schema().as_ref())?;
+) -> Result<(PartitionStats, u32)> {
+    let mut writer = ShuffleFileWriter::try_new(path.to_string(), stream.schema().as_ref())?;
 
     while let Some(result) = stream.next().await {
         let batch = result?;
 
-        let batch_size_bytes: usize = batch.get_array_memory_size();
-        num_batches += 1;
-        num_rows += batch.num_rows();
-        num_bytes += batch_size_bytes;
-
         let timer = disk_write_metric.timer();
         writer.write(&batch)?;
         timer.done();
     }
     let timer = disk_write_metric.timer();
-    writer.finish()?;
+    let checksum = writer.finish()?;
     timer.done();
-    Ok(PartitionStats {
-        num_rows: num_rows as i64,
-        num_batches: num_batches as i64,
-        num_bytes: num_bytes as i64,
+    let stats = PartitionStats {
+        num_rows: writer.num_rows as i64,
+        num_batches: writer.num_batches as i64,
+        num_bytes: writer.num_bytes as i64,
         column_stats: vec![],
-    })
+    };
+    Ok((stats, checksum))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shuffle::read_map_output;
+    use crate::test_utils::{TempDir, TestResult};
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::col;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::prelude::SessionContext;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn commit_complete_files() -> TestResult<()> {
+        let sql = "SELECT column1 AS a FROM (VALUES (1), (2), (3), (4), (5))";
+        let (ctx, plan) = create_plan(sql)?;
+        let dir = TempDir::new()?;
+        let storage = Arc::new(LocalShuffleStorage::new(dir.path_str()));
+        let partitioning = Partitioning::Hash(vec![col("a", &plan.schema())?], 3);
+        let writer = ShuffleWriterExec::new(0, plan, partitioning, storage.clone());
+        let rt = Runtime::new()?;
+        rt.block_on(collect(writer.execute(0, ctx.task_ctx())?))?;
+
+        let output = rt
+            .block_on(read_map_output(storage.as_ref(), 0, 0))?
+            .expect("committed output");
+        assert_eq!(5, output.partitions.iter().map(|p| p.num_rows).sum::<u64>());
+        for partition in &output.partitions {
+            assert!(Path::new(&storage.path(&partition.path)).exists());
+        }
+        assert_eq!(output.partitions.len() + 1, list_files(&dir)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn coalesce_partition_batches() -> TestResult<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batches = (0..100)
+            .map(|i| {
+                let values = Int32Array::from((i * 10..i * 10 + 10).collect::<Vec<_>>());
+                RecordBatch::try_new(schema.clone(), vec![Arc::new(values)])
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let plan = Arc::new(MemoryExec::try_new(&[batches], schema.clone(), None)?);
+        let dir = TempDir::new()?;
+        let storage = Arc::new(LocalShuffleStorage::new(dir.path_str()));
+        let partitioning = Partitioning::Hash(vec![col("a", &schema)?], 4);
+        let writer = ShuffleWriterExec::new(0, plan, partitioning, storage.clone());
+        let ctx = SessionContext::new();
+        let rt = Runtime::new()?;
+        rt.block_on(collect(writer.execute(0, ctx.task_ctx())?))?;
+
+        // the slices of all input batches fit in a single batch per output partition
+        let output = rt
+            .block_on(read_map_output(storage.as_ref(), 0, 0))?
+            .expect("committed output");
+        assert_eq!(4, output.partitions.len());
+        assert!(output.partitions.iter().all(|p| p.num_batches == 1));
+        assert_eq!(
+            1000,
+            output.partitions.iter().map(|p| p.num_rows).sum::<u64>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn failed_task_commits_nothing() -> TestResult<()> {
+        let sql = "SELECT CAST(column1 AS INT) AS a FROM (VALUES ('1'), ('x'))";
+        let (ctx, plan) = create_plan(sql)?;
+        let dir = TempDir::new()?;
+        let storage = Arc::new(LocalShuffleStorage::new(dir.path_str()));
+        let partitioning = Partitioning::UnknownPartitioning(1);
+        let writer = ShuffleWriterExec::new(0, plan, partitioning, storage.clone());
+        let rt = Runtime::new()?;
+        let result = rt.block_on(collect(writer.execute(0, ctx.task_ctx())?));
+        assert!(result.is_err());
+
+        assert!(rt
+            .block_on(read_map_output(storage.as_ref(), 0, 0))?
+            .is_none());
+        assert!(list_files(&dir)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_round_robin_partitioning() -> TestResult<()> {
+        let sql = "SELECT column1 AS a FROM (VALUES (1), (2))";
+        let (ctx, plan) = create_plan(sql)?;
+        let dir = TempDir::new()?;
+        let storage = Arc::new(LocalShuffleStorage::new(dir.path_str()));
+        let partitioning = Partitioning::RoundRobinBatch(2);
+        let writer = ShuffleWriterExec::new(0, plan, partitioning, storage);
+        let rt = Runtime::new()?;
+        let result = rt.block_on(collect(writer.execute(0, ctx.task_ctx())?));
+        assert!(matches!(result, Err(DataFusionError::NotImplemented(_))));
+        Ok(())
+    }
+
+    fn create_plan(sql: &str) -> TestResult<(SessionContext, Arc<dyn ExecutionPlan>)> {
+        let ctx = SessionContext::new();
+        let plan =
+            Runtime::new()?.block_on(async { ctx.sql(sql).await?.create_physical_plan().await })?;
+        Ok((ctx, plan))
+    }
+
+    fn list_files(dir: &TempDir) -> TestResult<Vec<String>> {
+        Ok(std::fs::read_dir(dir.path())?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect::<std::io::Result<Vec<_>>>()?)
+    }
 }
@@ -0,0 +1,576 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Distributed writes for INSERT INTO and COPY TO.
+//!
+//! DataFusion writes files with a single [`DataSinkExec`] over the coalesced output of its
+//! input. [`plan_distributed_write`] replaces it with a [`SinkWriterExec`], which writes each
+//! input partition in a task of its own, and a [`SinkCommitExec`] in the final query stage.
+//! The writers stage their files under a `_temporary` directory of the output directory, and
+//! the files are only moved to the output directory once all writers have succeeded.
+
+use datafusion::arrow::array::{Array, StringArray, UInt64Array};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::common::Statistics;
+use datafusion::datasource::file_format::csv::CsvSink;
+use datafusion::datasource::file_format::json::JsonSink;
+use datafusion::datasource::file_format::parquet::ParquetSink;
+use datafusion::datasource::listing::ListingTableUrl;
+use datafusion::datasource::physical_plan::FileSinkConfig;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_expr::EquivalenceProperties;
+use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+use datafusion::physical_plan::insert::{DataSink, DataSinkExec};
+use datafusion::physical_plan::sorts::sort_preserving_merge::SortPreservingMergeExec;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    execute_input_stream, DisplayAs, DisplayFormatType, ExecutionMode, ExecutionPlan, Partitioning,
+    PlanProperties, SendableRecordBatchStream,
+};
+use futures::{StreamExt, TryStreamExt};
+use log::debug;
+use object_store::path::Path;
+use object_store::ObjectStore;
+use std::any::Any;
+use std::fmt::Formatter;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Schema of the batch returned by each writer task: the number of rows it wrote and the
+/// directory it staged its files in
+fn staged_output_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("count", DataType::UInt64, false),
+        Field::new("staging_url", DataType::Utf8, false),
+    ]))
+}
+
+/// Schema of the result of a write, the number of rows written
+fn count_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![Field::new(
+        "count",
+        DataType::UInt64,
+        false,
+    )]))
+}
+
+/// Split a plan writing files into one writer per partition of its input and a commit of the
+/// written files. Plans that do not write files are returned unchanged, so writes to other
+/// sinks, such as memory tables, run in the final query stage.
+pub fn plan_distributed_write(plan: Arc<dyn ExecutionPlan>) -> Result<Arc<dyn ExecutionPlan>> {
+    let Some(exec) = plan.as_any().downcast_ref::<DataSinkExec>() else {
+        return Ok(plan);
+    };
+    let Some(output) = file_sink_config(exec.sink()).and_then(|c| c.table_paths.first()) else {
+        return Ok(plan);
+    };
+    let target = output_directory(output)?;
+    let staging =
+        ListingTableUrl::parse(format!("{}_temporary/{}/", target.as_str(), Uuid::new_v4()))?;
+    let mut input = exec.input().clone();
+    // a directory is written by one writer per input partition, a single file by one writer
+    if output.is_collection()
+        && (input.as_any().is::<CoalescePartitionsExec>()
+            || input.as_any().is::<SortPreservingMergeExec>())
+    {
+        input = input.children()[0].clone();
+    }
+    let sink = with_output_url(exec.sink(), output.clone())?;
+    let writer = Arc::new(SinkWriterExec::try_new(input, sink, staging.clone())?);
+    Ok(Arc::new(SinkCommitExec::new(
+        Arc::new(CoalescePartitionsExec::new(writer)),
+        target,
+        staging,
+    )))
+}
+
+fn file_sink_config(sink: &dyn DataSink) -> Option<&FileSinkConfig> {
+    let sink = sink.as_any();
+    if let Some(sink) = sink.downcast_ref::<ParquetSink>() {
+        Some(sink.config())
+    } else if let Some(sink) = sink.downcast_ref::<CsvSink>() {
+        Some(sink.config())
+    } else {
+        sink.downcast_ref::<JsonSink>().map(|sink| sink.config())
+    }
+}
+
+/// Copy of a file sink that writes to `url`
+fn with_output_url(sink: &dyn DataSink, url: ListingTableUrl) -> Result<Arc<dyn DataSink>> {
+    let config = file_sink_config(sink)
+        .ok_or_else(|| DataFusionError::NotImplemented(format!("Distributed write to {sink:?}")))?;
+    let config = FileSinkConfig {
+        object_store_url: config.object_store_url.clone(),
+        file_groups: config.file_groups.clone(),
+        table_paths: vec![url],
+        output_schema: config.output_schema.clone(),
+        table_partition_cols: config.table_partition_cols.clone(),
+        overwrite: config.overwrite,
+        keep_partition_by_columns: config.keep_partition_by_columns,
+    };
+    let sink = sink.as_any();
+    Ok(if let Some(sink) = sink.downcast_ref::<ParquetSink>() {
+        Arc::new(ParquetSink::new(config, sink.parquet_options().clone()))
+    } else if let Some(sink) = sink.downcast_ref::<CsvSink>() {
+        Arc::new(CsvSink::new(config, sink.writer_options().clone()))
+    } else if let Some(sink) = sink.downcast_ref::<JsonSink>() {
+        Arc::new(JsonSink::new(config, sink.writer_options().clone()))
+    } else {
+        return Err(DataFusionError::Internal(format!(
+            "{sink:?} is not a file sink"
+        )));
+    })
+}
+
+/// The output URL of a file sink
+fn output_url(sink: &dyn DataSink) -> Result<&ListingTableUrl> {
+    file_sink_config(sink)
+        .and_then(|config| config.table_paths.first())
+        .ok_or_else(|| DataFusionError::Internal(format!("{sink:?} has no output path")))
+}
+
+/// The directory of an output URL, which is the URL itself unless it is a single file
+fn output_directory(url: &ListingTableUrl) -> Result<ListingTableUrl> {
+    if url.is_collection() {
+        return Ok(url.clone());
+    }
+    match url.as_str().rsplit_once('/') {
+        Some((dir, _)) => ListingTableUrl::parse(format!("{dir}/")),
+        None => Err(DataFusionError::Plan(format!(
+            "Invalid output path {}",
+            url.as_str()
+        ))),
+    }
+}
+
+/// Writes each partition of its input to a file sink, staging the files of every task in a
+/// new directory under `staging_url`. Returns one row per task with the number of rows
+/// written and the staging directory.
+#[derive(Debug)]
+pub struct SinkWriterExec {
+    input: Arc<dyn ExecutionPlan>,
+    sink: Arc<dyn DataSink>,
+    /// Directory under which the tasks create their staging directories
+    pub staging_url: ListingTableUrl,
+    properties: PlanProperties,
+}
+
+impl SinkWriterExec {
+    pub fn try_new(
+        input: Arc<dyn ExecutionPlan>,
+        sink: Arc<dyn DataSink>,
+        staging_url: ListingTableUrl,
+    ) -> Result<Self> {
+        // only file sinks can be written to staging directories
+        output_url(sink.as_ref())?;
+        let partitions = input.properties().output_partitioning().partition_count();
+        let properties = PlanProperties::new(
+            EquivalenceProperties::new(staged_output_schema()),
+            Partitioning::UnknownPartitioning(partitions),
+            ExecutionMode::Bounded,
+        );
+        Ok(Self {
+            input,
+            sink,
+            staging_url,
+            properties,
+        })
+    }
+
+    pub fn sink(&self) -> &Arc<dyn DataSink> {
+        &self.sink
+    }
+}
+
+impl ExecutionPlan for SinkWriterExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        staged_output_schema()
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn ExecutionPlan>> {
+        vec![&self.input]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(Arc::new(SinkWriterExec::try_new(
+            children[0].clone(),
+            self.sink.clone(),
+            self.staging_url.clone(),
+        )?))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        // each attempt of a task stages its files in a directory of its own, so that the files
+        // of failed attempts are never committed
+        let staging =
+            ListingTableUrl::parse(format!("{}{}/", self.staging_url.as_str(), Uuid::new_v4()))?;
+        let output = output_url(self.sink.as_ref())?;
+        let url = match output.prefix().filename() {
+            Some(file_name) if !output.is_collection() => {
+                ListingTableUrl::parse(format!("{}{file_name}", staging.as_str()))?
+            }
+            _ => staging.clone(),
+        };
+        debug!(
+            "SinkWriterExec writing partition {} to {}",
+            partition,
+            url.as_str()
+        );
+        let sink = with_output_url(self.sink.as_ref(), url)?;
+        let sink_schema = file_sink_config(sink.as_ref())
+            .map(|config| config.output_schema.clone())
+            .unwrap_or_else(|| self.input.schema());
+        let data =
+            execute_input_stream(self.input.clone(), sink_schema, partition, context.clone())?;
+        let schema = self.schema();
+        let stream = futures::stream::once(async move {
+            let count = sink.write_all(data, &context).await?;
+            Ok(RecordBatch::try_new(
+                schema,
+                vec![
+                    Arc::new(UInt64Array::from(vec![count])),
+                    Arc::new(StringArray::from(vec![staging.as_str()])),
+                ],
+            )?)
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            stream,
+        )))
+    }
+
+    fn statistics(&self) -> Result<Statistics> {
+        Ok(Statistics::new_unknown(&self.schema()))
+    }
+
+    fn name(&self) -> &str {
+        "SinkWriterExec"
+    }
+
+    fn properties(&self) -> &PlanProperties {
+        &self.properties
+    }
+}
+
+impl DisplayAs for SinkWriterExec {
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "SinkWriterExec: sink=")?;
+        self.sink.fmt_as(t, f)?;
+        write!(f, ", staging_url={}", self.staging_url.as_str())
+    }
+}
+
+/// Moves the files staged by the [`SinkWriterExec`] tasks of a write to the output directory
+/// and returns the total number of rows written.
+///
+/// Nothing is moved unless all writers succeeded, but object stores cannot rename several
+/// files at once, so readers may see the files of a write appear one after the other.
+#[derive(Debug)]
+pub struct SinkCommitExec {
+    input: Arc<dyn ExecutionPlan>,
+    /// Directory the files are moved to
+    pub target_url: ListingTableUrl,
+    /// Directory under which the writers staged their files
+    pub staging_url: ListingTableUrl,
+    properties: PlanProperties,
+}
+
+impl SinkCommitExec {
+    pub fn new(
+        input: Arc<dyn ExecutionPlan>,
+        target_url: ListingTableUrl,
+        staging_url: ListingTableUrl,
+    ) -> Self {
+        let properties = PlanProperties::new(
+            EquivalenceProperties::new(count_schema()),
+            Partitioning::UnknownPartitioning(1),
+            ExecutionMode::Bounded,
+        );
+        Self {
+            input,
+            target_url,
+            staging_url,
+            properties,
+        }
+    }
+
+    /// Read the output of the writers and move their files to the output directory
+    async fn commit(
+        input: Arc<dyn ExecutionPlan>,
+        target: ListingTableUrl,
+        staging: ListingTableUrl,
+        context: Arc<TaskContext>,
+    ) -> Result<u64> {
+        let mut written = vec![];
+        for partition in 0..input.properties().output_partitioning().partition_count() {
+            let batches: Vec<RecordBatch> = input
+                .execute(partition, context.clone())?
+                .try_collect()
+                .await?;
+            written.extend(batches);
+        }
+        let store = context.runtime_env().object_store(&target)?;
+        let mut count = 0;
+        for batch in &written {
+            let counts = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<UInt64Array>()
+                .ok_or_else(|| unexpected_output("count"))?;
+            let dirs = batch
+                .column(1)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .ok_or_else(|| unexpected_output("staging_url"))?;
+            count += counts.values().iter().sum::<u64>();
+            for dir in dirs.iter().flatten() {
+                let dir = ListingTableUrl::parse(dir)?;
+                move_files(store.as_ref(), dir.prefix(), target.prefix()).await?;
+            }
+        }
+        // the staging directories of failed or speculative attempts
+        let orphans = store
+            .list(Some(staging.prefix()))
+            .map_ok(|meta| meta.location)
+            .boxed();
+        store.delete_stream(orphans).try_collect::<Vec<_>>().await?;
+        // the local filesystem keeps the empty staging directories
+        if let Ok(dir) = AsRef::<url::Url>::as_ref(&staging).to_file_path() {
+            let _ = std::fs::remove_dir_all(&dir);
+            if let Some(parent) = dir.parent() {
+                let _ = std::fs::remove_dir(parent);
+            }
+        }
+        Ok(count)
+    }
+}
+
+fn unexpected_output(column: &str) -> DataFusionError {
+    DataFusionError::Internal(format!(
+        "Unexpected type for column {column} of the output of the sink writers"
+    ))
+}
+
+/// Move the files under `from` to the same relative paths under `to`
+async fn move_files(store: &dyn ObjectStore, from: &Path, to: &Path) -> Result<()> {
+    let files: Vec<_> = store.list(Some(from)).try_collect().await?;
+    for file in files {
+        let Some(parts) = file.location.prefix_match(from) else {
+            continue;
+        };
+        let target = parts.fold(to.clone(), |path, part| path.child(part));
+        debug!("Committing {} to {}", file.location, target);
+        store.rename(&file.location, &target).await?;
+    }
+    Ok(())
+}
+
+impl ExecutionPlan for SinkCommitExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        count_schema()
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn ExecutionPlan>> {
+        vec![&self.input]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(Arc::new(SinkCommitExec::new(
+            children[0].clone(),
+            self.target_url.clone(),
+            self.staging_url.clone(),
+        )))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        if partition != 0 {
+            return Err(DataFusionError::Internal(format!(
+                "SinkCommitExec has a single partition, got {partition}"
+            )));
+        }
+        let commit = SinkCommitExec::commit(
+            self.input.clone(),
+            self.target_url.clone(),
+            self.staging_url.clone(),
+            context,
+        );
+        let stream = futures::stream::once(async move {
+            let count = commit.await?;
+            Ok(RecordBatch::try_new(
+                count_schema(),
+                vec![Arc::new(UInt64Array::from(vec![count]))],
+            )?)
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            count_schema(),
+            stream,
+        )))
+    }
+
+    fn statistics(&self) -> Result<Statistics> {
+        Ok(Statistics::new_unknown(&self.schema()))
+    }
+
+    fn name(&self) -> &str {
+        "SinkCommitExec"
+    }
+
+    fn properties(&self) -> &PlanProperties {
+        &self.properties
+    }
+}
+
+impl DisplayAs for SinkCommitExec {
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "SinkCommitExec: target_url={}, staging_url={}",
+            self.target_url.as_str(),
+            self.staging_url.as_str()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::distributed::DistributedContext;
+    use crate::table::{TableDefinition, TableFormat};
+    use crate::test_utils::{roundtrip_execution_graph, TempDir, TestResult};
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use datafusion::datasource::MemTable;
+    use datafusion::prelude::{SessionConfig, SessionContext};
+    use std::path::Path;
+    use std::sync::Arc;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn copy_and_insert_with_parallel_writers() -> TestResult<()> {
+        let temp_dir = TempDir::new()?;
+        let dir = temp_dir.path();
+        let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(3));
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let partitions = (0..3)
+            .map(|p| {
+                let values = (0..100).map(|i| i * 3 + p).collect::<Vec<i64>>();
+                RecordBatch::try_new(schema.clone(), vec![Arc::new(Int64Array::from(values))])
+                    .map(|batch| vec![batch])
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        ctx.register_table("t", Arc::new(MemTable::try_new(schema, partitions)?))?;
+        let shuffle_dir = TempDir::new()?;
+        let ctx = DistributedContext::new(ctx).with_shuffle_storage(Some(shuffle_dir.path_str()));
+        let rt = Runtime::new()?;
+
+        // COPY TO a directory
+        let copy_dir = dir.join("copy");
+        let sql = format!(
+            "COPY (SELECT a FROM t WHERE a % 2 = 0) TO '{}/' STORED AS PARQUET",
+            copy_dir.display()
+        );
+        let graph = rt.block_on(ctx.plan_sql(&sql))?;
+        let writers = graph
+            .query_stages
+            .values()
+            .find(|stage| format!("{:?}", stage.plan).contains("SinkWriterExec"))
+            .expect("a stage of writers");
+        assert_eq!(3, writers.get_task_count());
+        let count = rt.block_on(ctx.sql(&sql))?;
+        assert_eq!(
+            "+-------+\n\
+             | count |\n\
+             +-------+\n\
+             | 150   |\n\
+             +-------+",
+            pretty_format_batches(&count)?.to_string()
+        );
+        assert_eq!(3, data_files(&copy_dir)?);
+        assert!(!copy_dir.join("_temporary").exists());
+
+        // INSERT INTO a listing table, whose stages can be sent through the codec
+        let table = TableDefinition::new(
+            "copied",
+            TableFormat::Parquet,
+            vec![format!("{}/", copy_dir.display())],
+        );
+        rt.block_on(table.register(ctx.session_context()))?;
+        let table_dir = dir.join("table");
+        std::fs::create_dir_all(&table_dir)?;
+        let table = TableDefinition::new(
+            "csv",
+            TableFormat::Csv,
+            vec![format!("{}/", table_dir.display())],
+        )
+        .with_schema(Some(Arc::new(Schema::new(vec![Field::new(
+            "a",
+            DataType::Int64,
+            true,
+        )]))));
+        rt.block_on(table.register(ctx.session_context()))?;
+        for sql in [
+            "INSERT INTO csv SELECT a FROM copied",
+            "INSERT INTO csv SELECT a FROM copied WHERE a < 20",
+        ] {
+            roundtrip_execution_graph(&rt.block_on(ctx.plan_sql(sql))?)?;
+            rt.block_on(ctx.sql(sql))?;
+        }
+        assert!(!table_dir.join("_temporary").exists());
+        let results = rt.block_on(ctx.sql("SELECT count(*) AS c, sum(a) AS s FROM csv"))?;
+        assert_eq!(
+            "+-----+-------+\n\
+             | c   | s     |\n\
+             +-----+-------+\n\
+             | 160 | 22440 |\n\
+             +-----+-------+",
+            pretty_format_batches(&results)?.to_string()
+        );
+        Ok(())
+    }
+
+    /// Number of files in a directory
+    fn data_files(dir: &Path) -> TestResult<usize> {
+        Ok(std::fs::read_dir(dir)?
+            .filter(|entry| entry.as_ref().map_or(false, |e| e.path().is_file()))
+            .count())
+    }
+}
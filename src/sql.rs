@@ -0,0 +1,330 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use datafusion::error::{DataFusionError, Result};
+use datafusion::sql::parser::{DFParser, Statement};
+use datafusion::sql::sqlparser::ast::Statement as SQLStatement;
+use datafusion::sql::sqlparser::dialect::{dialect_from_str, Dialect};
+use datafusion::sql::sqlparser::keywords::Keyword;
+use datafusion::sql::sqlparser::parser::ParserError;
+use datafusion::sql::sqlparser::tokenizer::{Token, Tokenizer, Word};
+use pyo3::prelude::*;
+
+/// A statement of a SQL script, classified by how it is run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SqlStatement {
+    /// A query, which is planned into query stages and run on the workers
+    Query(String),
+    /// EXPLAIN of a statement. Unless it is EXPLAIN ANALYZE, the EXPLAIN of a query or a write
+    /// returns the distributed plan.
+    Explain {
+        sql: String,
+        analyze: bool,
+        verbose: bool,
+        statement: Box<SqlStatement>,
+    },
+    /// A statement that changes the catalog of the session, such as CREATE VIEW or DROP TABLE,
+    /// which runs locally
+    Ddl(String),
+    /// A statement that writes data, such as INSERT INTO or COPY TO, which is planned into
+    /// query stages like a query
+    Dml(String),
+    /// Any other statement, such as SET, SHOW or DESCRIBE, which reads or changes the state of
+    /// the session and runs locally
+    Session(String),
+}
+
+impl SqlStatement {
+    /// The SQL of the statement, without the terminating semicolon
+    pub fn sql(&self) -> &str {
+        match self {
+            SqlStatement::Query(sql)
+            | SqlStatement::Explain { sql, .. }
+            | SqlStatement::Ddl(sql)
+            | SqlStatement::Dml(sql)
+            | SqlStatement::Session(sql) => sql,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SqlStatement::Query(_) => "query",
+            SqlStatement::Explain { .. } => "explain",
+            SqlStatement::Ddl(_) => "ddl",
+            SqlStatement::Dml(_) => "dml",
+            SqlStatement::Session(_) => "session",
+        }
+    }
+
+    /// Whether this is the EXPLAIN of a query or a write that returns the distributed plan
+    pub fn is_distributed_explain(&self) -> bool {
+        matches!(
+            self,
+            SqlStatement::Explain {
+                analyze: false,
+                statement,
+                ..
+            } if matches!(**statement, SqlStatement::Query(_) | SqlStatement::Dml(_))
+        )
+    }
+}
+
+/// Split a SQL script into statements and classify them. `dialect` is the name of a SQL
+/// dialect supported by DataFusion, such as "generic" or "postgresql". The SQL of each statement
+/// is its text in the script, because the SQL that sqlparser prints for some statements, such as
+/// COPY TO and CREATE EXTERNAL TABLE, does not parse again.
+pub fn parse_sql(sql: &str, dialect: &str) -> Result<Vec<SqlStatement>> {
+    let dialect = dialect_from_str(dialect).ok_or_else(|| {
+        DataFusionError::Configuration(format!("Unsupported SQL dialect: {dialect}"))
+    })?;
+    split_statements(sql, dialect.as_ref())?
+        .into_iter()
+        .map(|sql| {
+            let mut statements = DFParser::parse_sql_with_dialect(sql, dialect.as_ref())?;
+            match (statements.pop_front(), statements.is_empty()) {
+                (Some(statement), true) => classify(statement, sql, dialect.as_ref()),
+                _ => Err(DataFusionError::Internal(format!(
+                    "Expected a single statement in {sql:?}"
+                ))),
+            }
+        })
+        .collect()
+}
+
+/// The tokens of `sql` with their byte offsets
+fn tokenize(sql: &str, dialect: &dyn Dialect) -> Result<Vec<(usize, Token)>> {
+    let tokens = Tokenizer::new(dialect, sql)
+        .tokenize_with_location()
+        .map_err(ParserError::from)?;
+    // tokens are located by their line and column, counted in characters from 1
+    let mut line_starts = vec![0];
+    line_starts.extend(sql.match_indices('\n').map(|(i, _)| i + 1));
+    Ok(tokens
+        .into_iter()
+        .map(|token| {
+            let line_start = line_starts[token.location.line as usize - 1];
+            let offset = sql[line_start..]
+                .char_indices()
+                .nth(token.location.column as usize - 1)
+                .map_or(sql.len(), |(i, _)| line_start + i);
+            (offset, token.token)
+        })
+        .collect())
+}
+
+/// Split a SQL script into the text of its statements, on the semicolons that are not part of
+/// a string literal, a quoted identifier or a comment
+fn split_statements<'a>(sql: &'a str, dialect: &dyn Dialect) -> Result<Vec<&'a str>> {
+    let tokens = tokenize(sql, dialect)?;
+    let mut statements = vec![];
+    // the byte range of the statement from its first to its last token that is not whitespace
+    let mut range: Option<(usize, usize)> = None;
+    for (i, (offset, token)) in tokens.iter().enumerate() {
+        match token {
+            Token::Whitespace(_) => {}
+            Token::SemiColon => {
+                statements.extend(range.take().map(|(start, end)| &sql[start..end]))
+            }
+            _ => {
+                let end = tokens.get(i + 1).map_or(sql.len(), |(next, _)| *next);
+                range = Some((range.map_or(*offset, |(start, _)| start), end));
+            }
+        }
+    }
+    statements.extend(range.map(|(start, end)| &sql[start..end]));
+    Ok(statements)
+}
+
+/// The text of the statement that an EXPLAIN explains, after its `EXPLAIN [ANALYZE] [VERBOSE]`
+fn explained_sql<'a>(sql: &'a str, dialect: &dyn Dialect) -> Result<&'a str> {
+    let start = tokenize(sql, dialect)?
+        .into_iter()
+        .find(|(_, token)| {
+            !matches!(
+                token,
+                Token::Whitespace(_)
+                    | Token::Word(Word {
+                        keyword: Keyword::EXPLAIN | Keyword::ANALYZE | Keyword::VERBOSE,
+                        ..
+                    })
+            )
+        })
+        .map_or(sql.len(), |(offset, _)| offset);
+    Ok(&sql[start..])
+}
+
+fn classify(statement: Statement, sql: &str, dialect: &dyn Dialect) -> Result<SqlStatement> {
+    let text = sql.to_string();
+    Ok(match statement {
+        Statement::Statement(statement) => match *statement {
+            SQLStatement::Query(_) => SqlStatement::Query(text),
+            SQLStatement::Insert(_)
+            | SQLStatement::Copy { .. }
+            | SQLStatement::Update { .. }
+            | SQLStatement::Delete(_)
+            | SQLStatement::Merge { .. } => SqlStatement::Dml(text),
+            SQLStatement::CreateView { .. }
+            | SQLStatement::CreateTable(_)
+            | SQLStatement::CreateIndex(_)
+            | SQLStatement::CreateSchema { .. }
+            | SQLStatement::CreateDatabase { .. }
+            | SQLStatement::CreateFunction { .. }
+            | SQLStatement::AlterTable { .. }
+            | SQLStatement::AlterView { .. }
+            | SQLStatement::Drop { .. }
+            | SQLStatement::DropFunction { .. }
+            | SQLStatement::Truncate { .. } => SqlStatement::Ddl(text),
+            _ => SqlStatement::Session(text),
+        },
+        Statement::CreateExternalTable(_) => SqlStatement::Ddl(text),
+        Statement::CopyTo(_) => SqlStatement::Dml(text),
+        Statement::Explain(explain) => SqlStatement::Explain {
+            sql: text,
+            analyze: explain.analyze,
+            verbose: explain.verbose,
+            statement: Box::new(classify(
+                *explain.statement,
+                explained_sql(sql, dialect)?,
+                dialect,
+            )?),
+        },
+    })
+}
+
+/// A statement of a SQL script, as returned by `Context.parse_sql`
+#[pyclass(name = "SqlStatement", module = "datafusion_ray", subclass)]
+#[derive(Clone)]
+pub struct PySqlStatement {
+    statement: SqlStatement,
+}
+
+impl PySqlStatement {
+    pub fn new(statement: SqlStatement) -> Self {
+        Self { statement }
+    }
+}
+
+#[pymethods]
+impl PySqlStatement {
+    /// "query", "explain", "ddl", "dml" or "session"
+    #[getter]
+    pub fn kind(&self) -> &'static str {
+        self.statement.kind()
+    }
+
+    #[getter]
+    pub fn sql(&self) -> String {
+        self.statement.sql().to_string()
+    }
+
+    /// Whether this is an EXPLAIN of a query or a write that returns the distributed plan
+    #[getter]
+    pub fn distributed_explain(&self) -> bool {
+        self.statement.is_distributed_explain()
+    }
+
+    /// Whether this is an EXPLAIN VERBOSE
+    #[getter]
+    pub fn verbose(&self) -> bool {
+        matches!(self.statement, SqlStatement::Explain { verbose: true, .. })
+    }
+
+    /// The statement that an EXPLAIN explains
+    #[getter]
+    pub fn explained(&self) -> Option<PySqlStatement> {
+        match &self.statement {
+            SqlStatement::Explain { statement, .. } => Some(Self::new(*statement.clone())),
+            _ => None,
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("SqlStatement({}, {:?})", self.kind(), self.statement.sql())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::TestResult;
+
+    #[test]
+    fn classify_script() -> TestResult<()> {
+        let sql = "CREATE VIEW v AS SELECT 1 AS a; \
+                   set datafusion.execution.batch_size = 1024;\n\
+                   SELECT a FROM v;\n\
+                   EXPLAIN VERBOSE SELECT a FROM v;\n\
+                   EXPLAIN ANALYZE SELECT a FROM v;\n\
+                   INSERT INTO t VALUES (1);\n\
+                   SHOW TABLES;\n\
+                   DROP VIEW v;";
+        let statements = parse_sql(sql, "generic")?;
+        let kinds = statements.iter().map(|s| s.kind()).collect::<Vec<_>>();
+        assert_eq!(
+            vec!["ddl", "session", "query", "explain", "explain", "dml", "session", "ddl"],
+            kinds
+        );
+        assert_eq!("SELECT a FROM v", statements[2].sql());
+        assert!(statements[3].is_distributed_explain());
+        assert!(!statements[4].is_distributed_explain());
+        match &statements[3] {
+            SqlStatement::Explain {
+                verbose, statement, ..
+            } => {
+                assert!(verbose);
+                assert_eq!(
+                    SqlStatement::Query("SELECT a FROM v".to_string()),
+                    **statement
+                );
+            }
+            other => panic!("unexpected statement {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keep_statement_text() -> TestResult<()> {
+        let copy = "COPY (SELECT 'a;b' AS a) TO '/tmp/out/' STORED AS PARQUET";
+        let create = "CREATE EXTERNAL TABLE t (a INT)\n\
+                      STORED AS CSV LOCATION '/tmp/t.csv'\n\
+                      OPTIONS ('format.has_header' 'true')";
+        let explain = format!("EXPLAIN ANALYZE VERBOSE {copy}");
+        let sql = format!("{copy};\n-- a comment; with a semicolon\n{create} ;\n{explain};");
+        let statements = parse_sql(&sql, "generic")?;
+        assert_eq!(
+            vec![copy, create, explain.as_str()],
+            statements.iter().map(|s| s.sql()).collect::<Vec<_>>()
+        );
+        match &statements[2] {
+            SqlStatement::Explain { statement, .. } => {
+                assert_eq!(SqlStatement::Dml(copy.to_string()), **statement)
+            }
+            other => panic!("unexpected statement {other:?}"),
+        }
+        // the text of each statement parses again
+        for statement in &statements {
+            assert_eq!(1, parse_sql(statement.sql(), "generic")?.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_sql() {
+        assert!(parse_sql("SELEC 1", "generic").is_err());
+        assert!(parse_sql("SELECT 1", "no-such-dialect").is_err());
+    }
+}
@@ -0,0 +1,302 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use datafusion::arrow::datatypes::{DataType, SchemaRef};
+use datafusion::datasource::file_format::arrow::ArrowFormat;
+use datafusion::datasource::file_format::avro::AvroFormat;
+use datafusion::datasource::file_format::csv::CsvFormat;
+use datafusion::datasource::file_format::json::JsonFormat;
+use datafusion::datasource::file_format::parquet::ParquetFormat;
+use datafusion::datasource::file_format::FileFormat;
+use datafusion::datasource::listing::{
+    ListingOptions, ListingTable, ListingTableConfig, ListingTableUrl,
+};
+use datafusion::error::{DataFusionError, Result};
+use datafusion::prelude::SessionContext;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// File format of a listing table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    Parquet,
+    Csv,
+    NdJson,
+    Avro,
+    Arrow,
+}
+
+impl TableFormat {
+    /// Extension of the files of the table, unless the table sets another one
+    pub fn default_file_extension(&self) -> &'static str {
+        match self {
+            TableFormat::Parquet => ".parquet",
+            TableFormat::Csv => ".csv",
+            TableFormat::NdJson => ".json",
+            TableFormat::Avro => ".avro",
+            TableFormat::Arrow => ".arrow",
+        }
+    }
+}
+
+impl FromStr for TableFormat {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "parquet" => Ok(TableFormat::Parquet),
+            "csv" => Ok(TableFormat::Csv),
+            "json" | "ndjson" => Ok(TableFormat::NdJson),
+            "avro" => Ok(TableFormat::Avro),
+            "arrow" | "ipc" => Ok(TableFormat::Arrow),
+            other => Err(DataFusionError::Configuration(format!(
+                "Invalid table format '{other}', expected 'parquet', 'csv', 'json', 'avro' \
+                 or 'arrow'"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for TableFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TableFormat::Parquet => "parquet",
+            TableFormat::Csv => "csv",
+            TableFormat::NdJson => "json",
+            TableFormat::Avro => "avro",
+            TableFormat::Arrow => "arrow",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Definition of a listing table over files in one or more directories or object store
+/// prefixes, optionally partitioned hive-style into `column=value` directories.
+///
+/// The scans of the table are encoded with the plans of the query stages, so the workers
+/// read the same files without registering the table.
+#[derive(Debug, Clone)]
+pub struct TableDefinition {
+    pub name: String,
+    pub format: TableFormat,
+    pub paths: Vec<String>,
+    /// Columns whose values are taken from the `column=value` directories of the paths
+    pub partition_cols: Vec<(String, DataType)>,
+    /// Schema of the files, inferred from the first path if not set
+    pub schema: Option<SchemaRef>,
+    pub file_extension: Option<String>,
+    /// Whether CSV files start with a header row
+    pub has_header: bool,
+    /// Delimiter of CSV files
+    pub delimiter: u8,
+}
+
+impl TableDefinition {
+    pub fn new(name: &str, format: TableFormat, paths: Vec<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            format,
+            paths,
+            partition_cols: vec![],
+            schema: None,
+            file_extension: None,
+            has_header: true,
+            delimiter: b',',
+        }
+    }
+
+    pub fn with_partition_cols(mut self, partition_cols: Vec<(String, DataType)>) -> Self {
+        self.partition_cols = partition_cols;
+        self
+    }
+
+    pub fn with_schema(mut self, schema: Option<SchemaRef>) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    pub fn with_file_extension(mut self, file_extension: Option<&str>) -> Self {
+        self.file_extension = file_extension.map(str::to_string);
+        self
+    }
+
+    pub fn with_has_header(mut self, has_header: bool) -> Self {
+        self.has_header = has_header;
+        self
+    }
+
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    fn file_format(&self) -> Arc<dyn FileFormat> {
+        match self.format {
+            TableFormat::Parquet => Arc::new(ParquetFormat::default()),
+            TableFormat::Csv => Arc::new(
+                CsvFormat::default()
+                    .with_has_header(self.has_header)
+                    .with_delimiter(self.delimiter),
+            ),
+            TableFormat::NdJson => Arc::new(JsonFormat::default()),
+            TableFormat::Avro => Arc::new(AvroFormat),
+            TableFormat::Arrow => Arc::new(ArrowFormat),
+        }
+    }
+
+    /// Create the listing table, inferring its schema if it is not set
+    pub async fn create_table(&self, ctx: &SessionContext) -> Result<ListingTable> {
+        if self.paths.is_empty() {
+            return Err(DataFusionError::Configuration(format!(
+                "Table {} has no paths",
+                self.name
+            )));
+        }
+        let urls = self
+            .paths
+            .iter()
+            .map(ListingTableUrl::parse)
+            .collect::<Result<Vec<_>>>()?;
+        let file_extension = self
+            .file_extension
+            .as_deref()
+            .unwrap_or(self.format.default_file_extension());
+        let options = ListingOptions::new(self.file_format())
+            .with_file_extension(file_extension)
+            .with_table_partition_cols(self.partition_cols.clone());
+        let config = ListingTableConfig::new_with_multi_paths(urls).with_listing_options(options);
+        let config = match &self.schema {
+            Some(schema) => config.with_schema(schema.clone()),
+            None => config.infer_schema(&ctx.state()).await?,
+        };
+        ListingTable::try_new(config)
+    }
+
+    /// Register the table with a session, replacing any table of the same name
+    pub async fn register(&self, ctx: &SessionContext) -> Result<()> {
+        let table = self.create_table(ctx).await?;
+        ctx.deregister_table(self.name.as_str())?;
+        ctx.register_table(self.name.as_str(), Arc::new(table))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shuffle::ShuffleCodec;
+    use crate::test_utils::{TempDir, TestResult};
+    use datafusion::arrow::array::{Int64Array, RecordBatch};
+    use datafusion::arrow::datatypes::{Field, Schema};
+    use datafusion::arrow::ipc::writer::FileWriter;
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use datafusion::physical_plan::collect;
+    use datafusion_proto::bytes::{
+        physical_plan_from_bytes_with_extension_codec, physical_plan_to_bytes_with_extension_codec,
+    };
+    use std::path::Path;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn register_partitioned_tables() -> TestResult<()> {
+        let temp_dir = TempDir::new()?;
+        let dir = temp_dir.path();
+        write_files(dir)?;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, true),
+            Field::new("b", DataType::Int64, true),
+        ]));
+        let paths = ["x", "y"]
+            .iter()
+            .map(|d| dir.join(d).to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        let ctx = SessionContext::new();
+        let rt = Runtime::new()?;
+        for format in [TableFormat::Csv, TableFormat::NdJson, TableFormat::Arrow] {
+            let table = TableDefinition::new("t", format, paths.clone())
+                .with_partition_cols(vec![("p".to_string(), DataType::Int32)])
+                .with_schema(Some(schema.clone()));
+            rt.block_on(table.register(&ctx))?;
+
+            let sql = "SELECT p, sum(a) AS a, sum(b) AS b FROM t GROUP BY p ORDER BY p";
+            let batches = rt.block_on(async {
+                let plan = ctx.sql(sql).await?.create_physical_plan().await?;
+                // the scans are decoded by workers that do not know about the table
+                let codec = ShuffleCodec {};
+                let bytes = physical_plan_to_bytes_with_extension_codec(plan, &codec)?;
+                let plan = physical_plan_from_bytes_with_extension_codec(
+                    &bytes,
+                    &SessionContext::new(),
+                    &codec,
+                )?;
+                collect(plan, ctx.task_ctx()).await
+            })?;
+            assert_eq!(
+                "+---+----+-----+\n\
+                 | p | a  | b   |\n\
+                 +---+----+-----+\n\
+                 | 1 | 3  | 30  |\n\
+                 | 2 | 10 | 100 |\n\
+                 +---+----+-----+",
+                pretty_format_batches(&batches)?.to_string(),
+                "{format}"
+            );
+        }
+        Ok(())
+    }
+
+    /// Write the values 1 to 4 into files in `x/p=1`, `x/p=2` and `y/p=2`, in each format
+    fn write_files(dir: &Path) -> TestResult<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, false),
+            Field::new("b", DataType::Int64, false),
+        ]));
+        for (subdir, values) in [
+            ("x/p=1", vec![1, 2]),
+            ("x/p=2", vec![3]),
+            ("y/p=2", vec![7]),
+        ] {
+            let path = dir.join(subdir);
+            std::fs::create_dir_all(&path)?;
+            let csv = values
+                .iter()
+                .map(|a| format!("{a},{}\n", a * 10))
+                .collect::<String>();
+            std::fs::write(path.join("data.csv"), format!("a,b\n{csv}"))?;
+            let json = values
+                .iter()
+                .map(|a| format!("{{\"a\": {a}, \"b\": {}}}\n", a * 10))
+                .collect::<String>();
+            std::fs::write(path.join("data.json"), json)?;
+            let batch = RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(Int64Array::from(values.clone())),
+                    Arc::new(Int64Array::from(
+                        values.iter().map(|a| a * 10).collect::<Vec<_>>(),
+                    )),
+                ],
+            )?;
+            let file = std::fs::File::create(path.join("data.arrow"))?;
+            let mut writer = FileWriter::try_new(file, &schema)?;
+            writer.write(&batch)?;
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,766 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Utilities for tests that need TPC-H style data without a `TPCH_DATA_PATH`.
+//!
+//! The tables are generated with a fixed seed, so every run sees the same data. The value
+//! domains follow the TPC-H specification closely enough for the queries in
+//! `testdata/queries` to match rows.
+
+use crate::executor::LocalExecutor;
+use crate::planner::{make_execution_graph_with_shuffle_mode, ExecutionGraph};
+use crate::query_stage::QueryStage;
+use crate::shuffle::{ShuffleCodec, ShuffleMode, ShuffleWriterExec};
+use datafusion::arrow::array::{
+    ArrayRef, Date32Array, Decimal128Array, Int32Array, Int64Array, StringArray,
+};
+use datafusion::arrow::compute::concat_batches;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::display::array_value_to_string;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::parquet::arrow::ArrowWriter;
+use datafusion::physical_plan::ExecutionPlan;
+use datafusion::prelude::{ParquetReadOptions, SessionConfig, SessionContext};
+use datafusion_proto::bytes::{
+    physical_plan_from_bytes_with_extension_codec, physical_plan_to_bytes_with_extension_codec,
+};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use tokio::runtime::Builder;
+use uuid::Uuid;
+
+pub const TPCH_TABLES: &[&str] = &[
+    "customer", "lineitem", "nation", "orders", "part", "partsupp", "region", "supplier",
+];
+
+/// Number of parquet files written per table, so that scans have several file groups
+const FILES_PER_TABLE: usize = 3;
+
+const SUPPLIERS: i64 = 40;
+const PARTS: i64 = 400;
+const CUSTOMERS: i64 = 300;
+const ORDERS: i64 = 3000;
+
+/// 1992-01-01, the first order date
+const START_DATE: i32 = 8035;
+/// 1998-08-02, the last order date
+const END_DATE: i32 = 10440;
+/// 1995-06-17, the date used to derive line status and return flags
+const CURRENT_DATE: i32 = 9298;
+
+const REGIONS: &[&str] = &["AFRICA", "AMERICA", "ASIA", "EUROPE", "MIDDLE EAST"];
+const NATIONS: &[(&str, i64)] = &[
+    ("ALGERIA", 0),
+    ("ARGENTINA", 1),
+    ("BRAZIL", 1),
+    ("CANADA", 1),
+    ("EGYPT", 4),
+    ("ETHIOPIA", 0),
+    ("FRANCE", 3),
+    ("GERMANY", 3),
+    ("INDIA", 2),
+    ("INDONESIA", 2),
+    ("IRAN", 4),
+    ("IRAQ", 4),
+    ("JAPAN", 2),
+    ("JORDAN", 4),
+    ("KENYA", 0),
+    ("MOROCCO", 0),
+    ("MOZAMBIQUE", 0),
+    ("PERU", 1),
+    ("CHINA", 2),
+    ("ROMANIA", 3),
+    ("SAUDI ARABIA", 4),
+    ("VIETNAM", 2),
+    ("RUSSIA", 3),
+    ("UNITED KINGDOM", 3),
+    ("UNITED STATES", 1),
+];
+const COLORS: &[&str] = &[
+    "almond",
+    "antique",
+    "aquamarine",
+    "azure",
+    "beige",
+    "bisque",
+    "black",
+    "blanched",
+    "blue",
+    "blush",
+    "brown",
+    "burlywood",
+    "chartreuse",
+    "chocolate",
+    "coral",
+    "cornflower",
+    "cream",
+    "cyan",
+    "dark",
+    "forest",
+    "frosted",
+    "green",
+    "honeydew",
+    "ivory",
+    "khaki",
+    "lavender",
+    "lemon",
+    "linen",
+    "magenta",
+    "maroon",
+    "midnight",
+    "mint",
+    "moccasin",
+    "navy",
+    "olive",
+];
+const TYPE_SIZES: &[&str] = &["STANDARD", "SMALL", "MEDIUM", "LARGE", "ECONOMY", "PROMO"];
+const TYPE_FINISHES: &[&str] = &["ANODIZED", "BURNISHED", "PLATED", "POLISHED", "BRUSHED"];
+const TYPE_METALS: &[&str] = &["TIN", "NICKEL", "BRASS", "STEEL", "COPPER"];
+const CONTAINER_SIZES: &[&str] = &["SM", "LG", "MED", "JUMBO", "WRAP"];
+const CONTAINER_TYPES: &[&str] = &["CASE", "BOX", "BAG", "JAR", "PKG", "PACK", "CAN", "DRUM"];
+const SEGMENTS: &[&str] = &[
+    "AUTOMOBILE",
+    "BUILDING",
+    "FURNITURE",
+    "MACHINERY",
+    "HOUSEHOLD",
+];
+const PRIORITIES: &[&str] = &["1-URGENT", "2-HIGH", "3-MEDIUM", "4-NOT SPECIFIED", "5-LOW"];
+const INSTRUCTIONS: &[&str] = &[
+    "DELIVER IN PERSON",
+    "COLLECT COD",
+    "NONE",
+    "TAKE BACK RETURN",
+];
+const MODES: &[&str] = &["REG AIR", "AIR", "RAIL", "SHIP", "TRUCK", "MAIL", "FOB"];
+const WORDS: &[&str] = &[
+    "furiously",
+    "special",
+    "express",
+    "requests",
+    "Customer",
+    "Complaints",
+    "carefully",
+    "final",
+    "deposits",
+    "pending",
+    "accounts",
+    "ironic",
+    "packages",
+    "regular",
+    "blithely",
+];
+
+/// A small deterministic pseudo random number generator
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+
+    /// A value in the inclusive range `lo..=hi`
+    fn range(&mut self, lo: i64, hi: i64) -> i64 {
+        lo + (self.next() % (hi - lo + 1) as u64) as i64
+    }
+
+    fn pick<'a>(&mut self, values: &[&'a str]) -> &'a str {
+        values[self.next() as usize % values.len()]
+    }
+
+    fn text(&mut self, words: usize) -> String {
+        (0..words)
+            .map(|_| self.pick(WORDS))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn phone(&mut self, nation_key: i64) -> String {
+        format!(
+            "{}-{}-{}-{}",
+            nation_key + 10,
+            self.range(100, 999),
+            self.range(100, 999),
+            self.range(1000, 9999)
+        )
+    }
+}
+
+/// Accumulates the columns of one table
+#[derive(Default)]
+struct TableBuilder {
+    fields: Vec<Field>,
+    columns: Vec<ArrayRef>,
+}
+
+impl TableBuilder {
+    fn int64(mut self, name: &str, values: Vec<i64>) -> Self {
+        self.fields.push(Field::new(name, DataType::Int64, false));
+        self.columns.push(Arc::new(Int64Array::from(values)));
+        self
+    }
+
+    fn int32(mut self, name: &str, values: Vec<i32>) -> Self {
+        self.fields.push(Field::new(name, DataType::Int32, false));
+        self.columns.push(Arc::new(Int32Array::from(values)));
+        self
+    }
+
+    /// Decimal(15, 2) column from values in cents
+    fn decimal(mut self, name: &str, values: Vec<i64>) -> Self {
+        let array = Decimal128Array::from(values.into_iter().map(i128::from).collect::<Vec<_>>())
+            .with_precision_and_scale(15, 2)
+            .unwrap();
+        self.fields
+            .push(Field::new(name, DataType::Decimal128(15, 2), false));
+        self.columns.push(Arc::new(array));
+        self
+    }
+
+    fn utf8(mut self, name: &str, values: Vec<String>) -> Self {
+        self.fields.push(Field::new(name, DataType::Utf8, false));
+        self.columns.push(Arc::new(StringArray::from(values)));
+        self
+    }
+
+    fn date(mut self, name: &str, values: Vec<i32>) -> Self {
+        self.fields.push(Field::new(name, DataType::Date32, false));
+        self.columns.push(Arc::new(Date32Array::from(values)));
+        self
+    }
+
+    fn build(self) -> Result<RecordBatch> {
+        let schema: SchemaRef = Arc::new(Schema::new(self.fields));
+        Ok(RecordBatch::try_new(schema, self.columns)?)
+    }
+}
+
+/// Generate all TPC-H tables
+fn generate_tables() -> Result<Vec<(&'static str, RecordBatch)>> {
+    let mut rng = Lcg(42);
+
+    let region = TableBuilder::default()
+        .int64("r_regionkey", (0..REGIONS.len() as i64).collect())
+        .utf8("r_name", REGIONS.iter().map(|r| r.to_string()).collect())
+        .utf8("r_comment", REGIONS.iter().map(|_| rng.text(4)).collect())
+        .build()?;
+
+    let nation = TableBuilder::default()
+        .int64("n_nationkey", (0..NATIONS.len() as i64).collect())
+        .utf8(
+            "n_name",
+            NATIONS.iter().map(|(n, _)| n.to_string()).collect(),
+        )
+        .int64("n_regionkey", NATIONS.iter().map(|(_, r)| *r).collect())
+        .utf8("n_comment", NATIONS.iter().map(|_| rng.text(4)).collect())
+        .build()?;
+
+    let supp_nations = (1..=SUPPLIERS)
+        .map(|_| rng.range(0, NATIONS.len() as i64 - 1))
+        .collect::<Vec<_>>();
+    let supplier = TableBuilder::default()
+        .int64("s_suppkey", (1..=SUPPLIERS).collect())
+        .utf8(
+            "s_name",
+            (1..=SUPPLIERS)
+                .map(|k| format!("Supplier#{k:09}"))
+                .collect(),
+        )
+        .utf8(
+            "s_address",
+            (1..=SUPPLIERS).map(|k| format!("address {k}")).collect(),
+        )
+        .int64("s_nationkey", supp_nations.clone())
+        .utf8(
+            "s_phone",
+            supp_nations.iter().map(|n| rng.phone(*n)).collect(),
+        )
+        .decimal(
+            "s_acctbal",
+            (1..=SUPPLIERS)
+                .map(|_| rng.range(-99_999, 999_999))
+                .collect(),
+        )
+        .utf8("s_comment", (1..=SUPPLIERS).map(|_| rng.text(6)).collect())
+        .build()?;
+
+    let retail_prices = (1..=PARTS)
+        .map(|k| 90_000 + (k / 10) % 20_001 + 100 * (k % 1_000))
+        .collect::<Vec<_>>();
+    let part = TableBuilder::default()
+        .int64("p_partkey", (1..=PARTS).collect())
+        .utf8(
+            "p_name",
+            (1..=PARTS)
+                .map(|_| {
+                    (0..5)
+                        .map(|_| rng.pick(COLORS))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect(),
+        )
+        .utf8(
+            "p_mfgr",
+            (1..=PARTS)
+                .map(|k| format!("Manufacturer#{}", k % 5 + 1))
+                .collect(),
+        )
+        .utf8(
+            "p_brand",
+            (1..=PARTS)
+                .map(|k| format!("Brand#{}{}", k % 5 + 1, rng.range(1, 5)))
+                .collect(),
+        )
+        .utf8(
+            "p_type",
+            (1..=PARTS)
+                .map(|_| {
+                    format!(
+                        "{} {} {}",
+                        rng.pick(TYPE_SIZES),
+                        rng.pick(TYPE_FINISHES),
+                        rng.pick(TYPE_METALS)
+                    )
+                })
+                .collect(),
+        )
+        .int32(
+            "p_size",
+            (1..=PARTS).map(|_| rng.range(1, 50) as i32).collect(),
+        )
+        .utf8(
+            "p_container",
+            (1..=PARTS)
+                .map(|_| {
+                    format!(
+                        "{} {}",
+                        rng.pick(CONTAINER_SIZES),
+                        rng.pick(CONTAINER_TYPES)
+                    )
+                })
+                .collect(),
+        )
+        .decimal("p_retailprice", retail_prices.clone())
+        .utf8("p_comment", (1..=PARTS).map(|_| rng.text(3)).collect())
+        .build()?;
+
+    // every part is supplied by four suppliers
+    let supplier_of = |part_key: i64, i: i64| (part_key + i * (SUPPLIERS / 4)) % SUPPLIERS + 1;
+    let mut ps_partkey = vec![];
+    let mut ps_suppkey = vec![];
+    for part_key in 1..=PARTS {
+        for i in 0..4 {
+            ps_partkey.push(part_key);
+            ps_suppkey.push(supplier_of(part_key, i));
+        }
+    }
+    let partsupp_rows = ps_partkey.len();
+    let partsupp = TableBuilder::default()
+        .int64("ps_partkey", ps_partkey)
+        .int64("ps_suppkey", ps_suppkey)
+        .int32(
+            "ps_availqty",
+            (0..partsupp_rows)
+                .map(|_| rng.range(1, 9999) as i32)
+                .collect(),
+        )
+        .decimal(
+            "ps_supplycost",
+            (0..partsupp_rows)
+                .map(|_| rng.range(100, 100_000))
+                .collect(),
+        )
+        .utf8(
+            "ps_comment",
+            (0..partsupp_rows).map(|_| rng.text(5)).collect(),
+        )
+        .build()?;
+
+    let cust_nations = (1..=CUSTOMERS)
+        .map(|_| rng.range(0, NATIONS.len() as i64 - 1))
+        .collect::<Vec<_>>();
+    let customer = TableBuilder::default()
+        .int64("c_custkey", (1..=CUSTOMERS).collect())
+        .utf8(
+            "c_name",
+            (1..=CUSTOMERS)
+                .map(|k| format!("Customer#{k:09}"))
+                .collect(),
+        )
+        .utf8(
+            "c_address",
+            (1..=CUSTOMERS).map(|k| format!("address {k}")).collect(),
+        )
+        .int64("c_nationkey", cust_nations.clone())
+        .utf8(
+            "c_phone",
+            cust_nations.iter().map(|n| rng.phone(*n)).collect(),
+        )
+        .decimal(
+            "c_acctbal",
+            (1..=CUSTOMERS)
+                .map(|_| rng.range(-99_999, 999_999))
+                .collect(),
+        )
+        .utf8(
+            "c_mktsegment",
+            (1..=CUSTOMERS)
+                .map(|_| rng.pick(SEGMENTS).to_string())
+                .collect(),
+        )
+        .utf8("c_comment", (1..=CUSTOMERS).map(|_| rng.text(6)).collect())
+        .build()?;
+
+    let mut o_custkey = vec![];
+    let mut o_orderstatus = vec![];
+    let mut o_totalprice = vec![];
+    let mut o_orderdate = vec![];
+    let mut l = LineItems::default();
+    for order_key in 1..=ORDERS {
+        // as in TPC-H, a third of the customers never place an order
+        let mut cust_key = rng.range(1, CUSTOMERS);
+        while cust_key % 3 == 0 {
+            cust_key = rng.range(1, CUSTOMERS);
+        }
+        let order_date = rng.range(START_DATE as i64, END_DATE as i64) as i32;
+        let mut total = 0;
+        let mut statuses = vec![];
+        for line_number in 1..=rng.range(1, 7) {
+            let part_key = rng.range(1, PARTS);
+            let quantity = rng.range(1, 50);
+            let price = quantity * retail_prices[part_key as usize - 1];
+            let discount = rng.range(0, 10);
+            let tax = rng.range(0, 8);
+            let ship_date = order_date + rng.range(1, 121) as i32;
+            let commit_date = order_date + rng.range(30, 90) as i32;
+            let receipt_date = ship_date + rng.range(1, 30) as i32;
+            let status = if ship_date > CURRENT_DATE { "O" } else { "F" };
+            let return_flag = if receipt_date <= CURRENT_DATE {
+                if rng.range(0, 1) == 0 {
+                    "R"
+                } else {
+                    "A"
+                }
+            } else {
+                "N"
+            };
+            total += price * (100 - discount) * (100 + tax) / 10_000;
+            statuses.push(status);
+
+            l.orderkey.push(order_key);
+            l.partkey.push(part_key);
+            l.suppkey.push(supplier_of(part_key, rng.range(0, 3)));
+            l.linenumber.push(line_number as i32);
+            l.quantity.push(quantity * 100);
+            l.extendedprice.push(price);
+            l.discount.push(discount);
+            l.tax.push(tax);
+            l.returnflag.push(return_flag.to_string());
+            l.linestatus.push(status.to_string());
+            l.shipdate.push(ship_date);
+            l.commitdate.push(commit_date);
+            l.receiptdate.push(receipt_date);
+            l.shipinstruct.push(rng.pick(INSTRUCTIONS).to_string());
+            l.shipmode.push(rng.pick(MODES).to_string());
+            l.comment.push(rng.text(3));
+        }
+        let order_status = if statuses.iter().all(|s| *s == "F") {
+            "F"
+        } else if statuses.iter().all(|s| *s == "O") {
+            "O"
+        } else {
+            "P"
+        };
+        o_custkey.push(cust_key);
+        o_orderstatus.push(order_status.to_string());
+        o_totalprice.push(total);
+        o_orderdate.push(order_date);
+    }
+    let orders = TableBuilder::default()
+        .int64("o_orderkey", (1..=ORDERS).collect())
+        .int64("o_custkey", o_custkey)
+        .utf8("o_orderstatus", o_orderstatus)
+        .decimal("o_totalprice", o_totalprice)
+        .date("o_orderdate", o_orderdate)
+        .utf8(
+            "o_orderpriority",
+            (1..=ORDERS)
+                .map(|_| rng.pick(PRIORITIES).to_string())
+                .collect(),
+        )
+        .utf8(
+            "o_clerk",
+            (1..=ORDERS)
+                .map(|_| format!("Clerk#{:09}", rng.range(1, 100)))
+                .collect(),
+        )
+        .int32("o_shippriority", (1..=ORDERS).map(|_| 0).collect())
+        .utf8("o_comment", (1..=ORDERS).map(|_| rng.text(5)).collect())
+        .build()?;
+
+    let lineitem = TableBuilder::default()
+        .int64("l_orderkey", l.orderkey)
+        .int64("l_partkey", l.partkey)
+        .int64("l_suppkey", l.suppkey)
+        .int32("l_linenumber", l.linenumber)
+        .decimal("l_quantity", l.quantity)
+        .decimal("l_extendedprice", l.extendedprice)
+        .decimal("l_discount", l.discount)
+        .decimal("l_tax", l.tax)
+        .utf8("l_returnflag", l.returnflag)
+        .utf8("l_linestatus", l.linestatus)
+        .date("l_shipdate", l.shipdate)
+        .date("l_commitdate", l.commitdate)
+        .date("l_receiptdate", l.receiptdate)
+        .utf8("l_shipinstruct", l.shipinstruct)
+        .utf8("l_shipmode", l.shipmode)
+        .utf8("l_comment", l.comment)
+        .build()?;
+
+    Ok(vec![
+        ("customer", customer),
+        ("lineitem", lineitem),
+        ("nation", nation),
+        ("orders", orders),
+        ("part", part),
+        ("partsupp", partsupp),
+        ("region", region),
+        ("supplier", supplier),
+    ])
+}
+
+#[derive(Default)]
+struct LineItems {
+    orderkey: Vec<i64>,
+    partkey: Vec<i64>,
+    suppkey: Vec<i64>,
+    linenumber: Vec<i32>,
+    quantity: Vec<i64>,
+    extendedprice: Vec<i64>,
+    discount: Vec<i64>,
+    tax: Vec<i64>,
+    returnflag: Vec<String>,
+    linestatus: Vec<String>,
+    shipdate: Vec<i32>,
+    commitdate: Vec<i32>,
+    receiptdate: Vec<i32>,
+    shipinstruct: Vec<String>,
+    shipmode: Vec<String>,
+    comment: Vec<String>,
+}
+
+/// Encode the generated tables as parquet files, one directory per table, and return the path
+/// of each file relative to the data directory with its contents
+fn encode_tables() -> Result<Vec<(PathBuf, Vec<u8>)>> {
+    let mut files = vec![];
+    for (name, batch) in generate_tables()? {
+        let rows_per_file = batch.num_rows().div_ceil(FILES_PER_TABLE);
+        for i in 0..FILES_PER_TABLE {
+            let offset = (i * rows_per_file).min(batch.num_rows());
+            let len = rows_per_file.min(batch.num_rows() - offset);
+            let mut writer = ArrowWriter::try_new(vec![], batch.schema(), None)?;
+            writer.write(&batch.slice(offset, len))?;
+            let path = Path::new(name).join(format!("part-{i}.parquet"));
+            files.push((path, writer.into_inner()?));
+        }
+    }
+    Ok(files)
+}
+
+/// Write the generated tables under the system temp directory, in a directory named after a
+/// checksum of their files. A directory written by an earlier test process is reused.
+fn write_tables() -> Result<PathBuf> {
+    let files = encode_tables()?;
+    let mut hasher = crc32fast::Hasher::new();
+    for (path, bytes) in &files {
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.update(bytes);
+    }
+    let dir = std::env::temp_dir().join(format!("ray-sql-tpch-{:08x}", hasher.finalize()));
+    if dir.exists() {
+        return Ok(dir);
+    }
+    // the files are written to a staging directory that is renamed into place, so that test
+    // processes running at the same time never read a partially written directory
+    let staging = std::env::temp_dir().join(format!("ray-sql-tpch-{}", Uuid::new_v4()));
+    for (path, bytes) in files {
+        let path = staging.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, bytes)?;
+    }
+    if std::fs::rename(&staging, &dir).is_err() {
+        // another test process renamed its copy into place first
+        let _ = std::fs::remove_dir_all(&staging);
+    }
+    Ok(dir)
+}
+
+pub type TestResult<T> = std::result::Result<T, anyhow::Error>;
+
+/// A new directory under the system temp directory, deleted with its contents when dropped
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("ray-sql-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path as a string, as taken by the shuffle storages
+    pub fn path_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Deletes the local shuffle directories of the query stages of a graph when dropped. The
+/// planner creates a temp directory for each stage when the graph has no shuffle storage.
+pub struct ShuffleDirs {
+    dirs: Vec<PathBuf>,
+}
+
+impl ShuffleDirs {
+    pub fn of(graph: &ExecutionGraph) -> Self {
+        let dirs = graph
+            .query_stages
+            .values()
+            .filter_map(|stage| stage.plan.as_any().downcast_ref::<ShuffleWriterExec>())
+            .map(|writer| PathBuf::from(writer.storage.url()))
+            .filter(|dir| dir.is_absolute())
+            .collect();
+        Self { dirs }
+    }
+}
+
+impl Drop for ShuffleDirs {
+    fn drop(&mut self) {
+        for dir in &self.dirs {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Directory containing the generated tables, which is shared by all test processes that
+/// generate the same data
+pub fn tpch_data_dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| write_tables().expect("failed to generate TPC-H data"))
+}
+
+/// Register the generated TPC-H tables with a context
+pub async fn register_tpch_tables(ctx: &SessionContext) -> Result<()> {
+    let dir = tpch_data_dir();
+    for table in TPCH_TABLES {
+        ctx.register_parquet(
+            table,
+            dir.join(table).to_str().unwrap(),
+            ParquetReadOptions::default(),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Serialize and deserialize every query stage, as happens when stages are sent to Ray workers
+pub fn roundtrip_execution_graph(graph: &ExecutionGraph) -> Result<ExecutionGraph> {
+    let ctx = SessionContext::new();
+    let codec = ShuffleCodec {};
+    let mut new_graph = ExecutionGraph::new();
+    new_graph.shuffle_mode = graph.shuffle_mode;
+    new_graph.shuffle_storage = graph.shuffle_storage.clone();
+    for (id, stage) in &graph.query_stages {
+        let bytes = physical_plan_to_bytes_with_extension_codec(stage.plan.clone(), &codec)?;
+        let plan: Arc<dyn ExecutionPlan> =
+            physical_plan_from_bytes_with_extension_codec(&bytes, &ctx, &codec)?;
+        new_graph
+            .query_stages
+            .insert(*id, Arc::new(QueryStage::new(*id, plan)));
+    }
+    Ok(new_graph)
+}
+
+/// Format every row of the batches as a string and sort them, so that results can be compared
+/// regardless of the order in which partitions produced them
+pub fn sorted_rows(batches: &[RecordBatch]) -> Result<Vec<String>> {
+    let Some(first) = batches.first() else {
+        return Ok(vec![]);
+    };
+    let batch = concat_batches(&first.schema(), batches)?;
+    let mut rows = (0..batch.num_rows())
+        .map(|row| {
+            batch
+                .columns()
+                .iter()
+                .map(|c| array_value_to_string(c, row))
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map(|values| values.join("|"))
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    rows.sort();
+    Ok(rows)
+}
+
+/// Run a query with plain DataFusion and through the distributed execution graph and return
+/// the sorted rows of both
+pub fn run_differential(
+    sql: &str,
+    target_partitions: usize,
+    shuffle_mode: ShuffleMode,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let config = SessionConfig::new().with_target_partitions(target_partitions);
+    let ctx = SessionContext::new_with_config(config);
+    // planning the larger queries needs more stack than the test threads have in debug builds
+    let rt = Builder::new_multi_thread()
+        .thread_stack_size(16 * 1024 * 1024)
+        .enable_all()
+        .build()?;
+    let sql = sql.to_string();
+    let (expected, graph) = rt
+        .block_on(rt.spawn(async move {
+            register_tpch_tables(&ctx).await?;
+            let expected = ctx.sql(&sql).await?.collect().await?;
+            let plan = ctx.sql(&sql).await?.create_physical_plan().await?;
+            let mut graph = make_execution_graph_with_shuffle_mode(plan, shuffle_mode)?;
+            graph.optimize_query_stages()?;
+            let graph = roundtrip_execution_graph(&graph)?;
+            Ok::<_, DataFusionError>((expected, graph))
+        }))
+        .map_err(DataFusionError::ExecutionJoin)??;
+    let _shuffle_dirs = ShuffleDirs::of(&graph);
+    let actual = LocalExecutor::new(4).execute(&graph)?;
+    Ok((sorted_rows(&expected)?, sorted_rows(&actual)?))
+}